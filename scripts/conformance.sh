@@ -0,0 +1,44 @@
+#!/usr/bin/env bash
+#
+# Runs the OCI distribution-spec conformance suite against a rustgistry
+# started on a temporary local storage, writing the suite's report.html and
+# junit.xml to $REPORT_DIR.
+#
+# Requires cargo, curl and docker. Workflows can be left out by setting their
+# OCI_TEST_* variable to 0, e.g. OCI_TEST_CONTENT_MANAGEMENT=0.
+
+set -euo pipefail
+
+PORT="${PORT:-5000}"
+CONFORMANCE_IMAGE="${CONFORMANCE_IMAGE:-ghcr.io/opencontainers/distribution-spec/conformance:v1.1.0}"
+REPORT_DIR="${REPORT_DIR:-$PWD/conformance-report}"
+
+cd "$(dirname "$0")/.."
+
+cargo build --release
+
+storage="$(mktemp -d)"
+STORAGE_PATH="$storage" ./target/release/rustgistry --host 127.0.0.1 --port "$PORT" &
+server=$!
+trap 'kill "$server" 2>/dev/null; rm -rf "$storage"' EXIT
+
+for _ in $(seq 50); do
+    curl -fs "http://127.0.0.1:$PORT/readyz" >/dev/null && break
+    sleep 0.2
+done
+
+mkdir -p "$REPORT_DIR"
+
+docker run --rm --network host \
+    -v "$REPORT_DIR:/report" \
+    -e OCI_ROOT_URL="http://127.0.0.1:$PORT" \
+    -e OCI_NAMESPACE=conformance \
+    -e OCI_CROSSMOUNT_NAMESPACE=conformance-mount \
+    -e OCI_TEST_PULL="${OCI_TEST_PULL:-1}" \
+    -e OCI_TEST_PUSH="${OCI_TEST_PUSH:-1}" \
+    -e OCI_TEST_CONTENT_DISCOVERY="${OCI_TEST_CONTENT_DISCOVERY:-1}" \
+    -e OCI_TEST_CONTENT_MANAGEMENT="${OCI_TEST_CONTENT_MANAGEMENT:-1}" \
+    -e OCI_HIDE_SKIPPED_WORKFLOWS=0 \
+    -e OCI_DEBUG="${OCI_DEBUG:-0}" \
+    -e OCI_REPORT_DIR=/report \
+    "$CONFORMANCE_IMAGE"
@@ -1,11 +1,24 @@
 use std::env;
 use std::error::Error;
+use std::io::{self, Write};
 use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use flate2::Compression;
+use google_cloud_storage::client::{Client, ClientConfig};
+use rusoto_core::Region;
+use rustgistry::api::v2::audit::AuditLog;
+use rustgistry::api::v2::config::{AuthConfig, Config};
 use rustgistry::api::v2::ApiV2;
-use rustgistry::storage::LocalStorage;
+use rustgistry::auth::{Acl, Htpasswd};
+use rustgistry::storage::{
+    CompressedStorage, DefaultLayout, DigestAlgorithm, DistributionLayout, EncryptedStorage,
+    GcsStorage, LayoutStrategy, LocalStorage, RedisIndexedStorage, S3Storage, Storage,
+};
+use rustgistry::utils::expand_env;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -17,31 +30,503 @@ struct Args {
     /// Host to listen on
     #[arg(long, default_value = "0.0.0.0")]
     host: String,
+
+    /// Unix domain socket to listen on instead of a TCP port, for a reverse
+    /// proxy on the same host. Requires --external-url
+    #[arg(long, conflicts_with_all = ["port", "host"], requires = "external_url")]
+    unix_socket: Option<PathBuf>,
+
+    /// htpasswd file (bcrypt) used to authenticate clients with HTTP Basic auth
+    #[arg(long)]
+    htpasswd: Option<PathBuf>,
+
+    /// Realm advertised in the authentication challenge
+    #[arg(long, default_value = "rustgistry")]
+    auth_realm: String,
+
+    /// Repository anyone may pull from without authenticating, `*` matching
+    /// any sequence of characters
+    #[arg(long = "public-repository", value_name = "PATTERN")]
+    public_repositories: Vec<String>,
+
+    /// JSON access control list restricting repository actions per user
+    #[arg(long)]
+    acl: Option<PathBuf>,
+
+    /// Maximum number of bytes a repository may hold
+    #[arg(long)]
+    max_repository_size: Option<u64>,
+
+    /// Size limit of a single repository, overriding --max-repository-size
+    #[arg(long = "repository-size-limit", value_name = "NAME=BYTES", value_parser = parse_repository_size_limit)]
+    repository_size_limits: Vec<(String, u64)>,
+
+    /// Maximum number of uploads a repository may have open at once
+    #[arg(long)]
+    max_concurrent_uploads: Option<usize>,
+
+    /// Maximum number of tags a repository may hold
+    #[arg(long)]
+    max_tags: Option<usize>,
+
+    /// Maximum number of repositories the registry may hold
+    #[arg(long)]
+    max_repositories: Option<usize>,
+
+    /// Maximum number of bytes of a pushed manifest, 4 MiB by default
+    #[arg(long)]
+    max_manifest_size: Option<u64>,
+
+    /// Media type pushed manifests and their configs may declare, the common
+    /// OCI and Docker schema2 ones being allowed when none is given
+    #[arg(long = "manifest-media-type", value_name = "MEDIA_TYPE")]
+    manifest_media_types: Vec<String>,
+
+    /// Only serve pulls, rejecting every push
+    #[arg(long)]
+    read_only: bool,
+
+    /// Allow deleting whole repositories through DELETE /v2/<name>
+    #[arg(long)]
+    delete_repositories: bool,
+
+    /// Build Location URLs from the X-Forwarded-Proto and X-Forwarded-Host
+    /// headers set by a reverse proxy
+    #[arg(long)]
+    trust_forwarded_headers: bool,
+
+    /// Base of the generated Location URLs, e.g. https://registry.example.com
+    #[arg(long)]
+    external_url: Option<String>,
+
+    /// Hostname clients may reach the registry at, other hosts being rejected
+    #[arg(long = "allowed-host", value_name = "HOST")]
+    allowed_hosts: Vec<String>,
+
+    /// Translate manifests to the unsigned schema1 format for legacy clients
+    #[arg(long)]
+    schema1_translation: bool,
+
+    /// Serve blobs with the media type their manifests declare rather than
+    /// application/octet-stream
+    #[arg(long)]
+    blob_media_types: bool,
+
+    /// Value of the Docker-Distribution-Api-Version header, registry/2.0 by
+    /// default as Docker clients expect
+    #[arg(long)]
+    api_version: Option<String>,
+
+    /// Maximum number of connections served at once
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Seconds clients have to send the headers of a request, 0 meaning no
+    /// limit
+    #[arg(long, default_value_t = 30)]
+    header_read_timeout: u64,
+
+    /// Write an audit trail of pushes and deletions as JSON lines to this
+    /// file, `-` meaning stderr
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Also record pulls in the audit trail
+    #[arg(long)]
+    audit_pulls: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let args = Args::parse();
+/// Path given on the command line, with its environment variables expanded.
+fn expand_path(path: &Path) -> Result<PathBuf, String> {
+    match path.to_str() {
+        Some(path) => expand_env(path)
+            .map(PathBuf::from)
+            .map_err(|e| format!("Invalid path '{}': {}", path, e)),
+        None => Ok(path.to_path_buf()),
+    }
+}
 
+fn parse_repository_size_limit(value: &str) -> Result<(String, u64), String> {
+    let (name, size) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=BYTES, got '{}'", value))?;
+    let size = size.parse::<u64>().map_err(|e| e.to_string())?;
+
+    Ok((name.to_string(), size))
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Re-hash stored layers and report those not matching their digest
+    Verify {
+        /// Repository to verify, the whole store is scanned when omitted
+        name: Option<String>,
+    },
+    /// Rebuild the referrers index and digest links from the stored manifests
+    Reindex {
+        /// Repository to reindex, every repository is when omitted
+        name: Option<String>,
+    },
+    /// List the repositories, or the tags of a repository
+    List {
+        /// Repository to list the tags of, the repositories are listed when
+        /// omitted
+        name: Option<String>,
+
+        /// Print the listing as JSON, shaped like the catalog and tags list
+        /// API responses
+        #[arg(long)]
+        json: bool,
+    },
+    /// Move the local store to another layout, then set STORAGE_LAYOUT to it
+    Migrate {
+        /// Layout to move the store to
+        #[arg(long, value_enum)]
+        to: Layout,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Layout {
+    /// layers/<name>/<digest>, manifests/<name>/<reference>, ...
+    Default,
+    /// repositories/<name>/_layers/<digest>, ..., layers being deduplicated
+    Distribution,
+}
+
+impl Layout {
+    fn from_env() -> Result<Layout, Box<dyn Error + Send + Sync>> {
+        match env::var("STORAGE_LAYOUT") {
+            Ok(layout) => Ok(Layout::from_str(&layout, true)?),
+            Err(_) => Ok(Layout::Default),
+        }
+    }
+
+    fn strategy(self) -> Box<dyn LayoutStrategy> {
+        match self {
+            Layout::Default => Box::new(DefaultLayout),
+            Layout::Distribution => Box::new(DistributionLayout),
+        }
+    }
+}
+
+fn create_local_storage() -> Result<LocalStorage, Box<dyn Error + Send + Sync>> {
+    let storage_path = match env::var("STORAGE_PATH") {
+        Ok(path) => expand_env(&path).map_err(|e| format!("Invalid STORAGE_PATH: {}", e))?,
+        Err(_) => "/var/lib/rustgistry".to_string(),
+    };
+    let mut local_storage = LocalStorage::new(storage_path);
+
+    let layout = Layout::from_env()?;
+    local_storage.layout = layout.strategy();
+    local_storage.deduplicate = layout == Layout::Distribution;
+
+    if let Ok(size) = env::var("STORAGE_WRITE_BUFFER_SIZE") {
+        local_storage.write_buffer_size = size.parse()?;
+    }
+
+    if let Ok(size) = env::var("STORAGE_READ_BUFFER_SIZE") {
+        local_storage.read_buffer_size = size.parse()?;
+    }
+
+    Ok(local_storage)
+}
+
+async fn create_storage() -> Result<Arc<dyn Storage>, Box<dyn Error + Send + Sync>> {
     let storage_type = env::var("STORAGE_TYPE").unwrap_or_else(|_| "local".to_string());
 
-    let mut storage = None;
+    let digest_algorithm = match env::var("STORAGE_DIGEST_ALGORITHM") {
+        Ok(algorithm) => algorithm.parse::<DigestAlgorithm>()?,
+        Err(_) => DigestAlgorithm::default(),
+    };
+
+    let mut storage: Option<Arc<dyn Storage>> = None;
 
     if storage_type == "local" {
-        let storage_path =
-            env::var("STORAGE_PATH").unwrap_or_else(|_| "/var/lib/rustgistry".to_string());
-        storage = Some(LocalStorage::new(storage_path));
+        let mut local_storage = create_local_storage()?;
+        local_storage.digest_algorithm = digest_algorithm;
+
+        local_storage.prepare()?;
+
+        if env::var("STORAGE_DEDUPLICATE").is_ok() {
+            local_storage.deduplicate = true;
+            local_storage.deduplicate_layers()?;
+        }
+
+        storage = Some(Arc::new(local_storage));
+    }
+
+    if storage_type == "s3" {
+        let bucket = env::var("S3_BUCKET").map_err(|_| "S3_BUCKET must be set")?;
+
+        // Credentials and region are read from the usual AWS variables and files
+        let region = match env::var("S3_REGION") {
+            Ok(region) => region.parse::<Region>()?,
+            Err(_) => Region::default(),
+        };
+
+        let mut s3_storage = S3Storage::new(bucket, region);
+        s3_storage.digest_algorithm = digest_algorithm;
+
+        storage = Some(Arc::new(s3_storage));
+    }
+
+    if storage_type == "gcs" {
+        let bucket = env::var("GCS_BUCKET").map_err(|_| "GCS_BUCKET must be set")?;
+
+        // Credentials are read from GOOGLE_APPLICATION_CREDENTIALS or the
+        // metadata server
+        let config = ClientConfig::default().with_auth().await?;
+
+        let mut gcs_storage = GcsStorage::new(bucket, Client::new(config));
+        gcs_storage.digest_algorithm = digest_algorithm;
+
+        storage = Some(Arc::new(gcs_storage));
     }
 
     if storage.is_none() {
         panic!("Invalid storage type");
     }
 
-    let mut api = ApiV2::new(
-        args.host.parse::<Ipv4Addr>()?,
-        args.port,
-        Arc::new(storage.unwrap()),
-    );
+    let mut storage = storage.unwrap();
+
+    if let Err(e) = storage.health_check().await {
+        return Err(format!("Storage isn't usable: {}", e).into());
+    }
+
+    // Compressed before being encrypted, ciphertext not compressing
+    if let Ok(compression) = env::var("STORAGE_COMPRESSION") {
+        if compression != "gzip" {
+            return Err(format!("Unsupported STORAGE_COMPRESSION '{}'", compression).into());
+        }
+
+        let level = match env::var("STORAGE_COMPRESSION_LEVEL") {
+            Ok(level) => Compression::new(level.parse()?),
+            Err(_) => Compression::default(),
+        };
+        storage = Arc::new(CompressedStorage::with_level(storage, level));
+    }
+
+    if let Ok(key) = env::var("STORAGE_ENCRYPTION_KEY") {
+        let key: [u8; 32] = hex::decode(key)?
+            .try_into()
+            .map_err(|_| "STORAGE_ENCRYPTION_KEY must be 32 hex-encoded bytes")?;
+        storage = Arc::new(EncryptedStorage::new(storage, &key));
+    }
+
+    if let Ok(url) = env::var("REDIS_URL") {
+        let client = redis::Client::open(url)?;
+        storage = Arc::new(RedisIndexedStorage::new(storage, client).await?);
+    }
+
+    Ok(storage)
+}
+
+async fn verify(
+    storage: Arc<dyn Storage>,
+    name: Option<String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let repositories = match name {
+        Some(name) => vec![name],
+        None => storage.list_repositories().await?,
+    };
+
+    let mut verified = 0;
+    let mut mismatches = 0;
+
+    for name in repositories {
+        for digest in storage.list_layers(name.clone()).await? {
+            verified += 1;
+
+            match storage.verify_blob(name.clone(), digest.clone()).await {
+                Ok(verification) if verification.is_valid() => {}
+                Ok(verification) => {
+                    mismatches += 1;
+                    println!(
+                        "{}@{}: content hashes to {}",
+                        name, verification.digest, verification.computed_digest
+                    );
+                }
+                Err(e) => {
+                    mismatches += 1;
+                    println!("{}@{}: {}", name, digest, e);
+                }
+            }
+        }
+    }
+
+    println!("Verified {} blobs, {} mismatches", verified, mismatches);
+
+    if mismatches > 0 {
+        return Err(format!("{} blobs don't match their digest", mismatches).into());
+    }
+
+    Ok(())
+}
+
+async fn reindex(
+    storage: Arc<dyn Storage>,
+    name: Option<String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let repositories = match name {
+        Some(name) => vec![name],
+        None => storage.list_repositories().await?,
+    };
+
+    for name in repositories {
+        storage.link_manifest_digests(name.clone()).await?;
+        storage.rebuild_referrers(name.clone()).await?;
+        println!("Reindexed {}", name);
+    }
+
+    Ok(())
+}
+
+async fn list<W>(
+    storage: Arc<dyn Storage>,
+    name: Option<String>,
+    json: bool,
+    out: &mut W,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    W: Write,
+{
+    let entries = match &name {
+        Some(name) => storage.list_tags(name.clone()).await?,
+        None => storage.list_repositories().await?,
+    };
+
+    if json {
+        let listing = match name {
+            Some(name) => serde_json::json!({ "name": name, "tags": entries }),
+            None => serde_json::json!({ "repositories": entries }),
+        };
+        writeln!(out, "{}", listing)?;
+    } else {
+        for entry in entries {
+            writeln!(out, "{}", entry)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn migrate(to: Layout) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if env::var("STORAGE_TYPE").is_ok_and(|storage_type| storage_type != "local") {
+        return Err("Only the local storage can be migrated".into());
+    }
+
+    let mut storage = create_local_storage()?;
+    storage.migrate(to.strategy())?;
+
+    if to == Layout::Distribution {
+        storage.deduplicate_layers()?;
+    }
+
+    println!("Migrated {} to the {:?} layout", storage.path.display(), to);
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let args = Args::parse();
+
+    tracing_subscriber::fmt::init();
+
+    if let Some(Command::Migrate { to }) = args.command {
+        return migrate(to);
+    }
+
+    let storage = create_storage().await?;
+
+    match args.command {
+        Some(Command::Verify { name }) => return verify(storage, name).await,
+        Some(Command::Reindex { name }) => return reindex(storage, name).await,
+        Some(Command::List { name, json }) => {
+            return list(storage, name, json, &mut io::stdout()).await
+        }
+        Some(Command::Migrate { .. }) | None => {}
+    }
+
+    let mut config = Config::default();
+
+    if let Some(htpasswd) = &args.htpasswd {
+        config.auth = Some(AuthConfig {
+            realm: args.auth_realm.clone(),
+            users: Arc::new(Htpasswd::open(expand_path(htpasswd)?)?),
+        });
+    }
+
+    config.public_repositories = args.public_repositories;
+
+    if let Some(acl) = &args.acl {
+        config.acl = Some(Arc::new(Acl::open(expand_path(acl)?)?));
+    }
+
+    config.max_repository_size = args.max_repository_size;
+    config.repository_size_limits = args.repository_size_limits.into_iter().collect();
+    config.max_concurrent_uploads = args.max_concurrent_uploads;
+    config.max_tags = args.max_tags;
+    config.max_repositories = args.max_repositories;
+
+    config.max_manifest_size = args.max_manifest_size;
+    if !args.manifest_media_types.is_empty() {
+        config.manifest_media_types = Some(args.manifest_media_types);
+    }
+
+    config.read_only = args.read_only;
+    config.delete_repositories = args.delete_repositories;
+    config.trust_forwarded_headers = args.trust_forwarded_headers;
+    config.external_url = args.external_url;
+    config.allowed_hosts = args.allowed_hosts;
+    config.schema1_translation = args.schema1_translation;
+    config.blob_media_types = args.blob_media_types;
+    config.api_version = args.api_version;
+
+    config.http.max_connections = args.max_connections;
+    config.http.header_read_timeout =
+        Some(Duration::from_secs(args.header_read_timeout)).filter(|timeout| !timeout.is_zero());
+
+    if let Some(path) = &args.audit_log {
+        let mut audit_log = if path.as_os_str() == "-" {
+            AuditLog::stderr()
+        } else {
+            AuditLog::open(expand_path(path)?)?
+        };
+        audit_log.include_pulls = args.audit_pulls;
+
+        config.audit_log = Some(Arc::new(audit_log));
+    }
+
+    if let Ok(key) = env::var("UPLOAD_STATE_KEY") {
+        config.upload_state_key = Some(key.into_bytes());
+    }
+
+    #[cfg(not(unix))]
+    if args.unix_socket.is_some() {
+        return Err("Unix sockets aren't supported on this platform".into());
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &args.unix_socket {
+        let path = expand_path(path)?;
+        let api = ApiV2::with_config(Ipv4Addr::LOCALHOST, 0, storage, config);
+        let server = api.serve_unix(&path, async {
+            tokio::signal::ctrl_c().await.ok();
+        })?;
+
+        println!("Listening on {}", path.display());
+
+        server.await?;
+
+        return Ok(());
+    }
+
+    let mut api = ApiV2::with_config(args.host.parse::<Ipv4Addr>()?, args.port, storage, config);
     let server = api.listen();
 
     println!("Listening on http://{}:{}", args.host, args.port);
@@ -50,3 +535,39 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_list() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    let manifest = br#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","layers":[]}"#;
+    for (name, tag) in [("alpine", "latest"), ("alpine", "3.17"), ("redis", "7")] {
+        storage
+            .update_manifest(name.to_string(), tag.to_string(), manifest)
+            .await?;
+    }
+
+    let output = |name: Option<&str>, json: bool| {
+        let storage = storage.clone();
+        let name = name.map(str::to_string);
+        async move {
+            let mut out = Vec::new();
+            list(storage, name, json, &mut out).await?;
+            Ok::<_, Box<dyn Error + Send + Sync>>(String::from_utf8(out)?)
+        }
+    };
+
+    assert_eq!(output(None, false).await?, "alpine\nredis\n");
+    assert_eq!(output(Some("alpine"), false).await?, "3.17\nlatest\n");
+    assert_eq!(
+        output(None, true).await?,
+        "{\"repositories\":[\"alpine\",\"redis\"]}\n"
+    );
+    assert_eq!(
+        output(Some("alpine"), true).await?,
+        "{\"name\":\"alpine\",\"tags\":[\"3.17\",\"latest\"]}\n"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::extract::ConnectInfo;
+use hyper::{Method, Request, StatusCode};
+use serde::Serialize;
+
+use crate::auth::{Action, Subject};
+
+/// Audit trail of the operations performed on repositories, written as JSON
+/// lines to its own sink rather than through `tracing`.
+pub struct AuditLog {
+    sink: Mutex<Box<dyn Write + Send>>,
+
+    /// Also record pulls, only pushes and deletions being recorded otherwise.
+    pub include_pulls: bool,
+}
+
+impl AuditLog {
+    pub fn new<W>(sink: W) -> AuditLog
+    where
+        W: Write + Send + 'static,
+    {
+        AuditLog {
+            sink: Mutex::new(Box::new(sink)),
+            include_pulls: false,
+        }
+    }
+
+    pub fn stderr() -> AuditLog {
+        AuditLog::new(io::stderr())
+    }
+
+    /// Appends the records to the file at `path`, creating it if needed.
+    pub fn open<P>(path: P) -> io::Result<AuditLog>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog::new(file))
+    }
+
+    pub fn record(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = sink.write_all(&line).and_then(|_| sink.flush()) {
+            eprintln!("Failed to write audit record: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditResult {
+    Success,
+    Rejected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditRecord {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub subject: Option<String>,
+    pub client_ip: Option<String>,
+    pub repository: String,
+    /// Tag, digest or upload the operation is about, if any.
+    pub reference: Option<String>,
+    pub action: Action,
+    pub result: AuditResult,
+    pub status: u16,
+}
+
+/// Action a request performs on the repository its path is about, along with
+/// the repository and reference. `None` for requests outside repositories.
+pub fn request_action<B>(request: &Request<B>) -> Option<(Action, String, Option<String>)> {
+    let path = request.uri().path().strip_prefix("/v2/")?;
+    let mut segments = path.split('/');
+
+    let name = segments.next().filter(|name| !name.is_empty())?;
+    if name.starts_with('_') {
+        return None;
+    }
+
+    let action = match *request.method() {
+        Method::GET | Method::HEAD => Action::Pull,
+        Method::DELETE => Action::Delete,
+        _ => Action::Push,
+    };
+
+    // The digest of a finished upload is a query parameter
+    let digest = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("digest="))
+            .map(|digest| digest.replace("%3A", ":").replace("%3a", ":"))
+    });
+
+    let reference = segments
+        .rfind(|segment| !segment.is_empty())
+        .filter(|segment| !matches!(*segment, "manifests" | "blobs" | "uploads"))
+        .map(str::to_string);
+
+    Some((action, name.to_string(), digest.or(reference)))
+}
+
+/// Address of the client, taken from `X-Forwarded-For` when the proxy in
+/// front is trusted to set it.
+pub fn client_ip<B>(request: &Request<B>, trust_forwarded_headers: bool) -> Option<String> {
+    if trust_forwarded_headers {
+        let forwarded_for = request
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(|ip| ip.trim().to_string());
+
+        if forwarded_for.is_some() {
+            return forwarded_for;
+        }
+    }
+
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+}
+
+impl AuditRecord {
+    pub fn new(
+        subject: Option<&Subject>,
+        client_ip: Option<String>,
+        (action, repository, reference): (Action, String, Option<String>),
+        status: StatusCode,
+    ) -> AuditRecord {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+
+        let result = if status.is_client_error() || status.is_server_error() {
+            AuditResult::Rejected
+        } else {
+            AuditResult::Success
+        };
+
+        AuditRecord {
+            timestamp,
+            subject: subject.map(|subject| subject.name.clone()),
+            client_ip,
+            repository,
+            reference,
+            action,
+            result,
+            status: status.as_u16(),
+        }
+    }
+}
+
+#[test]
+fn test_request_action() {
+    let action = |method: Method, uri: &str| {
+        request_action(&Request::builder().method(method).uri(uri).body(()).unwrap())
+    };
+
+    assert_eq!(
+        action(Method::PUT, "/v2/test/manifests/latest"),
+        Some((Action::Push, "test".to_string(), Some("latest".to_string())))
+    );
+    assert_eq!(
+        action(
+            Method::PUT,
+            "/v2/test/blobs/uploads/1234?digest=sha256%3Aabcd"
+        ),
+        Some((
+            Action::Push,
+            "test".to_string(),
+            Some("sha256:abcd".to_string())
+        ))
+    );
+    assert_eq!(
+        action(Method::POST, "/v2/test/blobs/uploads/"),
+        Some((Action::Push, "test".to_string(), None))
+    );
+    assert_eq!(
+        action(Method::DELETE, "/v2/test"),
+        Some((Action::Delete, "test".to_string(), None))
+    );
+    assert_eq!(action(Method::GET, "/v2/_catalog"), None);
+    assert_eq!(action(Method::GET, "/v2/"), None);
+}
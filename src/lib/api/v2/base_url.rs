@@ -0,0 +1,99 @@
+use async_trait::async_trait;
+use axum::extract::{FromRequest, RequestParts};
+use hyper::{
+    header,
+    http::uri::{Authority, Scheme},
+    HeaderMap, StatusCode,
+};
+
+use super::state::SharedState;
+
+const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+
+/// Scheme and authority clients reached the registry at, e.g.
+/// `https://registry.example.com`, which generated URLs are based on.
+///
+/// `Config::external_url` is used as is when set. Otherwise, unlike axum's
+/// `Host`, the `X-Forwarded-*` headers are only honored when
+/// `Config::trust_forwarded_headers` is set, as any client can send them, and
+/// hosts missing from `Config::allowed_hosts` are rejected.
+pub struct BaseUrl(pub String);
+
+/// First value of header `name`, proxies appending theirs to a list.
+fn first_header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    let value = headers.get(name)?.to_str().ok()?;
+    let value = value.split(',').next()?.trim();
+
+    Some(value.to_string()).filter(|value| !value.is_empty())
+}
+
+/// Percent-encodes `path` for use in generated URLs, leaving its `/`
+/// separators and unreserved characters as they are.
+pub fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for BaseUrl
+where
+    B: Send,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let config = req
+            .extensions()
+            .get::<SharedState>()
+            .map(|state| state.config.clone());
+
+        if let Some(external_url) = config
+            .as_ref()
+            .and_then(|config| config.external_url.as_ref())
+        {
+            return Ok(BaseUrl(external_url.trim_end_matches('/').to_string()));
+        }
+
+        let trust_forwarded_headers = config
+            .as_ref()
+            .is_some_and(|config| config.trust_forwarded_headers);
+
+        let mut scheme = req.uri().scheme_str().unwrap_or("http").to_string();
+        let mut host = first_header_value(req.headers(), header::HOST.as_str())
+            .or_else(|| req.uri().authority().map(|authority| authority.to_string()));
+
+        if trust_forwarded_headers {
+            if let Some(proto) = first_header_value(req.headers(), X_FORWARDED_PROTO) {
+                scheme = proto;
+            }
+
+            if let Some(forwarded_host) = first_header_value(req.headers(), X_FORWARDED_HOST) {
+                host = Some(forwarded_host);
+            }
+        }
+
+        // Both end up in headers such as `Location`, which they could break
+        if scheme.parse::<Scheme>().is_err() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        match host {
+            Some(host) if host.parse::<Authority>().is_err() => Err(StatusCode::BAD_REQUEST),
+            Some(host) if config.is_some_and(|config| !config.is_allowed_host(&host)) => {
+                Err(StatusCode::BAD_REQUEST)
+            }
+            Some(host) => Ok(BaseUrl(format!("{}://{}", scheme, host))),
+            None => Err(StatusCode::BAD_REQUEST),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use crate::{auth::Acl, storage::Storage};
+
+use super::{
+    config::{AuthConfig, Config, HttpConfig},
+    ApiV2,
+};
+
+/// Configures an [`ApiV2`] server, see [`ApiV2::builder`].
+pub struct ApiV2Builder {
+    addr: SocketAddr,
+    storage: Option<Arc<dyn Storage>>,
+    config: Config,
+}
+
+impl Default for ApiV2Builder {
+    fn default() -> Self {
+        ApiV2Builder {
+            addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 8080)),
+            storage: None,
+            config: Config::default(),
+        }
+    }
+}
+
+impl ApiV2Builder {
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Address to listen on, `0.0.0.0:8080` by default.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// Replaces the whole configuration, including the settings made so far.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_auth(mut self, auth: AuthConfig) -> Self {
+        self.config.auth = Some(auth);
+        self
+    }
+
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.config.acl = Some(acl);
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// Keep-alive, timeouts and connection limit of the HTTP server, see
+    /// `HttpConfig` for the defaults.
+    pub fn with_http(mut self, http: HttpConfig) -> Self {
+        self.config.http = http;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.http.max_connections = Some(max_connections);
+        self
+    }
+
+    pub fn build(self) -> Result<ApiV2, Box<dyn Error + Send + Sync>> {
+        let storage = self.storage.ok_or("No storage configured")?;
+
+        Ok(ApiV2::from_parts(self.addr, storage, self.config))
+    }
+}
@@ -0,0 +1,209 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::auth::{matches_pattern, Acl, Htpasswd};
+
+use super::audit::AuditLog;
+
+/// Manifests larger than this many bytes are rejected unless
+/// `Config::max_manifest_size` says otherwise.
+pub const DEFAULT_MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Media types of the manifests, and of their configs, accepted unless
+/// `Config::manifest_media_types` says otherwise.
+pub const DEFAULT_MANIFEST_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.oci.image.config.v1+json",
+    "application/vnd.oci.empty.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.docker.container.image.v1+json",
+];
+
+/// `Docker-Distribution-Api-Version` sent unless `Config::api_version` says
+/// otherwise, the value Docker clients check for.
+pub const DEFAULT_API_VERSION: &str = "registry/2.0";
+
+#[derive(Clone)]
+pub struct AuthConfig {
+    pub realm: String,
+    pub users: Arc<Htpasswd>,
+}
+
+/// Connection handling of the HTTP server.
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    /// Keep HTTP/1 connections open between requests. Enabled by default.
+    pub http1_keep_alive: bool,
+
+    /// Interval of the TCP keep-alive probes detecting dead peers, 60 seconds
+    /// by default. Disabled when unset.
+    pub tcp_keep_alive: Option<Duration>,
+
+    /// Interval of the HTTP/2 pings keeping connections alive, 20 seconds by
+    /// default. Disabled when unset.
+    pub http2_keep_alive_interval: Option<Duration>,
+
+    /// Time an HTTP/2 ping is waited for before closing the connection, 20
+    /// seconds by default.
+    pub http2_keep_alive_timeout: Duration,
+
+    /// Time clients have to send the headers of a request, so that slow ones
+    /// can't hold connections open. 30 seconds by default, unlimited when
+    /// unset.
+    pub header_read_timeout: Option<Duration>,
+
+    /// Maximum number of connections served at once, further ones being
+    /// closed as soon as they are accepted. Unlimited by default.
+    pub max_connections: Option<usize>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            http1_keep_alive: true,
+            tcp_keep_alive: Some(Duration::from_secs(60)),
+            http2_keep_alive_interval: Some(Duration::from_secs(20)),
+            http2_keep_alive_timeout: Duration::from_secs(20),
+            header_read_timeout: Some(Duration::from_secs(30)),
+            max_connections: None,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Require HTTP Basic authentication against an htpasswd user store.
+    /// Authentication is disabled when unset.
+    pub auth: Option<AuthConfig>,
+
+    /// Repositories anyone may pull from without authenticating, as patterns
+    /// where `*` matches any sequence of characters, `*` alone making every
+    /// repository public. Pushes always require authentication.
+    pub public_repositories: Vec<String>,
+
+    /// Restrict repository actions per authenticated subject.
+    /// Every action is allowed when unset.
+    pub acl: Option<Arc<Acl>>,
+
+    /// Key signing the upload `_state` tokens. A random key is generated when
+    /// unset, which invalidates uploads in progress whenever the server restarts.
+    pub upload_state_key: Option<Vec<u8>>,
+
+    /// Maximum number of bytes a repository may hold, layers and manifests
+    /// included. Repositories are unlimited when unset.
+    pub max_repository_size: Option<u64>,
+
+    /// Per-repository overrides of `max_repository_size`.
+    pub repository_size_limits: HashMap<String, u64>,
+
+    /// Maximum number of uploads a repository may have open at once, further
+    /// ones being rejected until some are completed or cancelled. Unlimited
+    /// when unset.
+    pub max_concurrent_uploads: Option<usize>,
+
+    /// Maximum number of tags a repository may hold, pushes of new tags being
+    /// rejected past it. Existing tags can still be updated. Unlimited when
+    /// unset.
+    pub max_tags: Option<usize>,
+
+    /// Maximum number of repositories the registry may hold, pushes to new
+    /// repositories being rejected past it. Unlimited when unset.
+    pub max_repositories: Option<usize>,
+
+    /// Maximum number of bytes of a pushed manifest, checked before parsing it.
+    /// `DEFAULT_MAX_MANIFEST_SIZE` when unset.
+    pub max_manifest_size: Option<u64>,
+
+    /// Media types pushed manifests, and their configs, may declare.
+    /// `DEFAULT_MANIFEST_MEDIA_TYPES` when unset.
+    pub manifest_media_types: Option<Vec<String>>,
+
+    /// Serve blobs with the media type the manifests referencing them declare,
+    /// recorded as they are pushed, rather than `application/octet-stream`.
+    pub blob_media_types: bool,
+
+    /// Only serve pulls, rejecting every push.
+    pub read_only: bool,
+
+    /// Serve `DELETE /v2/<name>`, removing a whole repository. Disabled by
+    /// default as, unless an ACL restricts it, anyone could wipe any
+    /// repository.
+    pub delete_repositories: bool,
+
+    /// Build `Location` URLs from the `X-Forwarded-Proto` and `X-Forwarded-Host`
+    /// headers, for a registry behind a reverse proxy setting them. Clients can
+    /// forge these headers, so only enable it when the proxy overwrites them.
+    pub trust_forwarded_headers: bool,
+
+    /// Base of the generated `Location` URLs, e.g. `https://registry.example.com`,
+    /// taking precedence over the request scheme and host.
+    pub external_url: Option<String>,
+
+    /// Hostnames clients may reach the registry at. Requests with any other
+    /// `Host` are rejected, so that generated URLs never point elsewhere.
+    /// Every host is accepted when empty.
+    pub allowed_hosts: Vec<String>,
+
+    /// Translate image manifests to the deprecated, unsigned schema1 format for
+    /// clients only accepting it. See `Schema1Manifest::from_image_manifest`
+    /// for the limitations of the translation.
+    pub schema1_translation: bool,
+
+    /// Value of the `Docker-Distribution-Api-Version` response header.
+    /// `DEFAULT_API_VERSION` when unset, strict clients rejecting others.
+    pub api_version: Option<String>,
+
+    /// Record who pushed and deleted what, successfully or not, in an audit
+    /// trail kept apart from the `tracing` logs. Disabled when unset.
+    pub audit_log: Option<Arc<AuditLog>>,
+
+    /// Keep-alive, timeouts and connection limit of the HTTP server.
+    pub http: HttpConfig,
+}
+
+impl Config {
+    pub fn repository_size_limit(&self, name: &str) -> Option<u64> {
+        self.repository_size_limits
+            .get(name)
+            .copied()
+            .or(self.max_repository_size)
+    }
+
+    pub fn is_public_repository(&self, name: &str) -> bool {
+        self.public_repositories
+            .iter()
+            .any(|pattern| matches_pattern(pattern, name))
+    }
+
+    pub fn api_version(&self) -> &str {
+        self.api_version.as_deref().unwrap_or(DEFAULT_API_VERSION)
+    }
+
+    pub fn manifest_size_limit(&self) -> u64 {
+        self.max_manifest_size.unwrap_or(DEFAULT_MAX_MANIFEST_SIZE)
+    }
+
+    pub fn is_allowed_media_type(&self, media_type: &str) -> bool {
+        match &self.manifest_media_types {
+            Some(media_types) => media_types.iter().any(|allowed| allowed == media_type),
+            None => DEFAULT_MANIFEST_MEDIA_TYPES.contains(&media_type),
+        }
+    }
+
+    /// Whether `host`, with or without its port, is in `allowed_hosts`.
+    pub fn is_allowed_host(&self, host: &str) -> bool {
+        if self.allowed_hosts.is_empty() {
+            return true;
+        }
+
+        let hostname = match host.rsplit_once(':') {
+            Some((hostname, port)) if port.chars().all(|c| c.is_ascii_digit()) => hostname,
+            _ => host,
+        };
+
+        self.allowed_hosts.iter().any(|allowed| {
+            allowed.eq_ignore_ascii_case(host) || allowed.eq_ignore_ascii_case(hostname)
+        })
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    error::Error,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tower::Service;
+
+/// Makes the service of each connection while fewer than a maximum are open,
+/// hyper closing the connections it fails to make a service for.
+#[derive(Clone)]
+pub struct ConnectionLimit<M> {
+    inner: M,
+    permits: Option<Arc<Semaphore>>,
+}
+
+impl<M> ConnectionLimit<M> {
+    /// Limits connections to `max_connections`, unlimited when `None`.
+    pub fn new(inner: M, max_connections: Option<usize>) -> ConnectionLimit<M> {
+        ConnectionLimit {
+            inner,
+            permits: max_connections.map(|max| Arc::new(Semaphore::new(max))),
+        }
+    }
+}
+
+impl<M, T> Service<T> for ConnectionLimit<M>
+where
+    M: Service<T>,
+    M::Error: Into<Box<dyn Error + Send + Sync>>,
+    M::Future: Send + 'static,
+{
+    type Response = Limited<M::Response>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        let permit = match &self.permits {
+            Some(permits) => match permits.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    tracing::debug!("Connection refused, too many are open");
+                    return Box::pin(async { Err("Too many connections".into()) });
+                }
+            },
+            None => None,
+        };
+
+        let service = self.inner.call(target);
+        Box::pin(async move {
+            Ok(Limited {
+                inner: service.await.map_err(Into::into)?,
+                _permit: permit,
+            })
+        })
+    }
+}
+
+/// Service of a connection, holding its slot until the connection closes.
+pub struct Limited<S> {
+    inner: S,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<S, R> Service<R> for Limited<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        self.inner.call(request)
+    }
+}
@@ -4,7 +4,7 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
-use hyper::StatusCode;
+use hyper::{http, Body, StatusCode};
 use lazy_static::lazy_static;
 use serde::Serialize;
 
@@ -27,6 +27,10 @@ pub enum RegistryErrorCode {
     Unauthorized,
     Denied,
     Unsupported,
+    /// Rate limits, clients being expected to retry later.
+    TooManyRequests,
+    /// Failures the client can't do anything about, such as storage errors.
+    Unknown,
 }
 
 lazy_static! {
@@ -55,6 +59,8 @@ lazy_static! {
         m.insert(RegistryErrorCode::Unauthorized, "UNAUTHORIZED");
         m.insert(RegistryErrorCode::Denied, "DENIED");
         m.insert(RegistryErrorCode::Unsupported, "UNSUPPORTED");
+        m.insert(RegistryErrorCode::TooManyRequests, "TOOMANYREQUESTS");
+        m.insert(RegistryErrorCode::Unknown, "UNKNOWN");
         m
     };
 }
@@ -109,6 +115,8 @@ lazy_static! {
             RegistryErrorCode::Unsupported,
             "The operation is unsupported.",
         );
+        m.insert(RegistryErrorCode::TooManyRequests, "too many requests");
+        m.insert(RegistryErrorCode::Unknown, "unknown error");
         m
     };
 }
@@ -133,6 +141,14 @@ impl RegistryError {
     pub fn new(status: StatusCode, code: RegistryErrorCode) -> RegistryError {
         RegistryError { status, code }
     }
+
+    /// `500` for failures on the registry side, which still get an error body.
+    pub fn internal() -> RegistryError {
+        RegistryError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            RegistryErrorCode::Unknown,
+        )
+    }
 }
 
 impl IntoResponse for RegistryError {
@@ -149,3 +165,22 @@ impl IntoResponse for RegistryError {
             .into_response()
     }
 }
+
+/// Turns responses built with `Response::builder()` into `500`s when they
+/// couldn't be built, e.g. over a header value with a control character,
+/// rather than panicking.
+pub trait BuiltResponse {
+    fn or_internal(self) -> Response;
+}
+
+impl BuiltResponse for http::Result<http::Response<Body>> {
+    fn or_internal(self) -> Response {
+        match self {
+            Ok(response) => response.into_response(),
+            Err(e) => {
+                eprintln!("{}", e);
+                RegistryError::internal().into_response()
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use axum::{body::BoxBody, middleware::Next, response::Response};
+use hyper::Request;
+
+use crate::{
+    api::v2::{
+        audit::{client_ip, request_action, AuditRecord},
+        state::SharedState,
+    },
+    auth::{Action, Subject},
+};
+
+/// Records the outcome of the repository operations in `Config::audit_log`,
+/// the subject being the one the auth middleware attached to the response.
+pub async fn audit_middleware(request: Request<BoxBody>, next: Next<BoxBody>) -> Response {
+    let config = match request.extensions().get::<SharedState>() {
+        Some(state) if state.config.audit_log.is_some() => state.config.clone(),
+        _ => return next.run(request).await,
+    };
+    let audit_log = config.audit_log.clone().unwrap();
+
+    let action = match request_action(&request) {
+        Some(action) if action.0 != Action::Pull || audit_log.include_pulls => action,
+        _ => return next.run(request).await,
+    };
+    let client_ip = client_ip(&request, config.trust_forwarded_headers);
+
+    let response = next.run(request).await;
+
+    audit_log.record(&AuditRecord::new(
+        response.extensions().get::<Subject>(),
+        client_ip,
+        action,
+        response.status(),
+    ));
+
+    response
+}
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use axum::{
+    body::BoxBody,
+    headers::{authorization::Basic, Authorization, HeaderMapExt},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::{Method, Request, StatusCode};
+
+use crate::{
+    api::v2::{
+        config::Config,
+        errors::{RegistryError, RegistryErrorCode},
+        state::SharedState,
+    },
+    auth::Subject,
+};
+
+fn unauthorized(realm: &str) -> Response {
+    (
+        [(
+            header::WWW_AUTHENTICATE,
+            format!("Basic realm=\"{}\"", realm),
+        )],
+        RegistryError::new(StatusCode::UNAUTHORIZED, RegistryErrorCode::Unauthorized),
+    )
+        .into_response()
+}
+
+/// Whether `request` only reads from a public repository, which anonymous
+/// clients are then allowed to.
+fn is_public_pull<B>(request: &Request<B>, config: &Config) -> bool {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return false;
+    }
+
+    let name = match request.uri().path().strip_prefix("/v2/") {
+        Some(path) => path.split('/').next().unwrap_or_default(),
+        None => return false,
+    };
+
+    !name.is_empty() && !name.starts_with('_') && config.is_public_repository(name)
+}
+
+pub async fn auth_middleware(
+    mut request: Request<BoxBody>,
+    next: Next<BoxBody>,
+) -> Result<impl IntoResponse, Response> {
+    let config = match request.extensions().get::<SharedState>() {
+        Some(state) => state.config.clone(),
+        None => return Ok(next.run(request).await),
+    };
+
+    // Probes outside of the registry API, such as /readyz, are anonymous
+    let auth = match &config.auth {
+        Some(auth) if request.uri().path().starts_with("/v2") => auth.clone(),
+        _ => return Ok(next.run(request).await),
+    };
+
+    let credentials = match request.headers().typed_get::<Authorization<Basic>>() {
+        Some(Authorization(basic)) => basic,
+        None if is_public_pull(&request, &config) => return Ok(next.run(request).await),
+        None => return Err(unauthorized(&auth.realm)),
+    };
+
+    let users = Arc::clone(&auth.users);
+    let username = credentials.username().to_string();
+    let password = credentials.password().to_string();
+
+    // bcrypt is deliberately slow, keep it off the async workers
+    let verified = tokio::task::spawn_blocking(move || {
+        users
+            .verify(&username, &password)
+            .map(|verified| verified.then_some(Subject { name: username }))
+    })
+    .await;
+
+    match verified {
+        Ok(Ok(Some(subject))) => {
+            request.extensions_mut().insert(subject.clone());
+
+            // Exposed to the outer middlewares, such as the audit one
+            let mut response = next.run(request).await;
+            response.extensions_mut().insert(subject);
+            Ok(response)
+        }
+        Ok(Ok(None)) => Err(unauthorized(&auth.realm)),
+        Ok(Err(e)) => {
+            eprintln!("{}", e);
+            Err(RegistryError::internal().into_response())
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(RegistryError::internal().into_response())
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use axum::{
+    body::BoxBody,
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::{Request, StatusCode};
+
+use crate::api::v2::state::SharedState;
+
+/// Rejects requests whose `Host` isn't in `Config::allowed_hosts`. Probes
+/// outside of the registry API generate no URL and are let through.
+pub async fn host_middleware(
+    request: Request<BoxBody>,
+    next: Next<BoxBody>,
+) -> Result<impl IntoResponse, Response> {
+    let config = match request.extensions().get::<SharedState>() {
+        Some(state)
+            if !state.config.allowed_hosts.is_empty()
+                && request.uri().path().starts_with("/v2") =>
+        {
+            state.config.clone()
+        }
+        _ => return Ok(next.run(request).await),
+    };
+
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(|host| host.to_string())
+        .or_else(|| {
+            request
+                .uri()
+                .authority()
+                .map(|authority| authority.to_string())
+        });
+
+    match host {
+        Some(host) if config.is_allowed_host(&host) => Ok(next.run(request).await),
+        _ => Err(StatusCode::BAD_REQUEST.into_response()),
+    }
+}
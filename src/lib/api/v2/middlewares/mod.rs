@@ -1,3 +1,11 @@
+mod audit_middleware;
+mod auth_middleware;
+mod host_middleware;
+mod panic_middleware;
 mod version_header_middleware;
 
+pub use audit_middleware::*;
+pub use auth_middleware::*;
+pub use host_middleware::*;
+pub use panic_middleware::*;
 pub use version_header_middleware::*;
@@ -0,0 +1,18 @@
+use std::any::Any;
+
+use axum::response::{IntoResponse, Response};
+
+use crate::api::v2::errors::RegistryError;
+
+/// Answers requests whose handler panicked with a `500` and the usual error
+/// body, rather than dropping the connection. Used with `CatchPanicLayer`.
+pub fn panic_response(panic: Box<dyn Any + Send + 'static>) -> Response {
+    let message = match panic.downcast_ref::<String>() {
+        Some(message) => message.as_str(),
+        None => panic.downcast_ref::<&str>().copied().unwrap_or("unknown"),
+    };
+
+    tracing::error!("Request handler panicked: {}", message);
+
+    RegistryError::internal().into_response()
+}
@@ -6,15 +6,23 @@ use axum::{
 };
 use hyper::Request;
 
+use crate::api::v2::{config::DEFAULT_API_VERSION, state::SharedState};
+
+/// Sets `Docker-Distribution-Api-Version` to the version in the shared state,
+/// which must then be layered outside of this middleware.
 pub async fn version_header_middleware(
     request: Request<BoxBody>,
     next: Next<BoxBody>,
 ) -> Result<impl IntoResponse, Response> {
+    let version = match request.extensions().get::<SharedState>() {
+        Some(state) => state.api_version.clone(),
+        None => HeaderValue::from_static(DEFAULT_API_VERSION),
+    };
+
     let mut response = next.run(request).await;
-    response.headers_mut().insert(
-        "Docker-Distribution-Api-Version",
-        HeaderValue::from_static("rustgistry/2"),
-    );
+    response
+        .headers_mut()
+        .insert("Docker-Distribution-Api-Version", version);
 
     Ok(response)
 }
@@ -1,7 +1,18 @@
+pub mod audit;
+mod base_url;
+mod builder;
+pub mod config;
+mod connection_limit;
 mod errors;
 mod middlewares;
 mod routes;
 mod state;
+#[cfg(unix)]
+mod unix;
+mod upload_state;
+
+#[cfg(test)]
+mod tests;
 
 use std::{
     error::Error,
@@ -10,42 +21,99 @@ use std::{
 };
 
 use axum::{
-    body, middleware,
-    routing::{get, head, patch, post, put, IntoMakeService},
+    body,
+    extract::connect_info::IntoMakeServiceWithConnectInfo,
+    middleware,
+    routing::{delete, get, head, patch, post, put},
     Extension, Router, Server,
 };
-use hyper::{server::conn::AddrIncoming, Body};
+use futures::future::BoxFuture;
+use hyper::{
+    server::{conn::AddrIncoming, Builder},
+    Body,
+};
+use rand::Rng;
+use tokio::sync::oneshot;
 use tower::ServiceBuilder;
-use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 use tower_http::ServiceBuilderExt;
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    trace::{DefaultMakeSpan, TraceLayer},
+};
 
 use crate::storage::Storage;
 
-use self::state::SharedState;
+pub use self::builder::ApiV2Builder;
+use self::{config::Config, connection_limit::ConnectionLimit, state::SharedState};
+
+type MakeService = ConnectionLimit<IntoMakeServiceWithConnectInfo<Router<Body>, SocketAddr>>;
 
+/// Docker Registry HTTP API V2 server.
+///
+/// Requests are traced through `tracing`, but no subscriber is installed by the
+/// library: applications embedding `ApiV2` are expected to initialize their own
+/// (e.g. `tracing_subscriber::fmt::init()`) before calling [`ApiV2::listen`].
+///
+/// Applications wanting their own middlewares can wrap [`ApiV2::router`] in
+/// their tower layers and serve it themselves.
 pub struct ApiV2 {
     addr: SocketAddr,
     storage: Arc<dyn Storage>,
+    config: Arc<Config>,
 
-    server: Option<Server<AddrIncoming, IntoMakeService<Router<Body>>>>,
+    server: Option<Server<AddrIncoming, MakeService>>,
 }
 
 impl ApiV2 {
     pub fn new(host: Ipv4Addr, port: u16, storage: Arc<dyn Storage>) -> ApiV2 {
+        ApiV2::with_config(host, port, storage, Config::default())
+    }
+
+    pub fn with_config(
+        host: Ipv4Addr,
+        port: u16,
+        storage: Arc<dyn Storage>,
+        config: Config,
+    ) -> ApiV2 {
+        ApiV2::from_parts(SocketAddr::from((host, port)), storage, config)
+    }
+
+    pub fn builder() -> ApiV2Builder {
+        ApiV2Builder::default()
+    }
+
+    fn from_parts(addr: SocketAddr, storage: Arc<dyn Storage>, mut config: Config) -> ApiV2 {
+        if config.upload_state_key.is_none() {
+            config.upload_state_key = Some(rand::thread_rng().gen::<[u8; 32]>().to_vec());
+        }
+
+        if config.allowed_hosts.is_empty() && config.external_url.is_none() {
+            tracing::warn!(
+                "No allowed hosts configured, generated URLs are based on the Host clients send"
+            );
+        }
+
         ApiV2 {
-            addr: SocketAddr::from((host, port)),
+            addr,
             storage,
+            config: Arc::new(config),
             server: None,
         }
     }
 
-    pub async fn listen(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let app_state = SharedState::new(Arc::clone(&self.storage));
-
-        tracing_subscriber::fmt::init();
+    pub fn router(&self) -> Router<Body> {
+        let app_state = SharedState::new(Arc::clone(&self.storage), Arc::clone(&self.config));
 
-        let router = Router::new()
+        Router::new()
+            .route("/readyz", get(routes::health::get_readiness))
             .route("/v2", get(routes::version::get_version))
+            .route("/v2", head(routes::version::head_version))
+            .route("/v2/", get(routes::version::get_version))
+            .route("/v2/", head(routes::version::head_version))
+            .route("/v2/_catalog", get(routes::repositories::get_catalog))
+            .route("/v2/_uploads", get(routes::blobs::list_uploads))
+            .route("/v2/:name/tags/list", get(routes::repositories::get_tags))
+            .route("/v2/:name", delete(routes::repositories::delete_repository))
             .route(
                 "/v2/:name/manifests/:reference",
                 head(routes::manifests::get_manifest_info),
@@ -58,6 +126,14 @@ impl ApiV2 {
                 "/v2/:name/manifests/:reference",
                 put(routes::manifests::put_manifest),
             )
+            .route(
+                "/v2/:name/manifests/:reference",
+                post(routes::manifests::validate_manifest),
+            )
+            .route(
+                "/v2/:name/referrers/:digest",
+                get(routes::referrers::get_referrers),
+            )
             .route(
                 "/v2/:name/blobs/uploads/",
                 post(routes::blobs::start_upload_process),
@@ -72,18 +148,52 @@ impl ApiV2 {
             )
             .route("/v2/:name/blobs/:digest", head(routes::blobs::exists))
             .route("/v2/:name/blobs/:digest", get(routes::blobs::get_layer))
-            .layer(Extension(app_state))
+            .layer(middleware::from_fn(middlewares::auth_middleware))
+            .layer(middleware::from_fn(middlewares::audit_middleware))
+            .layer(middleware::from_fn(middlewares::host_middleware))
+            .layer(middleware::from_fn(middlewares::version_header_middleware))
             .layer(
                 ServiceBuilder::new()
                     .map_request_body(body::boxed)
-                    .layer(middleware::from_fn(middlewares::version_header_middleware)),
+                    .layer(Extension(app_state)),
             )
+            .layer(CatchPanicLayer::custom(middlewares::panic_response))
             .layer(
                 TraceLayer::new_for_http()
                     .make_span_with(DefaultMakeSpan::new().include_headers(true)),
-            );
+            )
+    }
+
+    /// Applies the settings of `Config::http` not specific to TCP.
+    fn configure<I>(&self, mut builder: Builder<I>) -> Builder<I> {
+        let http = &self.config.http;
+
+        builder = builder
+            .http1_keepalive(http.http1_keep_alive)
+            .http2_keep_alive_interval(http.http2_keep_alive_interval)
+            .http2_keep_alive_timeout(http.http2_keep_alive_timeout);
 
-        let server = axum::Server::bind(&self.addr).serve(router.into_make_service());
+        if let Some(timeout) = http.header_read_timeout {
+            builder = builder.http1_header_read_timeout(timeout);
+        }
+
+        builder
+    }
+
+    /// Serves the router on a bound server, configured after `Config::http`.
+    fn serve_with(&self, builder: Builder<AddrIncoming>) -> Server<AddrIncoming, MakeService> {
+        let http = &self.config.http;
+        let builder = self.configure(builder.tcp_keepalive(http.tcp_keep_alive));
+
+        builder.serve(ConnectionLimit::new(
+            self.router()
+                .into_make_service_with_connect_info::<SocketAddr>(),
+            http.max_connections,
+        ))
+    }
+
+    pub async fn listen(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let server = self.serve_with(axum::Server::bind(&self.addr));
         self.server = Some(server);
 
         self.server.as_mut().unwrap().await?;
@@ -91,6 +201,71 @@ impl ApiV2 {
         Ok(())
     }
 
+    /// Binds the server, returning the future running it along with a handle
+    /// to shut it down gracefully.
+    pub fn serve(
+        &self,
+    ) -> Result<(BoxFuture<'static, hyper::Result<()>>, ServerHandle), hyper::Error> {
+        let server = self.serve_with(axum::Server::try_bind(&self.addr)?);
+        let local_addr = server.local_addr();
+
+        let (shutdown, receiver) = oneshot::channel();
+        let server = server.with_graceful_shutdown(async {
+            receiver.await.ok();
+        });
+
+        Ok((
+            Box::pin(server),
+            ServerHandle {
+                local_addr,
+                shutdown,
+            },
+        ))
+    }
+
+    /// Serves on a Unix domain socket at `path` rather than on the TCP
+    /// address, until `shutdown` resolves. The socket file is removed once
+    /// the requests in flight are served.
+    ///
+    /// Clients connected to a socket have no address the registry could be
+    /// reached at, so `Config::external_url` must be set for the generated
+    /// URLs.
+    #[cfg(unix)]
+    pub fn serve_unix<P, F>(
+        &self,
+        path: P,
+        shutdown: F,
+    ) -> std::io::Result<BoxFuture<'static, hyper::Result<()>>>
+    where
+        P: AsRef<std::path::Path>,
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        use futures::FutureExt;
+
+        if self.config.external_url.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "An external URL must be configured to serve on a Unix socket",
+            ));
+        }
+
+        let path = path.as_ref().to_path_buf();
+        let incoming = unix::UnixIncoming::bind(&path)?;
+
+        let server = self
+            .configure(Server::builder(incoming))
+            .serve(ConnectionLimit::new(
+                self.router().into_make_service(),
+                self.config.http.max_connections,
+            ))
+            .with_graceful_shutdown(shutdown);
+
+        Ok(Box::pin(server.map(move |result| {
+            std::fs::remove_file(&path).ok();
+            result
+        })))
+    }
+
     pub async fn graceful_shutdown(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
         if let Some(server) = self.server.take() {
             let graceful = server.with_graceful_shutdown(async {
@@ -105,3 +280,22 @@ impl ApiV2 {
         Err("Server not running".into())
     }
 }
+
+/// Handle on a server started with [`ApiV2::serve`].
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+}
+
+impl ServerHandle {
+    /// Address the server listens on, with the actual port when bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting connections, the server future resolving once the
+    /// requests in flight are served.
+    pub fn shutdown(self) {
+        self.shutdown.send(()).ok();
+    }
+}
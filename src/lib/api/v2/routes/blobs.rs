@@ -1,26 +1,74 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
 use axum::{
-    extract::{BodyStream, Host, Path, Query},
-    http::Uri,
+    extract::{BodyStream, Path, Query},
     response::{IntoResponse, Response},
-    Extension,
+    Extension, Json,
 };
-use futures::StreamExt;
-use hyper::{Body, HeaderMap, StatusCode};
-use serde::Deserialize;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use hyper::{header, header::HeaderValue, Body, HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
 
-use crate::api::v2::errors::{RegistryError, RegistryErrorCode};
-use crate::{api::v2::state::SharedState, storage::Error};
+use crate::api::v2::{
+    base_url::{encode_path, BaseUrl},
+    errors::{BuiltResponse, RegistryError, RegistryErrorCode},
+};
+use crate::{
+    api::v2::state::SharedState,
+    auth::{Action, Subject},
+    storage::{
+        canonical_digest, is_digest_invalid, is_not_found, is_range_invalid, Error, Result,
+        UploadInfo,
+    },
+};
 
 pub async fn start_upload_process(
-    uri: Uri,
-    Host(hostname): Host,
+    BaseUrl(base_url): BaseUrl,
     Path(name): Path<String>,
     Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
 ) -> impl IntoResponse {
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Push) {
+        return e.into_response();
+    }
+
+    match state.exceeds_repository_limit(&name).await {
+        Ok(true) => {
+            return RegistryError::new(StatusCode::FORBIDDEN, RegistryErrorCode::Denied)
+                .into_response()
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return RegistryError::internal().into_response();
+        }
+        _ => {}
+    }
+
+    if let Some(limit) = state.config.max_concurrent_uploads {
+        match state.storage.list_uploads(Some(name.clone())).await {
+            Ok(uploads) if uploads.len() >= limit => {
+                return RegistryError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    RegistryErrorCode::TooManyRequests,
+                )
+                .into_response()
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("{}", e);
+                return RegistryError::internal().into_response();
+            }
+        }
+    }
+
     let upload_info_result = state.storage.create_upload_container(name.clone()).await;
     if let Err(e) = upload_info_result {
         eprintln!("{}", e);
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        return RegistryError::internal().into_response();
     }
 
     let upload_info = upload_info_result.unwrap();
@@ -30,19 +78,17 @@ pub async fn start_upload_process(
         .header(
             "Location",
             format!(
-                "{}://{}/v2/{}/blobs/uploads/{}?_state={}",
-                uri.scheme_str().unwrap_or("http"),
-                hostname,
-                name,
+                "{}/v2/{}/blobs/uploads/{}?_state={}",
+                base_url,
+                encode_path(&name),
                 upload_info.uuid,
-                upload_info.state,
+                state.sign_upload_state(&upload_info.state),
             ),
         )
-        .header("Range", "0-0")
+        .header("Range", upload_range(0))
         .status(StatusCode::ACCEPTED)
         .body(Body::empty())
-        .unwrap()
-        .into_response()
+        .or_internal()
 }
 
 #[derive(Deserialize)]
@@ -52,15 +98,24 @@ pub struct MonolithicUploadQuery {
     pub digest: Option<String>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn receive_upload_monolithic(
-    uri: Uri,
-    Host(hostname): Host,
+    BaseUrl(base_url): BaseUrl,
     Path((name, uuid)): Path<(String, String)>,
     query: Query<MonolithicUploadQuery>,
     headers: HeaderMap,
     Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
     mut body: BodyStream,
 ) -> impl IntoResponse {
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Push) {
+        return e.into_response();
+    }
+
+    if let Err(e) = state.verify_upload_state(&query._state, &name, &uuid) {
+        return e.into_response();
+    }
+
     let validity_result = state
         .storage
         .check_upload_container_validity(name.clone(), uuid.clone())
@@ -73,86 +128,156 @@ pub async fn receive_upload_monolithic(
         }
         Err(e) => {
             eprintln!("{}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            return RegistryError::internal().into_response();
         }
         _ => {}
     }
 
     let content_length = match headers.get("Content-Length") {
-        Some(v) => match v.to_str() {
-            Ok(v) => match v.parse::<usize>() {
-                Ok(v) => v,
-                Err(_) => {
-                    return RegistryError::new(
-                        StatusCode::BAD_REQUEST,
-                        RegistryErrorCode::BlobUploadInvalid,
-                    )
-                    .into_response()
-                }
-            },
-            Err(_) => 0,
+        Some(v) => match v.to_str().ok().and_then(|v| v.parse::<u64>().ok()) {
+            Some(v) => v,
+            None => {
+                return RegistryError::new(
+                    StatusCode::BAD_REQUEST,
+                    RegistryErrorCode::BlobUploadInvalid,
+                )
+                .into_response()
+            }
         },
         None => 0,
     };
 
-    if content_length > 0 {
-        let buffer =
+    // Chunked pushes carry no Content-Length, so the body is streamed until it
+    // ends whatever the header says, only skipping the write when it's empty
+    if let Some(first_chunk) = body.next().await {
+        let first_chunk = match first_chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                eprintln!("{}", e);
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+        };
+
+        let buffer = futures::stream::once(async move { Ok(first_chunk) }).chain(
             futures::stream::poll_fn(move |cx| body.poll_next_unpin(cx)).map(|chunk| match chunk {
                 Ok(chunk) => Ok(chunk),
                 Err(e) => Err(Error::from(e)),
-            });
+            }),
+        );
+
+        // The final chunk of a chunked push follows those already uploaded
+        let uploaded = match state
+            .storage
+            .get_upload_status(name.clone(), uuid.clone())
+            .await
+        {
+            Ok(status) => status.size,
+            Err(e) => {
+                eprintln!("{}", e);
+                return RegistryError::internal().into_response();
+            }
+        };
+
+        if let Err(e) = check_upload_quota(
+            &state,
+            &name,
+            &uuid,
+            query.digest.as_deref(),
+            uploaded + content_length,
+        )
+        .await
+        {
+            return e.into_response();
+        }
 
-        if let Err(e) = state
+        let status = match state
             .storage
             .write_upload_container(
                 name.clone(),
                 uuid.clone(),
                 Box::pin(buffer),
-                (0, content_length as u64),
+                (uploaded, uploaded + content_length),
             )
             .await
         {
-            eprintln!("{}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            Ok(status) => status,
+            Err(e) => {
+                eprintln!("{}", e);
+                return RegistryError::internal().into_response();
+            }
+        };
+
+        // Bodies may be longer than their Content-Length says
+        if let Err(e) =
+            check_upload_quota(&state, &name, &uuid, query.digest.as_deref(), status.size).await
+        {
+            return e.into_response();
         }
     }
 
     match state
         .storage
-        .close_upload_container(name.clone(), uuid.clone())
+        .close_upload_container(name.clone(), uuid.clone(), query.digest.clone())
         .await
     {
-        Ok(details) => {
-            if let Some(digest) = &query.digest {
-                if *digest != details.digest {
-                    return RegistryError::new(
-                        StatusCode::BAD_REQUEST,
-                        RegistryErrorCode::DigestInvalid,
-                    )
-                    .into_response();
-                }
-            }
-
-            Response::builder()
-                .status(StatusCode::CREATED)
-                .header("Docker-Content-Digest", &details.digest)
-                .header(
-                    "Location",
-                    format!(
-                        "{}://{}/v2/{}/blobs/{}",
-                        uri.scheme_str().unwrap_or("http"),
-                        hostname,
-                        name,
-                        details.digest,
-                    ),
-                )
-                .body(Body::empty())
-                .unwrap()
+        Ok(details) => Response::builder()
+            .status(StatusCode::CREATED)
+            .header("Docker-Content-Digest", &details.digest)
+            .header(
+                "Location",
+                format!(
+                    "{}/v2/{}/blobs/{}",
+                    base_url,
+                    encode_path(&name),
+                    details.digest,
+                ),
+            )
+            .body(Body::empty())
+            .or_internal(),
+        Err(e) if is_digest_invalid(&e) => {
+            RegistryError::new(StatusCode::BAD_REQUEST, RegistryErrorCode::DigestInvalid)
                 .into_response()
         }
         Err(e) => {
             eprintln!("{}", e);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            RegistryError::internal().into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListUploadsQuery {
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UploadList {
+    uploads: Vec<UploadInfo>,
+}
+
+/// Uploads in progress, in repository `name` if given, restricted to the
+/// repositories the client may push to.
+pub async fn list_uploads(
+    query: Query<ListUploadsQuery>,
+    Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
+) -> impl IntoResponse {
+    match state.storage.list_uploads(query.name.clone()).await {
+        Ok(uploads) => Json(UploadList {
+            uploads: uploads
+                .into_iter()
+                .filter(|upload| {
+                    state
+                        .authorize(subject.as_deref(), &upload.name, Action::Push)
+                        .is_ok()
+                })
+                .collect(),
+        })
+        .into_response(),
+        Err(e) => {
+            eprintln!("{}", e);
+            RegistryError::internal().into_response()
         }
     }
 }
@@ -162,12 +287,43 @@ pub struct ChunkedUploadQuery {
     pub _state: String,
 }
 
+/// Inclusive range of the bytes received so far, which the next chunk starts
+/// right after. An empty upload is reported as `0-0`.
+fn upload_range(size: u64) -> String {
+    format!("0-{}", size.saturating_sub(1))
+}
+
+/// Parses a `Content-Range` header, `<start>-<end>` as sent by Docker clients,
+/// optionally in the `bytes <start>-<end>/<size>` form.
+fn parse_content_range(value: &str) -> Option<(u64, u64)> {
+    let value = value.trim();
+    let value = value.strip_prefix("bytes ").unwrap_or(value);
+    let value = value.split('/').next()?;
+
+    let (start, end) = value.split_once('-')?;
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+
+    (start <= end).then_some((start, end))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn receive_upload_chunked(
+    BaseUrl(base_url): BaseUrl,
     Path((name, uuid)): Path<(String, String)>,
-    _query: Query<ChunkedUploadQuery>,
+    query: Query<ChunkedUploadQuery>,
+    headers: HeaderMap,
     Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
     mut body: BodyStream,
 ) -> impl IntoResponse {
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Push) {
+        return e.into_response();
+    }
+
+    if let Err(e) = state.verify_upload_state(&query._state, &name, &uuid) {
+        return e.into_response();
+    }
+
     let validity_result = state
         .storage
         .check_upload_container_validity(name.clone(), uuid.clone())
@@ -180,11 +336,55 @@ pub async fn receive_upload_chunked(
         }
         Err(e) => {
             eprintln!("{}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            return RegistryError::internal().into_response();
         }
         _ => {}
     }
 
+    let upload_status = match state
+        .storage
+        .get_upload_status(name.clone(), uuid.clone())
+        .await
+    {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("{}", e);
+            return RegistryError::internal().into_response();
+        }
+    };
+
+    // Chunks must follow each other, a gap would corrupt the blob. A chunk
+    // starting before the end is a retry, which storages able to rewrite
+    // what was uploaded accept. Chunks without a Content-Range are appended.
+    let range = match headers.get("Content-Range") {
+        Some(value) => match value.to_str().ok().and_then(parse_content_range) {
+            Some(range) if range.0 <= upload_status.size => range,
+            _ => {
+                return RegistryError::new(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    RegistryErrorCode::RangeInvalid,
+                )
+                .into_response()
+            }
+        },
+        None => (upload_status.size, upload_status.size),
+    };
+
+    // Checked before storing the chunk as well, so that clients past their
+    // quota can't fill the storage with chunks rejected afterwards
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .unwrap_or(0);
+    let declared_size = if headers.contains_key("Content-Range") {
+        (range.0 + content_length).max(range.1 + 1)
+    } else {
+        range.0 + content_length
+    };
+    if let Err(e) = check_upload_quota(&state, &name, &uuid, None, declared_size).await {
+        return e.into_response();
+    }
+
     let buffer =
         futures::stream::poll_fn(move |cx| body.poll_next_unpin(cx)).map(|chunk| match chunk {
             Ok(chunk) => Ok(chunk),
@@ -193,36 +393,113 @@ pub async fn receive_upload_chunked(
 
     let status_result = state
         .storage
-        .write_upload_container(name, uuid, Box::pin(buffer), (1, 2))
+        .write_upload_container(name.clone(), uuid.clone(), Box::pin(buffer), range)
         .await;
 
     if let Err(e) = status_result {
+        if is_range_invalid(&e) {
+            return RegistryError::new(
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                RegistryErrorCode::RangeInvalid,
+            )
+            .into_response();
+        }
+
         eprintln!("{}", e);
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        return RegistryError::internal().into_response();
     }
 
     let status = status_result.unwrap();
 
-    let response = Response::builder()
+    if let Err(e) = check_upload_quota(&state, &name, &uuid, None, status.size).await {
+        return e.into_response();
+    }
+
+    Response::builder()
         .status(StatusCode::ACCEPTED)
-        .header("Range", format!("0-{}", status.size))
+        .header("Docker-Upload-UUID", &uuid)
+        .header(
+            "Location",
+            format!(
+                "{}/v2/{}/blobs/uploads/{}?_state={}",
+                base_url,
+                encode_path(&name),
+                encode_path(&uuid),
+                query._state,
+            ),
+        )
+        .header("Range", upload_range(status.size))
         .body(Body::empty())
-        .unwrap();
+        .or_internal()
+}
+
+/// Rejects taking upload `uuid` to `size` bytes when that would take
+/// repository `name` past its quota, cancelling the upload so that the bytes
+/// it received don't linger. Uploads of a `digest` already in the storage's
+/// shared blob store take no space, and aren't charged unless larger than it.
+async fn check_upload_quota(
+    state: &SharedState,
+    name: &str,
+    uuid: &str,
+    digest: Option<&str>,
+    size: u64,
+) -> std::result::Result<(), RegistryError> {
+    let exceeds = match (state.exceeds_quota(name, size).await, digest) {
+        (Ok(true), Some(digest)) => state
+            .storage
+            .get_shared_blob_size(digest.to_string())
+            .await
+            .map(|shared_size| shared_size.is_none_or(|shared_size| size > shared_size)),
+        (result, _) => result,
+    };
 
-    response.into_response()
+    match exceeds {
+        Ok(false) => Ok(()),
+        Ok(true) => {
+            if let Err(e) = state
+                .storage
+                .delete_upload_container(name.to_string(), uuid.to_string())
+                .await
+            {
+                eprintln!("{}", e);
+            }
+
+            Err(RegistryError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                RegistryErrorCode::Denied,
+            ))
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(RegistryError::internal())
+        }
+    }
 }
 
 pub async fn exists(
     Path((name, digest)): Path<(String, String)>,
     Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
 ) -> impl IntoResponse {
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Pull) {
+        return e.into_response();
+    }
+
+    let digest = match canonical_digest(&digest) {
+        Some(digest) => digest,
+        None => {
+            return RegistryError::new(StatusCode::BAD_REQUEST, RegistryErrorCode::DigestInvalid)
+                .into_response()
+        }
+    };
+
     let layer_info_result = state
         .storage
-        .get_image_layer_info(name, digest.clone())
+        .get_image_layer_info(name.clone(), digest.clone())
         .await;
     if let Err(e) = layer_info_result {
         eprintln!("{}", e);
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        return RegistryError::internal().into_response();
     }
 
     let layer_info_option = layer_info_result.unwrap();
@@ -233,49 +510,289 @@ pub async fn exists(
             .header("Content-Length", layer_info.size.to_string())
             .header("Docker-Content-Digest", &digest)
             .header("Etag", format!("\"{}\"", digest))
-            .header("Content-Type", "application/octet-stream")
+            .header(
+                "Content-Type",
+                blob_content_type(&state, &name, &digest).await,
+            )
             .body(Body::empty())
-            .unwrap()
-            .into_response(),
+            .or_internal(),
         None => StatusCode::NOT_FOUND.into_response(),
     }
 }
 
+/// Content type to serve blob `digest` with: the media type recorded for it
+/// when `Config::blob_media_types` is set, `application/octet-stream`
+/// otherwise or when none was.
+async fn blob_content_type(state: &SharedState, name: &str, digest: &str) -> HeaderValue {
+    let default = HeaderValue::from_static("application/octet-stream");
+    if !state.config.blob_media_types {
+        return default;
+    }
+
+    match state
+        .storage
+        .get_blob_media_type(name.to_string(), digest.to_string())
+        .await
+    {
+        Ok(Some(media_type)) => HeaderValue::from_str(&media_type).unwrap_or(default),
+        Ok(None) => default,
+        Err(e) => {
+            eprintln!("{}", e);
+            default
+        }
+    }
+}
+
+/// Whether an `If-None-Match` header lists the ETag of blob `digest`, which
+/// the client then already holds as blobs never change.
+fn matches_etag(headers: &HeaderMap, digest: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|etag| etag.trim().trim_start_matches("W/").trim_matches('"'))
+        .any(|etag| etag == "*" || etag == digest)
+}
+
+/// Parses a `Range` header against a blob of `size` bytes into the inclusive
+/// range of bytes to send. Requests for several ranges get the whole blob.
+fn parse_range(value: &str, size: u64) -> std::result::Result<Option<(u64, u64)>, ()> {
+    let value = value.trim().strip_prefix("bytes=").ok_or(())?;
+    if value.contains(',') {
+        return Ok(None);
+    }
+
+    let (start, end) = value.split_once('-').ok_or(())?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let (start, end) = match (start.is_empty(), end.is_empty()) {
+        // Last `end` bytes
+        (true, false) => {
+            let length = end.parse::<u64>().map_err(|_| ())?;
+            if length == 0 {
+                return Err(());
+            }
+            (size.saturating_sub(length), size.saturating_sub(1))
+        }
+        (false, true) => (start.parse().map_err(|_| ())?, size.saturating_sub(1)),
+        (false, false) => {
+            let start = start.parse::<u64>().map_err(|_| ())?;
+            let end = end.parse::<u64>().map_err(|_| ())?;
+            (start, end.min(size.saturating_sub(1)))
+        }
+        (true, true) => return Err(()),
+    };
+
+    if start >= size || end < start {
+        return Err(());
+    }
+
+    Ok(Some((start, end)))
+}
+
+/// Bytes `start` to `end` included of a layer stream, which isn't read past them.
+fn slice_stream<S>(stream: S, start: u64, end: u64) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    stream
+        .scan(0u64, move |offset, chunk| {
+            let chunk_start = *offset;
+            if chunk_start > end {
+                return futures::future::ready(None);
+            }
+
+            let chunk = chunk.map(|chunk| {
+                *offset += chunk.len() as u64;
+
+                let from = start.saturating_sub(chunk_start).min(chunk.len() as u64);
+                let to = (end + 1)
+                    .saturating_sub(chunk_start)
+                    .min(chunk.len() as u64);
+                chunk.slice(from as usize..to as usize)
+            });
+
+            futures::future::ready(Some(chunk))
+        })
+        .filter(|chunk| futures::future::ready(!matches!(chunk, Ok(chunk) if chunk.is_empty())))
+}
+
+/// Layer stream sent to a client. Hyper drops it as soon as writing to the
+/// client fails, releasing the file or object being read, and aborted
+/// transfers are traced with the number of bytes sent.
+struct TransferStream<S> {
+    inner: S,
+    name: String,
+    digest: String,
+    sent: u64,
+    size: u64,
+    finished: bool,
+}
+
+impl<S> TransferStream<S> {
+    fn new(inner: S, name: String, digest: String, size: u64) -> TransferStream<S> {
+        TransferStream {
+            inner,
+            name,
+            digest,
+            sent: 0,
+            size,
+            finished: false,
+        }
+    }
+}
+
+impl<S> Stream for TransferStream<S>
+where
+    S: Stream<Item = Result<Bytes>> + Unpin,
+{
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.inner.poll_next_unpin(cx);
+
+        match &poll {
+            Poll::Ready(Some(Ok(bytes))) => self.sent += bytes.len() as u64,
+            Poll::Ready(None) => self.finished = true,
+            _ => {}
+        }
+
+        poll
+    }
+}
+
+impl<S> Drop for TransferStream<S> {
+    fn drop(&mut self) {
+        if !self.finished {
+            tracing::debug!(
+                "Transfer of {}@{} aborted after {} of {} bytes",
+                self.name,
+                self.digest,
+                self.sent,
+                self.size
+            );
+        }
+    }
+}
+
 pub async fn get_layer(
     Path((name, digest)): Path<(String, String)>,
+    headers: HeaderMap,
     Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
 ) -> impl IntoResponse {
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Pull) {
+        return e.into_response();
+    }
+
+    let digest = match canonical_digest(&digest) {
+        Some(digest) => digest,
+        None => {
+            return RegistryError::new(StatusCode::BAD_REQUEST, RegistryErrorCode::DigestInvalid)
+                .into_response()
+        }
+    };
+
     let layer_info_result = state
         .storage
         .get_image_layer_info(name.clone(), digest.clone())
         .await;
     if let Err(e) = layer_info_result {
         eprintln!("{}", e);
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        return RegistryError::internal().into_response();
     }
 
     let layer_info_option = layer_info_result.unwrap();
     if layer_info_option.is_none() {
-        return StatusCode::NOT_FOUND.into_response();
+        return RegistryError::new(StatusCode::NOT_FOUND, RegistryErrorCode::BlobUnknown)
+            .into_response();
     }
 
     let layer_info = layer_info_option.unwrap();
 
-    let layer_result = state.storage.get_layer(name, digest.clone()).await;
+    if matches_etag(&headers, &digest) {
+        return Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header("Docker-Content-Digest", &digest)
+            .header("Etag", format!("\"{}\"", digest))
+            .body(Body::empty())
+            .or_internal();
+    }
+
+    let range = match headers.get(header::RANGE) {
+        Some(value) => match value
+            .to_str()
+            .map_err(|_| ())
+            .and_then(|value| parse_range(value, layer_info.size))
+        {
+            Ok(range) => range,
+            Err(()) => {
+                return (
+                    [(
+                        header::CONTENT_RANGE,
+                        format!("bytes */{}", layer_info.size),
+                    )],
+                    RegistryError::new(
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        RegistryErrorCode::RangeInvalid,
+                    ),
+                )
+                    .into_response()
+            }
+        },
+        None => None,
+    };
+
+    let content_type = blob_content_type(&state, &name, &digest).await;
+
+    let layer_result = state.storage.get_layer(name.clone(), digest.clone()).await;
     if let Err(e) = layer_result {
+        if is_not_found(&e) {
+            return RegistryError::new(StatusCode::NOT_FOUND, RegistryErrorCode::BlobUnknown)
+                .into_response();
+        }
+
         eprintln!("{}", e);
-        return StatusCode::NOT_FOUND.into_response();
+        return RegistryError::internal().into_response();
     }
 
     let layer_stream = layer_result.unwrap();
 
+    if let Some((start, end)) = range {
+        let stream = TransferStream::new(
+            Box::pin(slice_stream(layer_stream, start, end)),
+            name,
+            digest.clone(),
+            end - start + 1,
+        );
+
+        return Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header("Accept-Ranges", "bytes")
+            .header("Content-Length", end - start + 1)
+            .header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, end, layer_info.size),
+            )
+            .header("Docker-Content-Digest", &digest)
+            .header("Etag", format!("\"{}\"", digest))
+            .header("Content-Type", content_type)
+            .body(Body::wrap_stream(stream))
+            .or_internal();
+    }
+
     Response::builder()
         .header("Accept-Ranges", "bytes")
         .header("Content-Length", layer_info.size)
         .header("Docker-Content-Digest", &digest)
         .header("Etag", format!("\"{}\"", digest))
-        .header("Content-Type", "application/octet-stream")
-        .body(Body::wrap_stream(layer_stream))
-        .unwrap()
-        .into_response()
+        .header("Content-Type", content_type)
+        .body(Body::wrap_stream(TransferStream::new(
+            layer_stream,
+            name,
+            digest.clone(),
+            layer_info.size,
+        )))
+        .or_internal()
 }
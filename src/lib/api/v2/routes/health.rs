@@ -0,0 +1,15 @@
+use axum::{response::IntoResponse, Extension};
+use hyper::StatusCode;
+
+use crate::api::v2::state::SharedState;
+
+/// Readiness probe, failing while the storage can't be used.
+pub async fn get_readiness(Extension(state): Extension<SharedState>) -> impl IntoResponse {
+    match state.storage.health_check().await {
+        Ok(()) => StatusCode::OK,
+        Err(e) => {
+            eprintln!("{}", e);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
@@ -1,99 +1,574 @@
 use axum::{
-    extract::Path,
+    body::Bytes,
+    extract::{BodyStream, Path, Query},
     response::{IntoResponse, Response},
     Extension, Json,
 };
-use hyper::{Body, StatusCode};
-use serde::Serialize;
+use futures::{StreamExt, TryStreamExt};
+use hyper::{header, Body, HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 
 use crate::{
     api::v2::{
-        errors::{RegistryError, RegistryErrorCode},
+        errors::{BuiltResponse, RegistryError, RegistryErrorCode},
         state::SharedState,
     },
-    storage::types::manifest::Manifest,
-    utils,
+    auth::{Action, Subject},
+    storage::{
+        canonical_digest, is_digest, is_not_found,
+        types::{
+            manifest::Manifest,
+            referrer::Referrer,
+            schema1::{
+                Schema1Manifest, SCHEMA1_MANIFEST_MEDIA_TYPE, SCHEMA1_SIGNED_MANIFEST_MEDIA_TYPE,
+            },
+        },
+        DigestAlgorithm, Error,
+    },
 };
 
 pub async fn get_manifest_info(
     Path((name, reference)): Path<(String, String)>,
     Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
 ) -> impl IntoResponse {
-    match state
-        .storage
-        .get_manifest_summary(name.clone(), reference.clone())
-        .await
-    {
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Pull) {
+        return e.into_response();
+    }
+
+    // Answered from the manifest's metadata, see `Storage::get_manifest_summary`
+    match state.storage.get_manifest_summary(name, reference).await {
+        Ok(summary) => Response::builder()
+            .header("Docker-Content-Digest", &summary.digest)
+            .header("Content-Length", summary.size)
+            .body(Body::empty())
+            .or_internal(),
+        Err(e) if is_not_found(&e) => {
+            RegistryError::new(StatusCode::NOT_FOUND, RegistryErrorCode::ManifestUnknown)
+                .into_response()
+        }
         Err(e) => {
             eprintln!("{}", e);
-            RegistryError::new(StatusCode::NOT_FOUND, RegistryErrorCode::ManifestUnknown)
+            RegistryError::internal().into_response()
+        }
+    }
+}
+
+/// Schema1 media type to translate the stored manifest to, when the client
+/// accepts one but not the stored manifest's own media type.
+fn requested_schema1_media_type(headers: &HeaderMap, content_type: &str) -> Option<&'static str> {
+    let accepted = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+        .collect::<Vec<_>>();
+
+    if accepted.contains(&content_type) {
+        return None;
+    }
+
+    [
+        SCHEMA1_MANIFEST_MEDIA_TYPE,
+        SCHEMA1_SIGNED_MANIFEST_MEDIA_TYPE,
+    ]
+    .into_iter()
+    .find(|media_type| accepted.contains(media_type))
+}
+
+async fn get_schema1_manifest(
+    state: &SharedState,
+    name: String,
+    reference: String,
+    manifest: &Manifest,
+    media_type: &str,
+) -> Response {
+    let config_digest = match &manifest.config {
+        Some(config) => config.digest.clone(),
+        None => {
+            return RegistryError::new(StatusCode::NOT_FOUND, RegistryErrorCode::ManifestUnknown)
                 .into_response()
         }
-        Ok(_) => Response::builder()
-            // .header("Docker-Content-Digest", &manifest_summary.digest)
-            // .header("Content-Length", manifest_summary.size.to_string())
-            .body(Body::empty())
-            .unwrap()
-            .into_response(),
+    };
+
+    let config = match state.storage.get_layer(name.clone(), config_digest).await {
+        Ok(stream) => stream.map_ok(|bytes| bytes.to_vec()).try_concat().await,
+        Err(e) => Err(e),
     }
+    .and_then(|config| serde_json::from_slice::<Value>(&config).map_err(Error::from));
+
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return RegistryError::internal().into_response();
+        }
+    };
+
+    // Pulls by digest have no tag to report
+    let tag = if is_digest(&reference) {
+        String::new()
+    } else {
+        reference
+    };
+
+    let content = match Schema1Manifest::from_image_manifest(name, tag, manifest, &config)
+        .map_err(|e| e.to_string())
+        .and_then(|manifest| serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string()))
+    {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("{}", e);
+            return RegistryError::new(StatusCode::NOT_FOUND, RegistryErrorCode::ManifestUnknown)
+                .into_response();
+        }
+    };
+
+    Response::builder()
+        .header(
+            "Docker-Content-Digest",
+            format!("sha256:{}", hex::encode(Sha256::digest(&content))),
+        )
+        .header("Content-Type", media_type)
+        .body(Body::from(content))
+        .or_internal()
 }
 
 pub async fn get_manifest(
     Path((name, reference)): Path<(String, String)>,
+    headers: HeaderMap,
     Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
 ) -> impl IntoResponse {
-    let manifest_details_result = state
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Pull) {
+        return e.into_response();
+    }
+
+    // Served as stored, reserializing it would change its digest
+    let manifest_result = state
         .storage
-        .get_manifest(name.clone(), reference.clone())
+        .get_manifest_raw(name.clone(), reference.clone())
         .await;
-    if let Err(e) = manifest_details_result {
+    if let Err(e) = manifest_result {
         eprintln!("{}", e);
         return RegistryError::new(StatusCode::NOT_FOUND, RegistryErrorCode::ManifestUnknown)
             .into_response();
     }
 
-    let manifest_details = manifest_details_result.unwrap();
-    match utils::to_json_normalized(&manifest_details.manifest) {
-        Ok(json) => Response::builder()
-            .header("Docker-Content-Digest", &manifest_details.digest)
-            .header("Content-Type", &manifest_details.manifest.media_type)
-            .body(json)
-            .unwrap()
-            .into_response(),
-        Err(e) => {
+    let (content, content_type, digest) = manifest_result.unwrap();
+
+    if state.config.schema1_translation {
+        if let Some(media_type) = requested_schema1_media_type(&headers, &content_type) {
+            let manifest = match serde_json::from_slice::<Manifest>(&content) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return RegistryError::internal().into_response();
+                }
+            };
+
+            return get_schema1_manifest(&state, name, reference, &manifest, media_type).await;
+        }
+    }
+
+    Response::builder()
+        .header("Docker-Content-Digest", &digest)
+        .header("Content-Type", content_type)
+        .body(Body::from(content))
+        .or_internal()
+}
+
+/// Reads a pushed manifest, rejecting it as soon as it's known to be larger
+/// than `limit` bytes rather than buffering all of it.
+async fn read_manifest(
+    headers: &HeaderMap,
+    mut body: BodyStream,
+    limit: u64,
+) -> Result<Bytes, Response> {
+    let too_large = || {
+        RegistryError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            RegistryErrorCode::ManifestInvalid,
+        )
+        .into_response()
+    };
+
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|content_length| content_length > limit) {
+        return Err(too_large());
+    }
+
+    let mut content = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| {
             eprintln!("{}", e);
-            RegistryError::new(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                RegistryErrorCode::ManifestInvalid,
+            StatusCode::BAD_REQUEST.into_response()
+        })?;
+
+        if (content.len() + chunk.len()) as u64 > limit {
+            return Err(too_large());
+        }
+
+        content.extend_from_slice(&chunk);
+    }
+
+    Ok(Bytes::from(content))
+}
+
+fn parse_manifest(body: &[u8]) -> Result<Manifest, RegistryError> {
+    serde_json::from_slice::<Manifest>(body).map_err(|e| {
+        eprintln!("{}", e);
+        RegistryError::new(StatusCode::BAD_REQUEST, RegistryErrorCode::ManifestInvalid)
+    })
+}
+
+/// Rejects manifests declaring, for themselves or their config, a media type
+/// `Config::manifest_media_types` doesn't allow.
+fn check_media_types(state: &SharedState, manifest: &Manifest) -> Result<(), RegistryError> {
+    let allowed = std::iter::once(manifest.content_type())
+        .chain(
+            manifest
+                .config
+                .iter()
+                .map(|config| config.media_type.as_str()),
+        )
+        .all(|media_type| state.config.is_allowed_media_type(media_type));
+
+    if !allowed {
+        return Err(RegistryError::new(
+            StatusCode::BAD_REQUEST,
+            RegistryErrorCode::ManifestInvalid,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Rejects pushes by digest of content it doesn't identify.
+fn check_reference_digest(reference: &str, body: &[u8]) -> Result<(), RegistryError> {
+    if let Some(digest) = canonical_digest(reference) {
+        let algorithm = DigestAlgorithm::of(&digest).unwrap_or_default();
+        if algorithm.digest(body) != digest {
+            return Err(RegistryError::new(
+                StatusCode::BAD_REQUEST,
+                RegistryErrorCode::DigestInvalid,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the blobs and manifests `manifest` refers to are stored in
+/// repository `name`, child manifests with the size it declares.
+async fn check_manifest_references(
+    state: &SharedState,
+    name: &str,
+    manifest: &Manifest,
+) -> Result<(), Response> {
+    // Artifacts without a config point to the empty descriptor, which needs no blob
+    let blobs = manifest
+        .config
+        .iter()
+        .filter(|config| !config.is_empty())
+        .map(|config| &config.digest)
+        .chain(manifest.layers.iter().flatten().map(|layer| &layer.digest));
+
+    for digest in blobs {
+        let exists = state
+            .storage
+            .blob_exists(name.to_string(), digest.clone())
+            .await
+            .map_err(|e| {
+                eprintln!("{}", e);
+                RegistryError::internal().into_response()
+            })?;
+        if !exists {
+            return Err(RegistryError::new(
+                StatusCode::BAD_REQUEST,
+                RegistryErrorCode::ManifestBlobUnknown,
             )
-            .into_response()
+            .into_response());
         }
     }
+
+    check_child_manifests(state, name, manifest).await
+}
+
+/// Checks the manifests an index lists are stored in repository `name`,
+/// addressable by their digest, with the size it declares.
+async fn check_child_manifests(
+    state: &SharedState,
+    name: &str,
+    manifest: &Manifest,
+) -> Result<(), Response> {
+    for entry in manifest.manifests.iter().flatten() {
+        match state
+            .storage
+            .get_manifest_summary(name.to_string(), entry.digest.clone())
+            .await
+        {
+            Ok(summary) if summary.size == entry.size => {}
+            Ok(_) => {
+                return Err(RegistryError::new(
+                    StatusCode::BAD_REQUEST,
+                    RegistryErrorCode::SizeInvalid,
+                )
+                .into_response())
+            }
+            Err(_) => {
+                return Err(RegistryError::new(
+                    StatusCode::BAD_REQUEST,
+                    RegistryErrorCode::ManifestBlobUnknown,
+                )
+                .into_response())
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct ValidateManifestQuery {
+    #[serde(default, rename = "dry-run")]
+    pub dry_run: bool,
 }
 
 #[derive(Serialize)]
-struct PutManifestResponse {}
+struct ManifestValidationReport {
+    digest: String,
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    size: u64,
+    references: usize,
+}
+
+/// Records the media types `manifest` declares its blobs with, for them to be
+/// served with. Failures only cost blobs their media type.
+async fn record_blob_media_types(state: &SharedState, name: &str, manifest: &Manifest) {
+    let blobs = manifest
+        .config
+        .iter()
+        .filter(|config| !config.is_empty())
+        .map(|config| (&config.digest, &config.media_type))
+        .chain(
+            manifest
+                .layers
+                .iter()
+                .flatten()
+                .map(|layer| (&layer.digest, &layer.media_type)),
+        );
+
+    for (digest, media_type) in blobs {
+        let digest = match canonical_digest(digest) {
+            Some(digest) => digest,
+            None => continue,
+        };
+
+        if let Err(e) = state
+            .storage
+            .set_blob_media_type(name.to_string(), digest, media_type.clone())
+            .await
+        {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+/// Checks pushing a manifest to `reference` wouldn't create a repository or a
+/// tag past their count limit.
+async fn check_count_limits(
+    state: &SharedState,
+    name: &str,
+    reference: &str,
+) -> Result<(), Response> {
+    let exceeds_limits = match state.exceeds_repository_limit(name).await {
+        Ok(true) => Ok(true),
+        Ok(false) => state.exceeds_tag_limit(name, reference).await,
+        Err(e) => Err(e),
+    };
+
+    match exceeds_limits {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(
+            RegistryError::new(StatusCode::FORBIDDEN, RegistryErrorCode::Denied).into_response(),
+        ),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(RegistryError::internal().into_response())
+        }
+    }
+}
+
+/// Runs the checks a push of the manifest would go through, and more, without
+/// storing it: its blobs must exist, and its child manifests with the
+/// declared size.
+pub async fn validate_manifest(
+    Path((name, reference)): Path<(String, String)>,
+    query: Query<ValidateManifestQuery>,
+    headers: HeaderMap,
+    Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
+    body: BodyStream,
+) -> impl IntoResponse {
+    if !query.dry_run {
+        return RegistryError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            RegistryErrorCode::Unsupported,
+        )
+        .into_response();
+    }
+
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Push) {
+        return e.into_response();
+    }
+
+    let body = match read_manifest(&headers, body, state.config.manifest_size_limit()).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    let manifest = match parse_manifest(&body) {
+        Ok(manifest) => manifest,
+        Err(e) => return e.into_response(),
+    };
+
+    if let Err(e) = check_media_types(&state, &manifest) {
+        return e.into_response();
+    }
+
+    if let Err(e) = check_reference_digest(&reference, &body) {
+        return e.into_response();
+    }
+
+    if let Err(response) = check_count_limits(&state, &name, &reference).await {
+        return response;
+    }
+
+    match state.exceeds_quota(&name, body.len() as u64).await {
+        Ok(true) => {
+            return RegistryError::new(StatusCode::PAYLOAD_TOO_LARGE, RegistryErrorCode::Denied)
+                .into_response()
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return RegistryError::internal().into_response();
+        }
+        _ => {}
+    }
+
+    if let Err(response) = check_manifest_references(&state, &name, &manifest).await {
+        return response;
+    }
+
+    let references = manifest.config.iter().count()
+        + manifest.layers.iter().flatten().count()
+        + manifest.manifests.iter().flatten().count();
+
+    Json(ManifestValidationReport {
+        digest: state.storage.digest_algorithm().digest(&body),
+        media_type: manifest.content_type().to_string(),
+        size: body.len() as u64,
+        references,
+    })
+    .into_response()
+}
 
 pub async fn put_manifest(
     Path((name, reference)): Path<(String, String)>,
+    headers: HeaderMap,
     Extension(state): Extension<SharedState>,
-    Json(manifest): Json<Manifest>,
+    subject: Option<Extension<Subject>>,
+    body: BodyStream,
 ) -> impl IntoResponse {
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Push) {
+        return e.into_response();
+    }
+
+    let body = match read_manifest(&headers, body, state.config.manifest_size_limit()).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    // The manifest is only parsed to be validated, the digest is computed over
+    // the bytes the client sent and those are stored verbatim.
+    let manifest = match parse_manifest(&body) {
+        Ok(manifest) => manifest,
+        Err(e) => return e.into_response(),
+    };
+
+    if let Err(e) = check_media_types(&state, &manifest) {
+        return e.into_response();
+    }
+
+    if let Err(e) = check_reference_digest(&reference, &body) {
+        return e.into_response();
+    }
+
+    // Clients pull an index, then the manifest of their platform by digest
+    if let Err(response) = check_child_manifests(&state, &name, &manifest).await {
+        return response;
+    }
+
+    if let Err(response) = check_count_limits(&state, &name, &reference).await {
+        return response;
+    }
+
+    match state.exceeds_quota(&name, body.len() as u64).await {
+        Ok(true) => {
+            return RegistryError::new(StatusCode::PAYLOAD_TOO_LARGE, RegistryErrorCode::Denied)
+                .into_response()
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return RegistryError::internal().into_response();
+        }
+        _ => {}
+    }
+
     let update_manifest_result = state
         .storage
-        .update_manifest(name, reference, manifest)
+        .update_manifest(name.clone(), reference, &body)
         .await;
 
     match update_manifest_result {
-        Ok(details) => Response::builder()
-            .header("Docker-Content-Digest", &details.digest)
-            .status(StatusCode::CREATED)
-            .body(Body::empty())
-            .unwrap()
-            .into_response(),
+        Ok(details) => {
+            if state.config.blob_media_types {
+                record_blob_media_types(&state, &name, &manifest).await;
+            }
+
+            let mut response = Response::builder()
+                .header("Docker-Content-Digest", &details.digest)
+                .status(StatusCode::CREATED);
+
+            if let Some(subject) = &manifest.subject {
+                let referrer = Referrer::new(&manifest, details.digest.clone(), body.len() as u64);
+
+                // A missing entry is restored by rebuilding the index, the
+                // manifest itself is stored. Without `OCI-Subject`, clients
+                // fall back to the referrers tag schema.
+                match state
+                    .storage
+                    .add_referrer(name, subject.digest.clone(), referrer)
+                    .await
+                {
+                    Ok(()) => response = response.header("OCI-Subject", &subject.digest),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+
+            response.body(Body::empty()).or_internal()
+        }
         Err(e) => {
             eprintln!("{}", e);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            RegistryError::internal().into_response()
         }
     }
 }
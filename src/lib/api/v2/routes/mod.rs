@@ -1,3 +1,6 @@
 pub mod blobs;
+pub mod health;
 pub mod manifests;
+pub mod referrers;
+pub mod repositories;
 pub mod version;
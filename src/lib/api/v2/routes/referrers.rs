@@ -0,0 +1,87 @@
+use axum::{
+    extract::{Path, Query},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::v2::{
+        errors::{RegistryError, RegistryErrorCode},
+        state::SharedState,
+    },
+    auth::{Action, Subject},
+    storage::{canonical_digest, types::referrer::Referrer},
+};
+
+const INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+
+#[derive(Deserialize)]
+pub struct ReferrersQuery {
+    #[serde(rename = "artifactType")]
+    pub artifact_type: Option<String>,
+}
+
+/// Image index listing the referrers, as the referrers API responds with.
+#[derive(Serialize)]
+struct ReferrersIndex {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    #[serde(rename = "mediaType")]
+    media_type: &'static str,
+    manifests: Vec<Referrer>,
+}
+
+/// Manifests whose subject is manifest `digest`, only those of the requested
+/// artifact type if any. `OCI-Filters-Applied` tells clients the filter was
+/// applied by the registry, so that they don't filter the list themselves.
+pub async fn get_referrers(
+    Path((name, digest)): Path<(String, String)>,
+    Query(query): Query<ReferrersQuery>,
+    Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
+) -> Response {
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Pull) {
+        return e.into_response();
+    }
+
+    let digest = match canonical_digest(&digest) {
+        Some(digest) => digest,
+        None => {
+            return RegistryError::new(StatusCode::BAD_REQUEST, RegistryErrorCode::DigestInvalid)
+                .into_response()
+        }
+    };
+
+    let filtered = query.artifact_type.is_some();
+
+    // Manifests without referrers have an empty list rather than none
+    let manifests = match state
+        .storage
+        .find_referrers(name, digest, query.artifact_type)
+        .await
+    {
+        Ok(manifests) => manifests,
+        Err(e) => {
+            eprintln!("{}", e);
+            return RegistryError::internal().into_response();
+        }
+    };
+
+    let index = Json(ReferrersIndex {
+        schema_version: 2,
+        media_type: INDEX_MEDIA_TYPE,
+        manifests,
+    });
+
+    let mut response = ([(header::CONTENT_TYPE, INDEX_MEDIA_TYPE)], index).into_response();
+    if filtered {
+        response.headers_mut().insert(
+            "OCI-Filters-Applied",
+            header::HeaderValue::from_static("artifactType"),
+        );
+    }
+
+    response
+}
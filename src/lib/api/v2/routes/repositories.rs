@@ -0,0 +1,164 @@
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use hyper::{header, HeaderMap, StatusCode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::v2::{
+        errors::{RegistryError, RegistryErrorCode},
+        state::SharedState,
+    },
+    auth::{Action, Subject},
+    storage::{is_not_found, is_repository_name},
+};
+
+#[derive(Serialize)]
+struct Catalog {
+    repositories: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct TagList {
+    name: String,
+    tags: Vec<String>,
+}
+
+/// Responds with the JSON `listing`, tagged with an ETag hashing it so
+/// clients polling it get a `304` as long as the sorted entries don't change.
+fn listing_response<T>(headers: &HeaderMap, listing: &T) -> Response
+where
+    T: Serialize,
+{
+    let body = match serde_json::to_vec(listing) {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("{}", e);
+            return RegistryError::internal().into_response();
+        }
+    };
+
+    let etag = format!("\"sha256:{}\"", hex::encode(Sha256::digest(&body)));
+
+    let not_modified = headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().trim_start_matches("W/"))
+        .any(|value| value == "*" || value == etag);
+
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+
+    (
+        [
+            (header::ETAG, etag),
+            (header::CONTENT_TYPE, "application/json".to_string()),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+pub async fn get_catalog(
+    headers: HeaderMap,
+    Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
+) -> Response {
+    let mut repositories = match state.storage.list_repositories().await {
+        Ok(repositories) => repositories,
+        Err(e) => {
+            eprintln!("{}", e);
+            return RegistryError::internal().into_response();
+        }
+    };
+
+    // Only list what the client may pull
+    repositories.retain(|name| {
+        state
+            .authorize(subject.as_deref(), name, Action::Pull)
+            .is_ok()
+    });
+    repositories.sort();
+
+    listing_response(&headers, &Catalog { repositories })
+}
+
+pub async fn get_tags(
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
+) -> Response {
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Pull) {
+        return e.into_response();
+    }
+
+    let mut tags = match state.storage.list_tags(name.clone()).await {
+        Ok(tags) => tags,
+        Err(e) if is_not_found(&e) => Vec::new(),
+        Err(e) => {
+            eprintln!("{}", e);
+            return RegistryError::internal().into_response();
+        }
+    };
+
+    if tags.is_empty() {
+        match state.storage.list_repositories().await {
+            Ok(repositories) if !repositories.contains(&name) => {
+                return RegistryError::new(StatusCode::NOT_FOUND, RegistryErrorCode::NameUnknown)
+                    .into_response()
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("{}", e);
+                return RegistryError::internal().into_response();
+            }
+        }
+    }
+
+    tags.sort();
+
+    listing_response(&headers, &TagList { name, tags })
+}
+
+pub async fn delete_repository(
+    Path(name): Path<String>,
+    Extension(state): Extension<SharedState>,
+    subject: Option<Extension<Subject>>,
+) -> Response {
+    if !state.config.delete_repositories {
+        return RegistryError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            RegistryErrorCode::Unsupported,
+        )
+        .into_response();
+    }
+
+    // Names such as `..` would resolve outside of the repository's storage
+    if !is_repository_name(&name) {
+        return RegistryError::new(StatusCode::BAD_REQUEST, RegistryErrorCode::NameInvalid)
+            .into_response();
+    }
+
+    if let Err(e) = state.authorize(subject.as_deref(), &name, Action::Delete) {
+        return e.into_response();
+    }
+
+    match state.storage.delete_repository(name).await {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(e) if is_not_found(&e) => {
+            RegistryError::new(StatusCode::NOT_FOUND, RegistryErrorCode::NameUnknown)
+                .into_response()
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            RegistryError::internal().into_response()
+        }
+    }
+}
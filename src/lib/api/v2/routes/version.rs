@@ -1,10 +1,34 @@
-use axum::{response::IntoResponse, Json};
+use axum::{response::IntoResponse, Extension, Json};
 use hyper::StatusCode;
 use serde::Serialize;
 
+use crate::api::v2::state::SharedState;
+
+/// Optional features of the running registry, for tooling to discover them.
 #[derive(Serialize)]
-struct GetVersionResponse {}
+struct GetVersionResponse {
+    read_only: bool,
+    delete_enabled: bool,
+    auth_required: bool,
+    /// Referrers are served through the referrers API, clients don't need to
+    /// fall back to the tag schema.
+    referrers_supported: bool,
+}
+
+pub async fn get_version(Extension(state): Extension<SharedState>) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(GetVersionResponse {
+            read_only: state.config.read_only,
+            delete_enabled: !state.config.read_only,
+            auth_required: state.config.auth.is_some(),
+            referrers_supported: true,
+        }),
+    )
+}
 
-pub async fn get_version() -> impl IntoResponse {
-    (StatusCode::OK, Json(GetVersionResponse {}))
+/// Probe of whether the server is a v2 registry, which some clients send as
+/// a HEAD request.
+pub async fn head_version() -> impl IntoResponse {
+    StatusCode::OK
 }
@@ -1,14 +1,134 @@
 use std::sync::Arc;
 
-use crate::storage::Storage;
+use axum::http::HeaderValue;
+use hyper::StatusCode;
+
+use crate::{
+    auth::{Action, Subject},
+    storage::{self, Storage},
+};
+
+use super::{
+    config::{Config, DEFAULT_API_VERSION},
+    errors::{RegistryError, RegistryErrorCode},
+    upload_state,
+};
 
 #[derive(Clone)]
 pub struct SharedState {
     pub storage: Arc<dyn Storage>,
+    pub config: Arc<Config>,
+    /// `Config::api_version` as sent in the `Docker-Distribution-Api-Version` header.
+    pub api_version: HeaderValue,
 }
 
 impl SharedState {
-    pub fn new(storage: Arc<dyn Storage>) -> SharedState {
-        SharedState { storage }
+    pub fn new(storage: Arc<dyn Storage>, config: Arc<Config>) -> SharedState {
+        let api_version = match HeaderValue::from_str(config.api_version()) {
+            Ok(api_version) => api_version,
+            Err(_) => {
+                tracing::warn!(
+                    "Invalid API version '{}', advertising {} instead",
+                    config.api_version(),
+                    DEFAULT_API_VERSION
+                );
+                HeaderValue::from_static(DEFAULT_API_VERSION)
+            }
+        };
+
+        SharedState {
+            storage,
+            config,
+            api_version,
+        }
+    }
+
+    pub fn authorize(
+        &self,
+        subject: Option<&Subject>,
+        name: &str,
+        action: Action,
+    ) -> Result<(), RegistryError> {
+        if self.config.read_only && action != Action::Pull {
+            return Err(RegistryError::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                RegistryErrorCode::Unsupported,
+            ));
+        }
+
+        if action == Action::Pull && self.config.is_public_repository(name) {
+            return Ok(());
+        }
+
+        match &self.config.acl {
+            Some(acl) if !acl.is_allowed(subject, name, action) => Err(RegistryError::new(
+                StatusCode::FORBIDDEN,
+                RegistryErrorCode::Denied,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether storing `incoming` more bytes in repository `name` would take
+    /// it past its size limit.
+    pub async fn exceeds_quota(&self, name: &str, incoming: u64) -> storage::Result<bool> {
+        let limit = match self.config.repository_size_limit(name) {
+            Some(limit) => limit,
+            None => return Ok(false),
+        };
+
+        let size = self.storage.repository_size(name.to_string()).await?;
+        Ok(size.saturating_add(incoming) > limit)
+    }
+
+    /// Whether pushing to repository `name` would create one past the
+    /// repository count limit.
+    pub async fn exceeds_repository_limit(&self, name: &str) -> storage::Result<bool> {
+        let limit = match self.config.max_repositories {
+            Some(limit) => limit,
+            None => return Ok(false),
+        };
+
+        let repositories = self.storage.list_repositories().await?;
+        Ok(
+            repositories.len() >= limit
+                && !repositories.iter().any(|repository| repository == name),
+        )
+    }
+
+    /// Whether pushing a manifest to `reference` would create a tag past the
+    /// tag count limit of repository `name`. Digests aren't tags.
+    pub async fn exceeds_tag_limit(&self, name: &str, reference: &str) -> storage::Result<bool> {
+        let limit = match self.config.max_tags {
+            Some(limit) if !storage::is_digest(reference) => limit,
+            _ => return Ok(false),
+        };
+
+        let tags = self.storage.list_tags(name.to_string()).await?;
+        Ok(tags.len() >= limit && !tags.iter().any(|tag| tag == reference))
+    }
+
+    fn upload_state_key(&self) -> &[u8] {
+        self.config.upload_state_key.as_deref().unwrap_or_default()
+    }
+
+    pub fn sign_upload_state(&self, state: &str) -> String {
+        upload_state::sign(self.upload_state_key(), state)
+    }
+
+    pub fn verify_upload_state(
+        &self,
+        token: &str,
+        name: &str,
+        uuid: &str,
+    ) -> Result<(), RegistryError> {
+        if upload_state::verify(self.upload_state_key(), token, name, uuid) {
+            Ok(())
+        } else {
+            Err(RegistryError::new(
+                StatusCode::BAD_REQUEST,
+                RegistryErrorCode::BlobUploadInvalid,
+            ))
+        }
     }
 }
@@ -0,0 +1,2720 @@
+use std::{io::Write, net::Ipv4Addr, sync::Arc};
+
+use axum::{http::request::Builder, response::Response, routing::get, Router};
+use hyper::{header, Body, Method, Request, StatusCode};
+use tempfile::TempDir;
+use tower::ServiceExt;
+use tower_http::catch_panic::CatchPanicLayer;
+
+use crate::{
+    auth::{Acl, Htpasswd},
+    storage::{
+        is_not_found,
+        s3_mock::MockS3,
+        tests::{sha256_digest, upload_layer, TEST_MANIFEST},
+        DigestAlgorithm, LocalStorage, Result, Storage,
+    },
+};
+
+use super::{
+    audit::AuditLog,
+    config::{AuthConfig, Config},
+    middlewares, ApiV2,
+};
+
+const MISSING_DIGEST: &str =
+    "sha256:0000000000000000000000000000000000000000000000000000000000000000";
+
+fn router(temp_dir: &TempDir, config: Config) -> Router<Body> {
+    router_with_storage(Arc::new(LocalStorage::new(temp_dir.path())), config)
+}
+
+fn router_with_storage(storage: Arc<dyn Storage>, config: Config) -> Router<Body> {
+    ApiV2::with_config(Ipv4Addr::LOCALHOST, 0, storage, config).router()
+}
+
+fn request(method: Method, uri: &str) -> Builder {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::HOST, "localhost")
+}
+
+async fn error_code(response: Response) -> Result<String> {
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let json: serde_json::Value = serde_json::from_slice(&body)?;
+
+    Ok(json["errors"][0]["code"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Starts an upload and returns its UUID and `_state` token.
+async fn start_upload(router: &Router<Body>, name: &str) -> Result<(String, String)> {
+    let response = router
+        .clone()
+        .oneshot(
+            request(Method::POST, &format!("/v2/{}/blobs/uploads/", name)).body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let uuid = response.headers()["Docker-Upload-UUID"]
+        .to_str()?
+        .to_string();
+    let location = response.headers()[header::LOCATION].to_str()?;
+    let (_, state) = location.split_once("_state=").unwrap_or_default();
+
+    Ok((uuid, state.to_string()))
+}
+
+/// Pushes `TEST_MANIFEST` to `reference` in repository `name`.
+async fn push_manifest(router: &Router<Body>, name: &str, reference: &str) -> Result<Response> {
+    Ok(router
+        .clone()
+        .oneshot(
+            request(
+                Method::PUT,
+                &format!("/v2/{}/manifests/{}", name, reference),
+            )
+            .body(Body::from(TEST_MANIFEST))?,
+        )
+        .await?)
+}
+
+fn basic_auth(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        base64::encode(format!("{}:{}", username, password))
+    )
+}
+
+fn auth_config(users: &[(&str, &str)]) -> Result<(AuthConfig, tempfile::NamedTempFile)> {
+    let mut file = tempfile::NamedTempFile::new()?;
+    for (username, password) in users {
+        writeln!(file, "{}:{}", username, bcrypt::hash(password, 4)?)?;
+    }
+
+    let auth = AuthConfig {
+        realm: "rustgistry".to_string(),
+        users: Arc::new(Htpasswd::open(file.path())?),
+    };
+
+    Ok((auth, file))
+}
+
+#[tokio::test]
+async fn test_acl_allows_pull_but_denies_push() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let (auth, _htpasswd) = auth_config(&[("alice", "secret")])?;
+    let config = Config {
+        auth: Some(auth),
+        acl: Some(Arc::new(Acl::from_json(
+            r#"{ "alice": { "team-a-*": ["pull"] } }"#,
+        )?)),
+        ..Default::default()
+    };
+    let router = router(&temp_dir, config);
+    let credentials = basic_auth("alice", "secret");
+
+    let response = router
+        .clone()
+        .oneshot(
+            request(
+                Method::GET,
+                &format!("/v2/team-a-app/blobs/{}", MISSING_DIGEST),
+            )
+            .header(header::AUTHORIZATION, &credentials)
+            .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = router
+        .clone()
+        .oneshot(
+            request(Method::POST, "/v2/team-a-app/blobs/uploads/")
+                .header(header::AUTHORIZATION, &credentials)
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = router
+        .clone()
+        .oneshot(
+            request(
+                Method::GET,
+                &format!("/v2/team-b-app/blobs/{}", MISSING_DIGEST),
+            )
+            .header(header::AUTHORIZATION, &credentials)
+            .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = router
+        .oneshot(
+            request(
+                Method::GET,
+                &format!("/v2/team-a-app/blobs/{}", MISSING_DIGEST),
+            )
+            .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        response.headers()[header::WWW_AUTHENTICATE],
+        "Basic realm=\"rustgistry\""
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_anonymous_pull_of_public_repository() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let (auth, _htpasswd) = auth_config(&[("alice", "secret")])?;
+    let config = Config {
+        auth: Some(auth),
+        acl: Some(Arc::new(Acl::from_json(
+            r#"{ "alice": { "*": ["pull", "push"] } }"#,
+        )?)),
+        public_repositories: vec!["library-*".to_string()],
+        ..Default::default()
+    };
+    let router = router(&temp_dir, config);
+
+    for (method, uri, status) in [
+        (
+            Method::GET,
+            format!("/v2/library-app/blobs/{}", MISSING_DIGEST),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            Method::HEAD,
+            format!("/v2/library-app/blobs/{}", MISSING_DIGEST),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            Method::GET,
+            format!("/v2/private-app/blobs/{}", MISSING_DIGEST),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            Method::POST,
+            "/v2/library-app/blobs/uploads/".to_string(),
+            StatusCode::UNAUTHORIZED,
+        ),
+    ] {
+        let response = router
+            .clone()
+            .oneshot(request(method, &uri).body(Body::empty())?)
+            .await?;
+        assert_eq!(response.status(), status, "{}", uri);
+
+        if status == StatusCode::UNAUTHORIZED {
+            assert_eq!(
+                response.headers()[header::WWW_AUTHENTICATE],
+                "Basic realm=\"rustgistry\""
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_push_is_audited() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let audit_file = tempfile::NamedTempFile::new()?;
+    let (auth, _htpasswd) = auth_config(&[("alice", "secret")])?;
+    let config = Config {
+        auth: Some(auth),
+        audit_log: Some(Arc::new(AuditLog::open(audit_file.path())?)),
+        ..Default::default()
+    };
+    let router = router(&temp_dir, config);
+
+    let response = router
+        .clone()
+        .oneshot(
+            request(Method::PUT, "/v2/test/manifests/latest")
+                .header(header::AUTHORIZATION, basic_auth("alice", "secret"))
+                .body(Body::from(TEST_MANIFEST))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::DELETE, "/v2/test").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Pulls aren't recorded unless asked to
+    let response = router
+        .oneshot(
+            request(Method::GET, "/v2/test/manifests/latest")
+                .header(header::AUTHORIZATION, basic_auth("alice", "secret"))
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let records = std::fs::read_to_string(audit_file.path())?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<std::result::Result<Vec<serde_json::Value>, _>>()?;
+    assert_eq!(records.len(), 2);
+
+    assert_eq!(records[0]["subject"], "alice");
+    assert_eq!(records[0]["repository"], "test");
+    assert_eq!(records[0]["reference"], "latest");
+    assert_eq!(records[0]["action"], "push");
+    assert_eq!(records[0]["result"], "success");
+    assert_eq!(records[0]["status"], 201);
+
+    assert_eq!(records[1]["subject"], serde_json::Value::Null);
+    assert_eq!(records[1]["repository"], "test");
+    assert_eq!(records[1]["action"], "delete");
+    assert_eq!(records[1]["result"], "rejected");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_missing_layer_on_s3() -> Result<()> {
+    let router = router_with_storage(Arc::new(MockS3::default().storage()), Config::default());
+
+    let response = router
+        .oneshot(
+            request(Method::GET, &format!("/v2/test/blobs/{}", MISSING_DIGEST))
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(error_code(response).await?, "BLOB_UNKNOWN");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pull_by_digest_on_s3() -> Result<()> {
+    let router = router_with_storage(Arc::new(MockS3::default().storage()), Config::default());
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(TEST_MANIFEST))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let digest = sha256_digest(TEST_MANIFEST.as_bytes());
+    let response = router
+        .oneshot(
+            request(Method::GET, &format!("/v2/test/manifests/{}", digest)).body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, TEST_MANIFEST.as_bytes());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pull_index_child_by_digest() -> Result<()> {
+    let router = router_with_storage(Arc::new(MockS3::default().storage()), Config::default());
+
+    let arm64 = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": 2,
+            "digest": MISSING_DIGEST,
+        },
+        "layers": [],
+    })
+    .to_string();
+
+    // One child pushed by tag, the other by digest as build tools do
+    let children = [
+        ("amd64".to_string(), TEST_MANIFEST.to_string()),
+        (sha256_digest(arm64.as_bytes()), arm64),
+    ];
+    for (reference, content) in &children {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::PUT, &format!("/v2/test/manifests/{}", reference))
+                    .body(Body::from(content.clone()))?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    let index = |entries: &[(String, usize)]| {
+        serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": entries
+                .iter()
+                .map(|(digest, size)| serde_json::json!({
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": digest,
+                    "size": size,
+                }))
+                .collect::<Vec<_>>(),
+        })
+        .to_string()
+    };
+
+    let mut entries = children
+        .iter()
+        .map(|(_, content)| (sha256_digest(content.as_bytes()), content.len()))
+        .collect::<Vec<_>>();
+
+    let response = router
+        .clone()
+        .oneshot(
+            request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(index(&entries)))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    for (digest, _) in &entries {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::GET, &format!("/v2/test/manifests/{}", digest))
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK, "{}", digest);
+        assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+    }
+
+    // An index can't list manifests the registry doesn't have
+    entries.push((MISSING_DIGEST.to_string(), 2));
+    let response = router
+        .oneshot(
+            request(Method::PUT, "/v2/test/manifests/broken").body(Body::from(index(&entries)))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(response).await?, "MANIFEST_BLOB_UNKNOWN");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_rejects_foreign_state() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    let (uuid, state) = start_upload(&router, "test").await?;
+    let (other_uuid, _) = start_upload(&router, "test").await?;
+
+    let response = router
+        .clone()
+        .oneshot(
+            request(
+                Method::PUT,
+                &format!("/v2/test/blobs/uploads/{}?_state={}", other_uuid, state),
+            )
+            .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(response).await?, "BLOB_UPLOAD_INVALID");
+
+    let response = router
+        .oneshot(
+            request(
+                Method::PUT,
+                &format!("/v2/test/blobs/uploads/{}?_state={}", uuid, state),
+            )
+            .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_put_manifest_keeps_raw_body() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(TEST_MANIFEST))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let digest = sha256_digest(TEST_MANIFEST.as_bytes());
+    assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+
+    let response = router
+        .oneshot(
+            request(Method::GET, &format!("/v2/test/manifests/{}", digest)).body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, TEST_MANIFEST.as_bytes());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_manifest_dry_run() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+    let router = router_with_storage(storage.clone(), Config::default());
+
+    let config_digest = upload_layer(&storage, "test", b"{}").await?;
+    let layer_digest = upload_layer(&storage, "test", b"layer content").await?;
+
+    let manifest = |layer_digest: &str| {
+        serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "size": 2,
+                "digest": config_digest,
+            },
+            "layers": [{
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "size": b"layer content".len(),
+                "digest": layer_digest,
+            }],
+        })
+        .to_string()
+    };
+
+    let valid = manifest(&layer_digest);
+    for (content, status, code) in [
+        (valid.clone(), StatusCode::OK, None),
+        (
+            manifest(MISSING_DIGEST),
+            StatusCode::BAD_REQUEST,
+            Some("MANIFEST_BLOB_UNKNOWN"),
+        ),
+    ] {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::POST, "/v2/test/manifests/latest?dry-run=true")
+                    .body(Body::from(content))?,
+            )
+            .await?;
+        assert_eq!(response.status(), status);
+
+        match code {
+            Some(code) => assert_eq!(error_code(response).await?, code),
+            None => {
+                let body = hyper::body::to_bytes(response.into_body()).await?;
+                let report: serde_json::Value = serde_json::from_slice(&body)?;
+                assert_eq!(report["digest"], sha256_digest(valid.as_bytes()));
+            }
+        }
+    }
+
+    // Pushes by digest must push the content it identifies
+    let response = router
+        .clone()
+        .oneshot(
+            request(
+                Method::POST,
+                &format!("/v2/test/manifests/{}?dry-run=true", MISSING_DIGEST),
+            )
+            .body(Body::from(valid.clone()))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(response).await?, "DIGEST_INVALID");
+
+    // Nothing was stored
+    let response = router
+        .oneshot(request(Method::GET, "/v2/test/manifests/latest").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_put_oversized_manifest() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(
+        &temp_dir,
+        Config {
+            max_manifest_size: Some(TEST_MANIFEST.len() as u64 - 1),
+            ..Config::default()
+        },
+    );
+
+    // Rejected from its Content-Length, or while reading it without one
+    for content_length in [Some(TEST_MANIFEST.len()), None] {
+        let mut builder = request(Method::PUT, "/v2/test/manifests/latest");
+        if let Some(content_length) = content_length {
+            builder = builder.header(header::CONTENT_LENGTH, content_length);
+        }
+
+        let response = router
+            .clone()
+            .oneshot(builder.body(Body::from(TEST_MANIFEST))?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error_code(response).await?, "MANIFEST_INVALID");
+    }
+
+    let response = router
+        .oneshot(request(Method::GET, "/v2/test/manifests/latest").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_put_manifest_disallowed_media_type() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    let helm_chart = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.cncf.helm.config.v1+json",
+            "size": 2,
+            "digest": MISSING_DIGEST,
+        },
+        "layers": [],
+    })
+    .to_string();
+    let schema1 = serde_json::json!({
+        "schemaVersion": 1,
+        "mediaType": "application/vnd.docker.distribution.manifest.v1+json",
+    })
+    .to_string();
+
+    for manifest in [helm_chart.clone(), schema1] {
+        let response = router
+            .clone()
+            .oneshot(request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(manifest))?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(response).await?, "MANIFEST_INVALID");
+    }
+
+    // Accepted once allowed
+    let router = router_with_storage(
+        Arc::new(LocalStorage::new(temp_dir.path())),
+        Config {
+            manifest_media_types: Some(vec![
+                "application/vnd.oci.image.manifest.v1+json".to_string(),
+                "application/vnd.cncf.helm.config.v1+json".to_string(),
+            ]),
+            ..Config::default()
+        },
+    );
+
+    let response = router
+        .oneshot(request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(helm_chart))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_manifest_unknown_fields_round_trip() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    // OCI index without a mediaType, config or any field the registry models
+    let manifest = r#"{"schemaVersion":2,"manifests":[],"x-custom":{"kept":true}}"#;
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(manifest))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = router
+        .oneshot(request(Method::GET, "/v2/test/manifests/latest").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "application/vnd.oci.image.index.v1+json"
+    );
+
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, manifest.as_bytes());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_manifest_served_verbatim() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+
+    // Key order, spacing and escapes a reserialization wouldn't keep
+    let manifest = concat!(
+        "{\n  \"mediaType\" : \"application/vnd.docker.distribution.manifest.v2+json\",\n",
+        "  \"schemaVersion\": 2,\n",
+        "  \"annotations\": {\"title\": \"caf\\u00e9\"},\n",
+        "  \"layers\": []\n}\n",
+    );
+    let digest = sha256_digest(manifest.as_bytes());
+
+    for storage in [
+        Arc::new(LocalStorage::new(temp_dir.path())) as Arc<dyn Storage>,
+        Arc::new(MockS3::default().storage()),
+    ] {
+        let router = router_with_storage(storage, Config::default());
+
+        let response = router
+            .clone()
+            .oneshot(request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(manifest))?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+
+        for reference in ["latest", digest.as_str()] {
+            let response = router
+                .clone()
+                .oneshot(
+                    request(Method::GET, &format!("/v2/test/manifests/{}", reference))
+                        .body(Body::empty())?,
+                )
+                .await?;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "application/vnd.docker.distribution.manifest.v2+json"
+            );
+
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            assert_eq!(body, manifest.as_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_head_manifest_without_reading_it() -> Result<()> {
+    let s3 = MockS3::default();
+    let router = router_with_storage(Arc::new(s3.storage()), Config::default());
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(TEST_MANIFEST))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    s3.requests.lock().unwrap().clear();
+
+    let digest = sha256_digest(TEST_MANIFEST.as_bytes());
+    for (reference, status) in [
+        ("latest", StatusCode::OK),
+        (digest.as_str(), StatusCode::OK),
+        ("missing", StatusCode::NOT_FOUND),
+    ] {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::HEAD, &format!("/v2/test/manifests/{}", reference))
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), status);
+
+        if status == StatusCode::OK {
+            assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+            assert_eq!(
+                response.headers()[header::CONTENT_LENGTH],
+                TEST_MANIFEST.len().to_string()
+            );
+        }
+    }
+
+    let requests = s3.requests.lock().unwrap().clone();
+    assert!(!requests.is_empty());
+    assert!(requests.iter().all(|(method, _)| method == "HEAD"));
+
+    // Local tags are resolved through the digest link pointing to them, so
+    // content replaced behind the registry's back goes unnoticed
+    let temp_dir = tempfile::tempdir()?;
+    let router = router_with_storage(
+        Arc::new(LocalStorage::new(temp_dir.path())),
+        Config::default(),
+    );
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(TEST_MANIFEST))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    std::fs::write(
+        temp_dir.path().join("manifests/test/latest"),
+        " ".repeat(TEST_MANIFEST.len()),
+    )?;
+
+    for (reference, status) in [
+        ("latest", StatusCode::OK),
+        (digest.as_str(), StatusCode::OK),
+        ("missing", StatusCode::NOT_FOUND),
+    ] {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::HEAD, &format!("/v2/test/manifests/{}", reference))
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), status);
+
+        if status == StatusCode::OK {
+            assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_push_manifest_by_digest() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+    let digest = sha256_digest(TEST_MANIFEST.as_bytes());
+
+    // Pushed again, as retried pushes do
+    for _ in 0..2 {
+        let response = push_manifest(&router, "test", &digest).await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::GET, &format!("/v2/test/manifests/{}", digest))
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        assert_eq!(body, TEST_MANIFEST.as_bytes());
+    }
+
+    let response = push_manifest(&router, "test", MISSING_DIGEST).await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(response).await?, "DIGEST_INVALID");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_by_digest_during_manifest_push() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+    let digest = sha256_digest(TEST_MANIFEST.as_bytes());
+
+    for tag in ["a", "b"] {
+        let response = push_manifest(&router, "test", tag).await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+    }
+
+    // Pushing to either tag in turn moves the digest link between them. The
+    // task owns its requests, `push_manifest` borrowing a router isn't `Send`.
+    let pusher = tokio::spawn({
+        let router = router.clone();
+        async move {
+            for i in 0..200 {
+                let tag = if i % 2 == 0 { "a" } else { "b" };
+                let response = router
+                    .clone()
+                    .oneshot(
+                        request(Method::PUT, &format!("/v2/test/manifests/{}", tag))
+                            .body(Body::from(TEST_MANIFEST))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::CREATED);
+            }
+        }
+    });
+
+    while !pusher.is_finished() {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::GET, &format!("/v2/test/manifests/{}", digest))
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        assert_eq!(body, TEST_MANIFEST.as_bytes());
+    }
+    pusher.await?;
+
+    // Pushing the same manifest to the same tag again changes nothing
+    let manifest_path = temp_dir.path().join("manifests/test/b");
+    let modified = std::fs::metadata(&manifest_path)?.modified()?;
+
+    let response = push_manifest(&router, "test", "b").await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+    assert_eq!(std::fs::metadata(&manifest_path)?.modified()?, modified);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_push_past_repository_quota() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let config = Config {
+        max_repository_size: Some(16),
+        ..Default::default()
+    };
+    let router = router(&temp_dir, config);
+
+    let push = |content: &'static [u8]| {
+        let router = router.clone();
+        async move {
+            let (uuid, state) = start_upload(&router, "test").await?;
+            let response = router
+                .oneshot(
+                    request(
+                        Method::PUT,
+                        &format!(
+                            "/v2/test/blobs/uploads/{}?_state={}&digest={}",
+                            uuid,
+                            state,
+                            sha256_digest(content)
+                        ),
+                    )
+                    .header(header::CONTENT_LENGTH, content.len())
+                    .body(Body::from(content))?,
+                )
+                .await?;
+            Result::Ok(response)
+        }
+    };
+
+    let response = push(b"small layer").await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = push(b"layer past the quota").await?;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(error_code(response).await?, "DENIED");
+
+    // Chunks are rejected before being stored, and their upload cancelled
+    let (uuid, state) = start_upload(&router, "test").await?;
+    let response = router
+        .clone()
+        .oneshot(
+            request(
+                Method::PATCH,
+                &format!("/v2/test/blobs/uploads/{}?_state={}", uuid, state),
+            )
+            .header(header::CONTENT_LENGTH, 20)
+            .body(Body::from("chunk past the quota"))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(
+        std::fs::read_dir(temp_dir.path().join("uploads/test"))?.count(),
+        0
+    );
+
+    let response = router
+        .oneshot(request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(TEST_MANIFEST))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_push_shared_blob_past_repository_quota() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let mut storage = LocalStorage::new(temp_dir.path());
+    storage.deduplicate = true;
+    let config = Config {
+        max_repository_size: Some(16),
+        ..Default::default()
+    };
+    let router = router_with_storage(Arc::new(storage), config);
+
+    let push = |name: &'static str, content: &'static [u8], digest: String| {
+        let router = router.clone();
+        async move {
+            let (uuid, state) = start_upload(&router, name).await?;
+            let response = router
+                .oneshot(
+                    request(
+                        Method::PUT,
+                        &format!(
+                            "/v2/{}/blobs/uploads/{}?_state={}&digest={}",
+                            name, uuid, state, digest
+                        ),
+                    )
+                    .header(header::CONTENT_LENGTH, content.len())
+                    .body(Body::from(content))?,
+                )
+                .await?;
+            Result::Ok(response.status())
+        }
+    };
+
+    let shared = b"shared layer....";
+    let digest = sha256_digest(shared);
+    assert_eq!(
+        push("first", shared, digest.clone()).await?,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        push("second", b"small", sha256_digest(b"small")).await?,
+        StatusCode::CREATED
+    );
+
+    // Already in the blob store, the layer takes no more space
+    assert_eq!(
+        push("second", shared, digest.clone()).await?,
+        StatusCode::CREATED
+    );
+
+    // Unless the upload claiming to be it is larger
+    assert_eq!(
+        push("third", b"layer past the quota", digest).await?,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_builder_serves_until_shutdown() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let api = ApiV2::builder()
+        .storage(Arc::new(LocalStorage::new(temp_dir.path())))
+        .bind((Ipv4Addr::LOCALHOST, 0).into())
+        .read_only(true)
+        .build()?;
+
+    let (server, handle) = api.serve()?;
+    let server = tokio::spawn(server);
+
+    let client = hyper::Client::new();
+    let base = format!("http://{}", handle.local_addr());
+
+    let response = client.get(format!("{}/v2", base).parse()?).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .request(Request::post(format!("{}/v2/test/blobs/uploads/", base)).body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(
+        error_code(response.map(axum::body::boxed)).await?,
+        "UNSUPPORTED"
+    );
+
+    handle.shutdown();
+    server.await??;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_push_and_pull_over_unix_socket() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let socket_path = temp_dir.path().join("registry.sock");
+
+    let api = ApiV2::builder()
+        .storage(Arc::new(LocalStorage::new(temp_dir.path().join("storage"))))
+        .config(Config {
+            external_url: Some("https://registry.example.com".to_string()),
+            ..Config::default()
+        })
+        .build()?;
+
+    let (shutdown, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(api.serve_unix(&socket_path, async {
+        shutdown_receiver.await.ok();
+    })?);
+
+    let stream = tokio::net::UnixStream::connect(&socket_path).await?;
+    let (mut client, connection) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(connection);
+
+    let response = client
+        .send_request(request(Method::POST, "/v2/test/blobs/uploads/").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let location = response.headers()[header::LOCATION].to_str()?;
+    let location = location
+        .strip_prefix("https://registry.example.com")
+        .expect("Location based on the external URL");
+
+    let content = b"layer content";
+    let digest = sha256_digest(content);
+    let response = client
+        .send_request(
+            request(Method::PUT, &format!("{}&digest={}", location, digest))
+                .header(header::CONTENT_LENGTH, content.len())
+                .body(Body::from(&content[..]))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = client
+        .send_request(
+            request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(TEST_MANIFEST))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    for (uri, expected) in [
+        (format!("/v2/test/blobs/{}", digest), &content[..]),
+        (
+            "/v2/test/manifests/latest".to_string(),
+            TEST_MANIFEST.as_bytes(),
+        ),
+    ] {
+        let response = client
+            .send_request(request(Method::GET, &uri).body(Body::empty())?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        assert_eq!(body, expected);
+    }
+
+    drop(client);
+    shutdown.send(()).ok();
+    server.await??;
+
+    // The socket is cleaned up on shutdown
+    assert!(!socket_path.exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_connections() -> Result<()> {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    let temp_dir = tempfile::tempdir()?;
+    let api = ApiV2::builder()
+        .storage(Arc::new(LocalStorage::new(temp_dir.path())))
+        .bind((Ipv4Addr::LOCALHOST, 0).into())
+        .max_connections(1)
+        .build()?;
+
+    let (server, handle) = api.serve()?;
+    let server = tokio::spawn(server);
+
+    // Sends a request on a new connection, kept open, and returns the start
+    // of the response, empty when the connection is closed right away
+    let connect = || async {
+        let mut stream = TcpStream::connect(handle.local_addr()).await?;
+        stream
+            .write_all(b"GET /v2 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+
+        let mut response = [0; 12];
+        let read = stream.read(&mut response).await.unwrap_or_default();
+        Result::Ok((
+            stream,
+            String::from_utf8_lossy(&response[..read]).to_string(),
+        ))
+    };
+
+    let (first, response) = connect().await?;
+    assert_eq!(response, "HTTP/1.1 200");
+
+    let (_, response) = connect().await?;
+    assert_eq!(response, "");
+
+    // Closing the first connection frees its slot
+    drop(first);
+    let mut response = String::new();
+    for _ in 0..50 {
+        response = connect().await?.1;
+        if !response.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(response, "HTTP/1.1 200");
+
+    handle.shutdown();
+    server.await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_head_layer_content_length_on_s3() -> Result<()> {
+    let storage: Arc<dyn Storage> = Arc::new(MockS3::default().storage());
+    let content = b"layer content";
+    let digest = upload_layer(&storage, "test", content).await?;
+    let router = router_with_storage(storage, Config::default());
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::HEAD, &format!("/v2/test/blobs/{}", digest)).body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_LENGTH],
+        content.len().to_string().as_str()
+    );
+
+    let response = router
+        .oneshot(
+            request(Method::HEAD, &format!("/v2/test/blobs/{}", MISSING_DIGEST))
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_put_manifest_indexes_referrer() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+    let router = router_with_storage(storage.clone(), Config::default());
+
+    let subject = sha256_digest(TEST_MANIFEST.as_bytes());
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "artifactType": "application/vnd.example.signature",
+        "layers": [],
+        "subject": {
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "size": TEST_MANIFEST.len(),
+            "digest": subject,
+        },
+    })
+    .to_string();
+
+    let response = router
+        .oneshot(
+            request(Method::PUT, "/v2/test/manifests/signature")
+                .body(Body::from(manifest.clone()))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let referrers = storage.list_referrers("test".to_string(), subject).await?;
+    assert_eq!(referrers.len(), 1);
+    assert_eq!(referrers[0].digest, sha256_digest(manifest.as_bytes()));
+    assert_eq!(
+        referrers[0].artifact_type.as_deref(),
+        Some("application/vnd.example.signature")
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_put_manifest_with_subject_sets_oci_subject() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+    let subject = sha256_digest(TEST_MANIFEST.as_bytes());
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(TEST_MANIFEST))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert!(!response.headers().contains_key("OCI-Subject"));
+
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "artifactType": "application/vnd.example.signature",
+        "config": {
+            "mediaType": "application/vnd.oci.empty.v1+json",
+            "size": 2,
+            "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+        },
+        "layers": [],
+        "subject": {
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "size": TEST_MANIFEST.len(),
+            "digest": subject,
+        },
+    })
+    .to_string();
+
+    let response = router
+        .oneshot(request(Method::PUT, "/v2/test/manifests/signature").body(Body::from(manifest))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()["OCI-Subject"], subject.as_str());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_referrers() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+    let subject = sha256_digest(TEST_MANIFEST.as_bytes());
+
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "artifactType": "application/vnd.example.signature",
+        "config": {
+            "mediaType": "application/vnd.oci.empty.v1+json",
+            "size": 2,
+            "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+        },
+        "layers": [],
+        "subject": {
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "size": TEST_MANIFEST.len(),
+            "digest": subject,
+        },
+    })
+    .to_string();
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::PUT, "/v2/test/manifests/signature").body(Body::from(manifest))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    for (query, filtered, count) in [
+        ("", false, 1),
+        ("?artifactType=application/vnd.example.signature", true, 1),
+        ("?artifactType=application/vnd.example.sbom", true, 0),
+    ] {
+        let response = router
+            .clone()
+            .oneshot(
+                request(
+                    Method::GET,
+                    &format!("/v2/test/referrers/{}{}", subject, query),
+                )
+                .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/vnd.oci.image.index.v1+json"
+        );
+        assert_eq!(
+            response
+                .headers()
+                .get("OCI-Filters-Applied")
+                .map(|value| value.as_bytes()),
+            filtered.then_some(&b"artifactType"[..]),
+            "{}",
+            query
+        );
+
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let index: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(index["manifests"].as_array().map(Vec::len), Some(count));
+    }
+
+    // Manifests without referrers have an empty list
+    let response = router
+        .oneshot(
+            request(
+                Method::GET,
+                &format!("/v2/test/referrers/{}", MISSING_DIGEST),
+            )
+            .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_artifact_manifest_round_trip() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+    let router = router_with_storage(storage.clone(), Config::default());
+
+    let layer_digest = upload_layer(&storage, "test", b"{\"spdxVersion\":\"SPDX-2.3\"}").await?;
+    let subject = sha256_digest(TEST_MANIFEST.as_bytes());
+
+    // The empty config blob is never pushed
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "artifactType": "application/spdx+json",
+        "config": {
+            "mediaType": "application/vnd.oci.empty.v1+json",
+            "size": 2,
+            "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+        },
+        "layers": [{
+            "mediaType": "application/spdx+json",
+            "size": 26,
+            "digest": layer_digest,
+        }],
+        "subject": {
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "size": TEST_MANIFEST.len(),
+            "digest": subject,
+        },
+    })
+    .to_string();
+
+    for (method, uri, status) in [
+        (
+            Method::POST,
+            "/v2/test/manifests/sbom?dry-run=true",
+            StatusCode::OK,
+        ),
+        (Method::PUT, "/v2/test/manifests/sbom", StatusCode::CREATED),
+    ] {
+        let response = router
+            .clone()
+            .oneshot(request(method, uri).body(Body::from(manifest.clone()))?)
+            .await?;
+        assert_eq!(response.status(), status);
+    }
+
+    let response = router
+        .oneshot(request(Method::GET, "/v2/test/manifests/sbom").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, manifest.as_bytes());
+
+    let referrers = storage
+        .find_referrers(
+            "test".to_string(),
+            subject,
+            Some("application/spdx+json".to_string()),
+        )
+        .await?;
+    assert_eq!(referrers.len(), 1);
+    assert_eq!(referrers[0].digest, sha256_digest(manifest.as_bytes()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_blob_served_with_its_media_type() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    let sbom = b"{\"spdxVersion\":\"SPDX-2.3\"}";
+    let digest = upload_layer(&storage, "test", sbom).await?;
+
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "artifactType": "application/spdx+json",
+        "config": {
+            "mediaType": "application/vnd.oci.empty.v1+json",
+            "size": 2,
+            "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+        },
+        "layers": [{
+            "mediaType": "application/spdx+json",
+            "size": sbom.len(),
+            "digest": digest,
+        }],
+    })
+    .to_string();
+
+    for (blob_media_types, content_type) in [
+        (false, "application/octet-stream"),
+        (true, "application/spdx+json"),
+    ] {
+        let router = router_with_storage(
+            storage.clone(),
+            Config {
+                blob_media_types,
+                ..Config::default()
+            },
+        );
+
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::PUT, "/v2/test/manifests/sbom")
+                    .body(Body::from(manifest.clone()))?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        for method in [Method::HEAD, Method::GET] {
+            let response = router
+                .clone()
+                .oneshot(
+                    request(method, &format!("/v2/test/blobs/{}", digest)).body(Body::empty())?,
+                )
+                .await?;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], content_type);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_version_reports_capabilities() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(
+        &temp_dir,
+        Config {
+            read_only: true,
+            ..Config::default()
+        },
+    );
+
+    let response = router
+        .oneshot(request(Method::GET, "/v2").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let json: serde_json::Value = serde_json::from_slice(&body)?;
+    assert_eq!(json["read_only"], true);
+    assert_eq!(json["auth_required"], false);
+    assert_eq!(json["delete_enabled"], false);
+    assert_eq!(json["referrers_supported"], true);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_readiness() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let (auth, _htpasswd) = auth_config(&[("alice", "secret")])?;
+    let config = Config {
+        auth: Some(auth),
+        ..Default::default()
+    };
+
+    let router = router(&temp_dir, config.clone());
+    let response = router
+        .oneshot(request(Method::GET, "/readyz").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let router = router_with_storage(
+        Arc::new(LocalStorage::new(temp_dir.path().join("missing"))),
+        config,
+    );
+    let response = router
+        .oneshot(request(Method::GET, "/readyz").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_head_version() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    for uri in ["/v2", "/v2/"] {
+        let response = router
+            .clone()
+            .oneshot(request(Method::HEAD, uri).body(Body::empty())?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        assert!(response
+            .headers()
+            .contains_key("Docker-Distribution-Api-Version"));
+
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        assert!(body.is_empty());
+    }
+
+    let response = router
+        .oneshot(
+            request(Method::HEAD, &format!("/v2/test/blobs/{}", MISSING_DIGEST))
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(response
+        .headers()
+        .contains_key("Docker-Distribution-Api-Version"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_version_header() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+
+    for (api_version, expected) in [
+        (None, "registry/2.0"),
+        (Some("rustgistry/2"), "rustgistry/2"),
+        // Not a valid header value
+        (Some("registry/2.0\n"), "registry/2.0"),
+    ] {
+        let router = router(
+            &temp_dir,
+            Config {
+                api_version: api_version.map(str::to_string),
+                ..Config::default()
+            },
+        );
+
+        let response = router
+            .oneshot(request(Method::GET, "/v2").body(Body::empty())?)
+            .await?;
+        assert_eq!(
+            response.headers()["Docker-Distribution-Api-Version"],
+            expected
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chunked_upload_reports_inclusive_range() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    let (uuid, state) = start_upload(&router, "test").await?;
+    let mut location = format!("/v2/test/blobs/uploads/{}?_state={}", uuid, state);
+
+    for (chunk, range) in [("hello ", "0-5"), ("world", "0-10")] {
+        let response = router
+            .clone()
+            .oneshot(request(Method::PATCH, &location).body(Body::from(chunk))?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(response.headers()["Range"], range);
+
+        let next = response.headers()[header::LOCATION].to_str()?;
+        location = next.trim_start_matches("http://localhost").to_string();
+    }
+
+    let digest = sha256_digest(b"hello world");
+    let response = router
+        .clone()
+        .oneshot(
+            request(Method::PUT, &format!("{}&digest={}", location, digest)).body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = router
+        .oneshot(request(Method::GET, &format!("/v2/test/blobs/{}", digest)).body(Body::empty())?)
+        .await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, "hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chunked_upload_rejects_gapped_range() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    let (uuid, state) = start_upload(&router, "test").await?;
+    let location = format!("/v2/test/blobs/uploads/{}?_state={}", uuid, state);
+
+    for (chunk, range, status) in [
+        ("hello", "0-4", StatusCode::ACCEPTED),
+        ("world", "10-14", StatusCode::RANGE_NOT_SATISFIABLE),
+        (" world", "5-10", StatusCode::ACCEPTED),
+    ] {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::PATCH, &location)
+                    .header("Content-Range", range)
+                    .body(Body::from(chunk))?,
+            )
+            .await?;
+        assert_eq!(response.status(), status, "{}", range);
+
+        if status != StatusCode::ACCEPTED {
+            assert_eq!(error_code(response).await?, "RANGE_INVALID");
+        }
+    }
+
+    let digest = sha256_digest(b"hello world");
+    let response = router
+        .oneshot(
+            request(Method::PUT, &format!("{}&digest={}", location, digest)).body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chunked_upload_retried_chunk() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    let (uuid, state) = start_upload(&router, "test").await?;
+    let location = format!("/v2/test/blobs/uploads/{}?_state={}", uuid, state);
+
+    // The second chunk is sent again, as after a lost response
+    for (chunk, range, uploaded) in [
+        ("hello", "0-4", "0-4"),
+        (" world", "5-10", "0-10"),
+        (" world", "5-10", "0-10"),
+    ] {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::PATCH, &location)
+                    .header("Content-Range", range)
+                    .body(Body::from(chunk))?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::ACCEPTED, "{}", range);
+        assert_eq!(response.headers()["Range"], uploaded);
+    }
+
+    let digest = sha256_digest(b"hello world");
+    let response = router
+        .clone()
+        .oneshot(
+            request(Method::PUT, &format!("{}&digest={}", location, digest)).body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = router
+        .oneshot(request(Method::GET, &format!("/v2/test/blobs/{}", digest)).body(Body::empty())?)
+        .await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, "hello world".as_bytes());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chunked_upload_rejects_rewind_on_s3() -> Result<()> {
+    let router = router_with_storage(Arc::new(MockS3::default().storage()), Config::default());
+
+    let (uuid, state) = start_upload(&router, "test").await?;
+    let location = format!("/v2/test/blobs/uploads/{}?_state={}", uuid, state);
+
+    for (range, status) in [
+        ("0-4", StatusCode::ACCEPTED),
+        ("0-4", StatusCode::RANGE_NOT_SATISFIABLE),
+    ] {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::PATCH, &location)
+                    .header("Content-Range", range)
+                    .body(Body::from("hello"))?,
+            )
+            .await?;
+        assert_eq!(response.status(), status);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_uploads() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    let list_uploads = |uri: &'static str| {
+        let router = router.clone();
+        async move {
+            let response = router
+                .oneshot(request(Method::GET, uri).body(Body::empty())?)
+                .await?;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            Result::Ok(serde_json::from_slice::<serde_json::Value>(&body)?)
+        }
+    };
+
+    assert_eq!(
+        list_uploads("/v2/_uploads").await?["uploads"],
+        serde_json::json!([])
+    );
+
+    let (uuid, _) = start_upload(&router, "test").await?;
+    start_upload(&router, "other").await?;
+
+    let uploads = list_uploads("/v2/_uploads?name=test").await?;
+    assert_eq!(uploads["uploads"][0]["uuid"], uuid.as_str());
+    assert_eq!(uploads["uploads"][0]["size"], 0);
+    assert_eq!(uploads["uploads"].as_array().map(Vec::len), Some(1));
+
+    let uploads = list_uploads("/v2/_uploads").await?;
+    assert_eq!(uploads["uploads"].as_array().map(Vec::len), Some(2));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_layer_if_none_match() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+    let router = router_with_storage(storage.clone(), Config::default());
+
+    let digest = upload_layer(&storage, "test", b"layer content").await?;
+    let uri = format!("/v2/test/blobs/{}", digest);
+
+    let response = router
+        .clone()
+        .oneshot(
+            request(Method::GET, &uri)
+                .header(header::IF_NONE_MATCH, format!("\"{}\"", digest))
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(body.is_empty());
+
+    let response = router
+        .oneshot(
+            request(Method::GET, &uri)
+                .header(header::IF_NONE_MATCH, format!("\"{}\"", MISSING_DIGEST))
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, "layer content");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_layer_range() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+    let router = router_with_storage(storage.clone(), Config::default());
+
+    let digest = upload_layer(&storage, "test", b"hello world").await?;
+    let uri = format!("/v2/test/blobs/{}", digest);
+
+    for (range, content_range, content) in [
+        ("bytes=6-", "bytes 6-10/11", "world"),
+        ("bytes=0-4", "bytes 0-4/11", "hello"),
+        ("bytes=-5", "bytes 6-10/11", "world"),
+        ("bytes=6-100", "bytes 6-10/11", "world"),
+    ] {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::GET, &uri)
+                    .header(header::RANGE, range)
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], content_range);
+
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        assert_eq!(body, content, "{}", range);
+    }
+
+    // Malformed, past the end, inverted, empty suffix or in another unit
+    for range in [
+        "bytes=abc",
+        "bytes=11-",
+        "bytes=20-30",
+        "bytes=5-2",
+        "bytes=-0",
+        "bytes=-",
+        "items=0-4",
+    ] {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::GET, &uri)
+                    .header(header::RANGE, range)
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(
+            response.status(),
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "{}",
+            range
+        );
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */11");
+        assert_eq!(error_code(response).await?, "RANGE_INVALID");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_listing_etag() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    let push = |name: &str, tag: &str| {
+        request(Method::PUT, &format!("/v2/{}/manifests/{}", name, tag))
+            .body(Body::from(TEST_MANIFEST))
+    };
+    let etag = |response: &Response| response.headers()[header::ETAG].clone();
+
+    let response = router.clone().oneshot(push("test", "latest")?).await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    for uri in ["/v2/_catalog", "/v2/test/tags/list"] {
+        let response = router
+            .clone()
+            .oneshot(request(Method::GET, uri).body(Body::empty())?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let first = etag(&response);
+
+        let response = router
+            .clone()
+            .oneshot(request(Method::GET, uri).body(Body::empty())?)
+            .await?;
+        assert_eq!(etag(&response), first, "{}", uri);
+
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::GET, uri)
+                    .header(header::IF_NONE_MATCH, first.clone())
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", uri);
+        assert_eq!(etag(&response), first);
+
+        // Another repository changes the catalog, another tag the tags list
+        let name = if uri == "/v2/_catalog" {
+            "other"
+        } else {
+            "test"
+        };
+        let response = router.clone().oneshot(push(name, "v2")?).await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::GET, uri)
+                    .header(header::IF_NONE_MATCH, first.clone())
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        assert_ne!(etag(&response), first, "{}", uri);
+    }
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::GET, "/v2/test/tags/list").body(Body::empty())?)
+        .await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, r#"{"name":"test","tags":["latest","v2"]}"#);
+
+    let response = router
+        .oneshot(request(Method::GET, "/v2/missing/tags/list").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(error_code(response).await?, "NAME_UNKNOWN");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_concurrent_uploads() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(
+        &temp_dir,
+        Config {
+            max_concurrent_uploads: Some(2),
+            ..Config::default()
+        },
+    );
+
+    let (uuid, state) = start_upload(&router, "test").await?;
+    start_upload(&router, "test").await?;
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::POST, "/v2/test/blobs/uploads/").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(error_code(response).await?, "TOOMANYREQUESTS");
+
+    // Other repositories have their own uploads
+    start_upload(&router, "other").await?;
+
+    let content = b"layer content";
+    let response = router
+        .clone()
+        .oneshot(
+            request(
+                Method::PUT,
+                &format!(
+                    "/v2/test/blobs/uploads/{}?_state={}&digest={}",
+                    uuid,
+                    state,
+                    sha256_digest(content)
+                ),
+            )
+            .header(header::CONTENT_LENGTH, content.len())
+            .body(Body::from(&content[..]))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // Completing an upload frees its slot
+    start_upload(&router, "test").await?;
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_aborted_pull_releases_layer() -> Result<()> {
+    use hyper::body::HttpBody;
+
+    let temp_dir = tempfile::tempdir()?;
+    let mut storage = LocalStorage::new(temp_dir.path());
+    storage.read_buffer_size = 1024;
+    let storage: Arc<dyn Storage> = Arc::new(storage);
+    let router = router_with_storage(storage.clone(), Config::default());
+
+    let digest = upload_layer(&storage, "test", &[7; 64 * 1024]).await?;
+
+    // Files of the store this process has open
+    let open_files = || -> Result<usize> {
+        let mut count = 0;
+        for entry in std::fs::read_dir("/proc/self/fd")? {
+            if let Ok(target) = std::fs::read_link(entry?.path()) {
+                if target.starts_with(temp_dir.path()) {
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    };
+
+    let response = router
+        .oneshot(request(Method::GET, &format!("/v2/test/blobs/{}", digest)).body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut body = response.into_body();
+    let chunk = body.data().await.transpose()?;
+    assert!(chunk.is_some_and(|chunk| chunk.len() < 64 * 1024));
+    assert_eq!(open_files()?, 1);
+
+    // The client going away drops the body mid-stream
+    drop(body);
+    assert_eq!(open_files()?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_error_has_error_body() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    // Upload directories can't be created under a file
+    std::fs::write(temp_dir.path().join("uploads"), "")?;
+
+    let response = router
+        .oneshot(request(Method::POST, "/v2/test/blobs/uploads/").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    assert_eq!(error_code(response).await?, "UNKNOWN");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_repository() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+    let router = router_with_storage(
+        storage.clone(),
+        Config {
+            delete_repositories: true,
+            ..Config::default()
+        },
+    );
+
+    upload_layer(&storage, "test", b"doomed layer").await?;
+    upload_layer(&storage, "other", b"kept layer").await?;
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::DELETE, "/v2/test").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(storage.list_repositories().await?, vec!["other"]);
+
+    let response = router
+        .oneshot(request(Method::DELETE, "/v2/test").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(error_code(response).await?, "NAME_UNKNOWN");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_repository_disabled_by_default() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+    let router = router_with_storage(storage.clone(), Config::default());
+
+    upload_layer(&storage, "test", b"kept layer").await?;
+
+    let response = router
+        .oneshot(request(Method::DELETE, "/v2/test").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(storage.list_repositories().await?, vec!["test"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_repository_rejects_traversal() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+    let router = router_with_storage(
+        storage.clone(),
+        Config {
+            delete_repositories: true,
+            ..Config::default()
+        },
+    );
+
+    upload_layer(&storage, "test", b"kept layer").await?;
+    upload_layer(&storage, "x", b"other kept layer").await?;
+
+    for uri in ["/v2/%2E%2E", "/v2/..%2Fx"] {
+        let response = router
+            .clone()
+            .oneshot(request(Method::DELETE, uri).body(Body::empty())?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        assert_eq!(error_code(response).await?, "NAME_INVALID");
+    }
+
+    let mut repositories = storage.list_repositories().await?;
+    repositories.sort();
+    assert_eq!(repositories, vec!["test", "x"]);
+    assert_eq!(storage.list_layers("test".to_string()).await?.len(), 1);
+
+    // Storage refuses them too, whatever calls it
+    for name in ["..", "../x"] {
+        let result = storage.delete_repository(name.to_string()).await;
+        assert!(matches!(result, Err(e) if is_not_found(&e)), "{}", name);
+    }
+    assert_eq!(storage.list_repositories().await?.len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_push_with_sha512() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let mut storage = LocalStorage::new(temp_dir.path());
+    storage.digest_algorithm = DigestAlgorithm::Sha512;
+    let router = router_with_storage(Arc::new(storage), Config::default());
+
+    let content = b"layer content";
+    let digest = DigestAlgorithm::Sha512.digest(content);
+
+    let (uuid, state) = start_upload(&router, "test").await?;
+    let response = router
+        .clone()
+        .oneshot(
+            request(
+                Method::PUT,
+                &format!(
+                    "/v2/test/blobs/uploads/{}?_state={}&digest={}",
+                    uuid, state, digest
+                ),
+            )
+            .header(header::CONTENT_LENGTH, content.len())
+            .body(Body::from(&content[..]))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+    assert!(digest.starts_with("sha512:"));
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::GET, &format!("/v2/test/blobs/{}", digest)).body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, &content[..]);
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::PUT, "/v2/test/manifests/latest").body(Body::from(TEST_MANIFEST))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let manifest_digest = DigestAlgorithm::Sha512.digest(TEST_MANIFEST);
+    assert_eq!(
+        response.headers()["Docker-Content-Digest"],
+        manifest_digest.as_str()
+    );
+
+    for reference in ["latest", manifest_digest.as_str()] {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::GET, &format!("/v2/test/manifests/{}", reference))
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK, "{}", reference);
+        assert_eq!(
+            response.headers()["Docker-Content-Digest"],
+            manifest_digest.as_str()
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_push_sha256_blob_to_sha512_registry() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let mut storage = LocalStorage::new(temp_dir.path());
+    storage.digest_algorithm = DigestAlgorithm::Sha512;
+    let router = router_with_storage(Arc::new(storage), Config::default());
+
+    // Uploads are hashed with the algorithm of the digest the client sends
+    let content = b"layer content";
+    let digest = sha256_digest(content);
+
+    let (uuid, state) = start_upload(&router, "test").await?;
+    let response = router
+        .clone()
+        .oneshot(
+            request(
+                Method::PUT,
+                &format!(
+                    "/v2/test/blobs/uploads/{}?_state={}&digest={}",
+                    uuid, state, digest
+                ),
+            )
+            .header(header::CONTENT_LENGTH, content.len())
+            .body(Body::from(&content[..]))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::GET, &format!("/v2/test/blobs/{}", digest)).body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, &content[..]);
+
+    // Uploads not matching their digest are refused before being stored
+    let content = b"other layer content";
+
+    let (uuid, state) = start_upload(&router, "test").await?;
+    let response = router
+        .clone()
+        .oneshot(
+            request(
+                Method::PUT,
+                &format!(
+                    "/v2/test/blobs/uploads/{}?_state={}&digest={}",
+                    uuid, state, MISSING_DIGEST
+                ),
+            )
+            .header(header::CONTENT_LENGTH, content.len())
+            .body(Body::from(&content[..]))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(response).await?, "DIGEST_INVALID");
+
+    for digest in [sha256_digest(content), MISSING_DIGEST.to_string()] {
+        let response = router
+            .clone()
+            .oneshot(
+                request(Method::HEAD, &format!("/v2/test/blobs/{}", digest)).body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_head_layer_canonical_digest() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+    let router = router_with_storage(storage.clone(), Config::default());
+
+    let digest = upload_layer(&storage, "test", b"layer content").await?;
+
+    let response = router
+        .clone()
+        .oneshot(
+            request(
+                Method::HEAD,
+                &format!("/v2/test/blobs/{}", digest.to_uppercase()),
+            )
+            .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Docker-Content-Digest"], digest.as_str());
+
+    let response = router
+        .oneshot(request(Method::GET, "/v2/test/blobs/sha256:not-a-digest").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(response).await?, "DIGEST_INVALID");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_location_honors_forwarded_headers_when_trusted() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+
+    for (trust_forwarded_headers, expected) in [
+        (false, "http://localhost/"),
+        (true, "https://registry.example.com/"),
+    ] {
+        let router = router(
+            &temp_dir,
+            Config {
+                trust_forwarded_headers,
+                ..Config::default()
+            },
+        );
+
+        let response = router
+            .oneshot(
+                request(Method::POST, "/v2/test/blobs/uploads/")
+                    .header("X-Forwarded-Proto", "https")
+                    .header("X-Forwarded-Host", "registry.example.com")
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let location = response.headers()[header::LOCATION].to_str()?;
+        assert!(location.starts_with(expected), "{}", location);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_location_based_on_external_url() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(
+        &temp_dir,
+        Config {
+            external_url: Some("https://registry.example.com/".to_string()),
+            ..Config::default()
+        },
+    );
+
+    let response = router
+        .oneshot(request(Method::POST, "/v2/test/blobs/uploads/").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let location = response.headers()[header::LOCATION].to_str()?;
+    assert!(
+        location.starts_with("https://registry.example.com/v2/test/blobs/uploads/"),
+        "{}",
+        location
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invalid_host_rejected() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    for host in ["registry example.com", "registry.example.com/v2"] {
+        let response = router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/v2/test/blobs/uploads/")
+                    .header(header::HOST, host)
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", host);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_spoofed_host_rejected() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(
+        &temp_dir,
+        Config {
+            allowed_hosts: vec!["localhost".to_string()],
+            trust_forwarded_headers: true,
+            ..Config::default()
+        },
+    );
+
+    let response = router
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v2/test/blobs/uploads/")
+                .header(header::HOST, "attacker.example.com")
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = router
+        .clone()
+        .oneshot(
+            request(Method::POST, "/v2/test/blobs/uploads/")
+                .header("X-Forwarded-Host", "attacker.example.com")
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = router
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v2/test/blobs/uploads/")
+                .header(header::HOST, "localhost:5000")
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let location = response.headers()[header::LOCATION].to_str()?;
+    assert!(
+        location.starts_with("http://localhost:5000/"),
+        "{}",
+        location
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_monolithic_upload_without_content_length() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    let (uuid, state) = start_upload(&router, "test").await?;
+    let digest = sha256_digest(b"hello world");
+
+    let chunks: Vec<Result<&'static str>> = vec![Ok("hello "), Ok("world")];
+    let response = router
+        .clone()
+        .oneshot(
+            request(
+                Method::PUT,
+                &format!(
+                    "/v2/test/blobs/uploads/{}?_state={}&digest={}",
+                    uuid, state, digest
+                ),
+            )
+            .header(header::TRANSFER_ENCODING, "chunked")
+            .body(Body::wrap_stream(futures::stream::iter(chunks)))?,
+        )
+        .await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = router
+        .oneshot(request(Method::GET, &format!("/v2/test/blobs/{}", digest)).body(Body::empty())?)
+        .await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, "hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_manifest_translated_to_schema1() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    let config = serde_json::json!({
+        "architecture": "arm64",
+        "os": "linux",
+        "created": "2023-01-01T00:00:00Z",
+        "config": { "Cmd": ["/bin/sh"] },
+        "rootfs": { "type": "layers", "diff_ids": ["sha256:00", "sha256:01"] },
+        "history": [
+            { "created_by": "ADD base.tar /" },
+            { "created_by": "ENV A=1", "empty_layer": true },
+            { "created_by": "RUN make" },
+        ],
+    })
+    .to_string();
+    let config_digest = upload_layer(&storage, "test", config.as_bytes()).await?;
+    let base = upload_layer(&storage, "test", b"base layer").await?;
+    let top = upload_layer(&storage, "test", b"top layer").await?;
+
+    let layer = |digest: &str| {
+        serde_json::json!({
+            "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+            "size": 10,
+            "digest": digest,
+        })
+    };
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+        "config": {
+            "mediaType": "application/vnd.docker.container.image.v1+json",
+            "size": config.len(),
+            "digest": config_digest,
+        },
+        "layers": [layer(&base), layer(&top)],
+    });
+    storage
+        .update_manifest(
+            "test".to_string(),
+            "latest".to_string(),
+            manifest.to_string().as_bytes(),
+        )
+        .await?;
+
+    let get = |schema1_translation: bool| {
+        let router = router_with_storage(
+            storage.clone(),
+            Config {
+                schema1_translation,
+                ..Config::default()
+            },
+        );
+
+        async move {
+            let response = router
+                .oneshot(
+                    request(Method::GET, "/v2/test/manifests/latest")
+                        .header(
+                            header::ACCEPT,
+                            "application/vnd.docker.distribution.manifest.v1+prettyjws",
+                        )
+                        .body(Body::empty())?,
+                )
+                .await?;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let content_type = response.headers()[header::CONTENT_TYPE]
+                .to_str()?
+                .to_string();
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            let json: serde_json::Value = serde_json::from_slice(&body)?;
+
+            Result::Ok((content_type, json))
+        }
+    };
+
+    // Served as stored unless enabled
+    let (content_type, json) = get(false).await?;
+    assert_eq!(
+        content_type,
+        "application/vnd.docker.distribution.manifest.v2+json"
+    );
+    assert_eq!(json["schemaVersion"], 2);
+
+    let (content_type, json) = get(true).await?;
+    assert_eq!(
+        content_type,
+        "application/vnd.docker.distribution.manifest.v1+prettyjws"
+    );
+    assert_eq!(json["schemaVersion"], 1);
+    assert_eq!(json["name"], "test");
+    assert_eq!(json["tag"], "latest");
+    assert_eq!(json["architecture"], "arm64");
+    assert_eq!(json["fsLayers"][0]["blobSum"], top.as_str());
+    assert_eq!(json["fsLayers"][1]["blobSum"], base.as_str());
+
+    let history = |i: usize| -> Result<serde_json::Value> {
+        Ok(serde_json::from_str(
+            json["history"][i]["v1Compatibility"]
+                .as_str()
+                .unwrap_or_default(),
+        )?)
+    };
+    let (top_history, base_history) = (history(0)?, history(1)?);
+    assert_eq!(top_history["config"]["Cmd"][0], "/bin/sh");
+    assert_eq!(top_history["parent"], base_history["id"]);
+    assert!(top_history.get("rootfs").is_none());
+    assert_eq!(base_history["container_config"]["Cmd"][0], "ADD base.tar /");
+
+    Ok(())
+}
+
+async fn panics() -> StatusCode {
+    panic!("handler panic")
+}
+
+#[tokio::test]
+async fn test_handler_panic_answers_internal_error() -> Result<()> {
+    let router = Router::new()
+        .route("/panic", get(panics))
+        .layer(CatchPanicLayer::custom(middlewares::panic_response));
+
+    let response = router
+        .oneshot(request(Method::GET, "/panic").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    assert_eq!(error_code(response).await?, "UNKNOWN");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_location_encodes_name() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(&temp_dir, Config::default());
+
+    // A line feed can't go in the Location header as is
+    let response = router
+        .oneshot(request(Method::POST, "/v2/te%0Ast/blobs/uploads/").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let location = response.headers()[header::LOCATION].to_str()?;
+    assert!(
+        location.starts_with("http://localhost/v2/te%0Ast/blobs/uploads/"),
+        "{}",
+        location
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_tags() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(
+        &temp_dir,
+        Config {
+            max_tags: Some(2),
+            ..Config::default()
+        },
+    );
+
+    for tag in ["v1", "v2"] {
+        let response = push_manifest(&router, "test", tag).await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    let response = push_manifest(&router, "test", "v3").await?;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await?, "DENIED");
+
+    // Existing tags, digests and other repositories aren't limited
+    let digest = sha256_digest(TEST_MANIFEST.as_bytes());
+    for (name, reference) in [("test", "v2"), ("test", digest.as_str()), ("other", "v3")] {
+        let response = push_manifest(&router, name, reference).await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_repositories() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let router = router(
+        &temp_dir,
+        Config {
+            max_repositories: Some(1),
+            ..Config::default()
+        },
+    );
+
+    let response = push_manifest(&router, "test", "v1").await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = push_manifest(&router, "other", "v1").await?;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await?, "DENIED");
+
+    let response = router
+        .clone()
+        .oneshot(request(Method::POST, "/v2/other/blobs/uploads/").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // The existing repository can still be pushed to
+    let response = push_manifest(&router, "test", "v2").await?;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    start_upload(&router, "test").await?;
+
+    Ok(())
+}
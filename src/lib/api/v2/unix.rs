@@ -0,0 +1,45 @@
+use std::{
+    io,
+    os::unix::fs::FileTypeExt,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::ready;
+use hyper::server::accept::Accept;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Connections accepted on a Unix domain socket, for hyper to serve.
+pub struct UnixIncoming {
+    listener: UnixListener,
+}
+
+impl UnixIncoming {
+    /// Binds the socket at `path`, replacing the one a previous run may have
+    /// left behind. Other files are never removed.
+    pub fn bind(path: &Path) -> io::Result<UnixIncoming> {
+        if let Ok(metadata) = path.symlink_metadata() {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        Ok(UnixIncoming {
+            listener: UnixListener::bind(path)?,
+        })
+    }
+}
+
+impl Accept for UnixIncoming {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let (stream, _) = ready!(self.listener.poll_accept(cx))?;
+        Poll::Ready(Some(Ok(stream)))
+    }
+}
@@ -0,0 +1,77 @@
+//! Signing and verification of the `_state` token handed out in upload
+//! `Location` URLs.
+//!
+//! The token is the backend's encoded upload state followed by an HMAC-SHA256
+//! signature (`<state>.<signature>`), so clients can't forge a token for an
+//! upload they didn't start.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::storage::UploadState;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(key: &[u8], state: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(state.as_bytes());
+    mac
+}
+
+pub fn sign(key: &[u8], state: &str) -> String {
+    let signature = mac(key, state).finalize().into_bytes();
+
+    format!(
+        "{}.{}",
+        state,
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Checks the token signature and that it was issued for the upload `uuid` of
+/// repository `name`.
+pub fn verify(key: &[u8], token: &str, name: &str, uuid: &str) -> bool {
+    let (state, signature) = match token.rsplit_once('.') {
+        Some(parts) => parts,
+        None => return false,
+    };
+
+    let signature = match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    if mac(key, state).verify_slice(&signature).is_err() {
+        return false;
+    }
+
+    match UploadState::decode(state) {
+        Ok(state) => state.name == name && state.uuid == uuid,
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn test_verify_upload_state() {
+    let state = UploadState::new("test".to_string(), "1234".to_string())
+        .encode()
+        .unwrap();
+    let token = sign(b"secret", &state);
+
+    assert!(verify(b"secret", &token, "test", "1234"));
+    assert!(!verify(b"secret", &token, "test", "5678"));
+    assert!(!verify(b"secret", &token, "other", "1234"));
+    assert!(!verify(b"other secret", &token, "test", "1234"));
+    assert!(!verify(b"secret", &state, "test", "1234"));
+
+    let forged = UploadState::new("test".to_string(), "5678".to_string())
+        .encode()
+        .unwrap();
+    let (_, signature) = token.rsplit_once('.').unwrap();
+    assert!(!verify(
+        b"secret",
+        &format!("{}.{}", forged, signature),
+        "test",
+        "5678"
+    ));
+}
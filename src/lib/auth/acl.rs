@@ -0,0 +1,120 @@
+use std::{collections::BTreeMap, ffi::OsStr, fs, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{Error, Result};
+
+use super::Subject;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Pull,
+    Push,
+    Delete,
+}
+
+/// Access control list mapping subjects to repository patterns and the actions
+/// they are allowed to perform on them.
+///
+/// Anything not explicitly granted is denied. Repository patterns may contain
+/// `*` wildcards (e.g. `team-a/*`), and the `*` subject applies to every
+/// authenticated user. The JSON representation is:
+///
+/// ```json
+/// {
+///     "alice": { "team-a/*": ["pull", "push"] },
+///     "*": { "library/*": ["pull"] }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Acl {
+    subjects: BTreeMap<String, BTreeMap<String, Vec<Action>>>,
+}
+
+impl Acl {
+    pub fn open<S>(path: S) -> Result<Acl>
+    where
+        S: AsRef<OsStr>,
+    {
+        let path = PathBuf::from(path.as_ref());
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                return Err(Error::from(format!(
+                    "Failed to read ACL file '{}': {}",
+                    path.display(),
+                    e,
+                )))
+            }
+        };
+
+        Acl::from_json(&content)
+    }
+
+    pub fn from_json(json: &str) -> Result<Acl> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn is_allowed(&self, subject: Option<&Subject>, repository: &str, action: Action) -> bool {
+        let subject = match subject {
+            Some(subject) => subject,
+            None => return false,
+        };
+
+        [subject.name.as_str(), "*"]
+            .iter()
+            .filter_map(|name| self.subjects.get(*name))
+            .flat_map(|repositories| repositories.iter())
+            .any(|(pattern, actions)| {
+                actions.contains(&action) && matches_pattern(pattern, repository)
+            })
+    }
+}
+
+/// Glob-style matching where `*` matches any sequence of characters.
+pub fn matches_pattern(pattern: &str, value: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == value,
+        Some((prefix, rest)) => {
+            let value = match value.strip_prefix(prefix) {
+                Some(value) => value,
+                None => return false,
+            };
+
+            (0..=value.len())
+                .filter(|i| value.is_char_boundary(*i))
+                .any(|i| matches_pattern(rest, &value[i..]))
+        }
+    }
+}
+
+#[test]
+fn test_acl_is_allowed() -> Result<()> {
+    let acl = Acl::from_json(
+        r#"{
+            "alice": { "team-a/*": ["pull", "push"], "shared": ["pull"] },
+            "*": { "library/*": ["pull"] }
+        }"#,
+    )?;
+
+    let alice = Subject {
+        name: "alice".to_string(),
+    };
+    let bob = Subject {
+        name: "bob".to_string(),
+    };
+
+    assert!(acl.is_allowed(Some(&alice), "team-a/app", Action::Push));
+    assert!(!acl.is_allowed(Some(&alice), "team-a/app", Action::Delete));
+    assert!(!acl.is_allowed(Some(&alice), "team-b/app", Action::Pull));
+    assert!(acl.is_allowed(Some(&alice), "shared", Action::Pull));
+    assert!(!acl.is_allowed(Some(&alice), "shared", Action::Push));
+    assert!(acl.is_allowed(Some(&bob), "library/alpine", Action::Pull));
+    assert!(!acl.is_allowed(Some(&bob), "team-a/app", Action::Pull));
+    assert!(!acl.is_allowed(None, "library/alpine", Action::Pull));
+
+    Ok(())
+}
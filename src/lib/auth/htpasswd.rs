@@ -0,0 +1,125 @@
+use std::{collections::HashMap, ffi::OsStr, fs, path::PathBuf, sync::RwLock, time::SystemTime};
+
+use crate::storage::{Error, Result};
+
+struct HtpasswdEntries {
+    users: HashMap<String, String>,
+    modified: Option<SystemTime>,
+}
+
+/// User store backed by an htpasswd-style file of `username:bcrypt-hash` lines.
+///
+/// The file is reloaded automatically when its modification time changes, so
+/// users can be added or removed without restarting the registry.
+pub struct Htpasswd {
+    pub path: PathBuf,
+    entries: RwLock<HtpasswdEntries>,
+}
+
+impl Htpasswd {
+    pub fn open<S>(path: S) -> Result<Htpasswd>
+    where
+        S: AsRef<OsStr>,
+    {
+        let htpasswd = Htpasswd {
+            path: PathBuf::from(path.as_ref()),
+            entries: RwLock::new(HtpasswdEntries {
+                users: HashMap::new(),
+                modified: None,
+            }),
+        };
+
+        htpasswd.reload()?;
+
+        Ok(htpasswd)
+    }
+
+    pub fn reload(&self) -> Result<()> {
+        let modified = self.path.metadata()?.modified().ok();
+
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) => {
+                return Err(Error::from(format!(
+                    "Failed to read htpasswd file '{}': {}",
+                    self.path.display(),
+                    e,
+                )))
+            }
+        };
+
+        let users = parse_htpasswd(&content)?;
+
+        let mut entries = self.entries.write().unwrap();
+        entries.users = users;
+        entries.modified = modified;
+
+        Ok(())
+    }
+
+    fn reload_if_modified(&self) -> Result<()> {
+        let modified = self.path.metadata()?.modified().ok();
+
+        if modified != self.entries.read().unwrap().modified {
+            self.reload()?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the given credentials, reloading the file first if it changed on disk.
+    pub fn verify(&self, username: &str, password: &str) -> Result<bool> {
+        self.reload_if_modified()?;
+
+        let hash = match self.entries.read().unwrap().users.get(username) {
+            Some(hash) => hash.clone(),
+            None => return Ok(false),
+        };
+
+        Ok(bcrypt::verify(password, &hash)?)
+    }
+}
+
+fn parse_htpasswd(content: &str) -> Result<HashMap<String, String>> {
+    let mut users = HashMap::new();
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (username, hash) = match line.split_once(':') {
+            Some(entry) => entry,
+            None => return Err(Error::from(format!("Invalid htpasswd line '{}'", line))),
+        };
+
+        if !(hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$")) {
+            return Err(Error::from(format!(
+                "Unsupported password hash for user '{}', only bcrypt is supported",
+                username,
+            )));
+        }
+
+        users.insert(username.to_string(), hash.to_string());
+    }
+
+    Ok(users)
+}
+
+#[test]
+fn test_htpasswd_verify() -> Result<()> {
+    use std::io::Write;
+
+    let mut file = tempfile::NamedTempFile::new()?;
+    writeln!(file, "# registry users")?;
+    writeln!(file, "alice:{}", bcrypt::hash("secret", 4)?)?;
+
+    let htpasswd = Htpasswd::open(file.path())?;
+
+    assert!(htpasswd.verify("alice", "secret")?);
+    assert!(!htpasswd.verify("alice", "wrong")?);
+    assert!(!htpasswd.verify("bob", "secret")?);
+
+    Ok(())
+}
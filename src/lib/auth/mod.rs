@@ -0,0 +1,7 @@
+mod acl;
+mod htpasswd;
+mod subject;
+
+pub use acl::*;
+pub use htpasswd::*;
+pub use subject::*;
@@ -0,0 +1,6 @@
+/// Identity of an authenticated client, attached to the request extensions by
+/// the auth middleware.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subject {
+    pub name: String,
+}
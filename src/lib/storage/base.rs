@@ -1,14 +1,86 @@
-use std::pin::Pin;
+use std::{pin::Pin, time::SystemTime};
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::Stream;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 
-use super::types::manifest::Manifest;
+use super::{
+    types::{manifest::Manifest, referrer::Referrer},
+    DigestAlgorithm,
+};
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Errors storage backends report for conditions callers are expected to
+/// handle, as opposed to opaque I/O or service failures.
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound(String),
+    /// A chunk starting at `start` can't be written to an upload of `size` bytes.
+    RangeInvalid {
+        start: u64,
+        size: u64,
+    },
+    /// An upload the client expected to be `expected` hashed to `actual`.
+    DigestInvalid {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::NotFound(what) => write!(f, "{} not found", what),
+            StorageError::RangeInvalid { start, size } => write!(
+                f,
+                "Chunk starting at {} doesn't follow the {} bytes uploaded",
+                start, size
+            ),
+            StorageError::DigestInvalid { expected, actual } => {
+                write!(f, "Upload expected to be {} hashed to {}", expected, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+pub fn is_not_found(error: &Error) -> bool {
+    matches!(
+        error.downcast_ref::<StorageError>(),
+        Some(StorageError::NotFound(_))
+    )
+}
+
+pub fn is_range_invalid(error: &Error) -> bool {
+    matches!(
+        error.downcast_ref::<StorageError>(),
+        Some(StorageError::RangeInvalid { .. })
+    )
+}
+
+pub fn is_digest_invalid(error: &Error) -> bool {
+    matches!(
+        error.downcast_ref::<StorageError>(),
+        Some(StorageError::DigestInvalid { .. })
+    )
+}
+
+/// Fails with `StorageError::DigestInvalid` when an upload hashed to `actual`
+/// while the client expected `expected`.
+pub fn check_upload_digest(expected: Option<&str>, actual: &str) -> Result<()> {
+    match expected {
+        Some(expected) if expected != actual => Err(Box::new(StorageError::DigestInvalid {
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        })),
+        _ => Ok(()),
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ImageLayerInfo {
     pub size: u64,
@@ -20,6 +92,56 @@ pub struct UploadContainer {
     pub state: String,
 }
 
+/// State of an upload container, handed to clients as the `_state` token.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadState {
+    pub name: String,
+    pub uuid: String,
+    pub created_at: u64,
+}
+
+impl UploadState {
+    pub fn new(name: String, uuid: String) -> UploadState {
+        let created_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        UploadState {
+            name,
+            uuid,
+            created_at,
+        }
+    }
+
+    /// Encodes the state as URL-safe base64 of its JSON representation.
+    pub fn encode(&self) -> Result<String> {
+        match serde_json::to_string(self) {
+            Ok(json) => Ok(base64::encode_config(json, base64::URL_SAFE_NO_PAD)),
+            Err(e) => Err(Error::from(format!(
+                "Failed to serialize upload container state: {}",
+                e
+            ))),
+        }
+    }
+
+    pub fn decode(state: &str) -> Result<UploadState> {
+        let json = base64::decode_config(state, base64::URL_SAFE_NO_PAD)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+/// Upload in progress, as listed by `Storage::list_uploads`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct UploadInfo {
+    pub name: String,
+    pub uuid: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch. Object stores rewrite uploads on every
+    /// chunk, so theirs is the time of the last chunk, when they report it.
+    pub created_at: Option<u64>,
+}
+
 #[derive(Clone, Debug)]
 pub struct UploadStatus {
     pub size: u64,
@@ -40,6 +162,8 @@ pub struct ManifestSummary {
 pub struct ManifestDetails {
     pub manifest: Manifest,
     pub digest: String,
+    /// Manifest as stored, byte for byte what the client pushed.
+    pub content: Bytes,
 }
 
 #[derive(Clone, Debug)]
@@ -47,6 +171,18 @@ pub struct UpdateManifestDetails {
     pub digest: String,
 }
 
+#[derive(Clone, Debug)]
+pub struct BlobVerification {
+    pub digest: String,
+    pub computed_digest: String,
+}
+
+impl BlobVerification {
+    pub fn is_valid(&self) -> bool {
+        self.digest == self.computed_digest
+    }
+}
+
 #[async_trait]
 pub trait Storage: Sync + Send {
     async fn get_image_layer_info(
@@ -55,6 +191,19 @@ pub trait Storage: Sync + Send {
         digest: String,
     ) -> Result<Option<ImageLayerInfo>>;
 
+    /// Whether repository `name` holds blob `digest`, for callers not needing
+    /// its size, which some storages can only tell by reading the blob.
+    async fn blob_exists(&self, name: String, digest: String) -> Result<bool> {
+        Ok(self.get_image_layer_info(name, digest).await?.is_some())
+    }
+
+    /// Size of blob `digest` when it's in a store shared by all repositories,
+    /// storing it in one more repository then taking no space. Storages
+    /// without such a store have none.
+    async fn get_shared_blob_size(&self, _digest: String) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
     async fn get_layer(
         &self,
         name: String,
@@ -73,30 +222,199 @@ pub trait Storage: Sync + Send {
         range: (u64, u64),
     ) -> Result<UploadStatus>;
 
-    async fn close_upload_container(&self, name: String, uuid: String) -> Result<UploadDetails>;
+    /// Bytes received so far by upload `uuid`, which the next chunk must
+    /// start right after.
+    async fn get_upload_status(&self, name: String, uuid: String) -> Result<UploadStatus>;
+
+    /// Uploads in progress in repository `name`, or in every repository.
+    async fn list_uploads(&self, name: Option<String>) -> Result<Vec<UploadInfo>>;
 
+    /// Cancels upload `uuid`, removing the bytes it received so far.
+    async fn delete_upload_container(&self, name: String, uuid: String) -> Result<()>;
+
+    /// Hashes upload `uuid` and moves it to the layer of its digest. When
+    /// the client gave the `digest` it expects, the upload is hashed with its
+    /// algorithm and, if it doesn't match, discarded without storing anything.
+    async fn close_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: Option<String>,
+    ) -> Result<UploadDetails>;
+
+    /// Moves upload `uuid` to layer `digest` as is, without hashing it. Meant
+    /// for storages wrapping this one and storing transformed content, whose
+    /// digest is computed over the original one.
+    async fn store_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: String,
+    ) -> Result<UploadDetails>;
+
+    /// Digest and size of manifest `reference`, read from metadata where the
+    /// storage keeps them rather than from the manifest itself. Fails with
+    /// `StorageError::NotFound` when there's no such manifest.
     async fn get_manifest_summary(
         &self,
         name: String,
         reference: String,
     ) -> Result<ManifestSummary>;
 
+    /// Whether `reference` points to a manifest in repository `name`, without
+    /// reading it.
+    async fn manifest_exists(&self, name: String, reference: String) -> Result<bool> {
+        match self.get_manifest_summary(name, reference).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_not_found(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
     async fn get_manifest(&self, name: String, reference: String) -> Result<ManifestDetails>;
 
+    /// Manifest `reference` points to as stored, along with the media type
+    /// and digest to serve it with, without modeling it.
+    async fn get_manifest_raw(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<(Bytes, String, String)> {
+        let details = self.get_manifest(name, reference).await?;
+        let media_type = details.manifest.content_type().to_string();
+
+        Ok((details.content, media_type, details.digest))
+    }
+
     async fn update_manifest(
         &self,
         name: String,
         reference: String,
-        manifest: Manifest,
+        content: &[u8],
     ) -> Result<UpdateManifestDetails>;
 
     async fn delete_manifest(&self, name: String, reference: String) -> Result<()>;
-}
 
-pub fn is_sha256_digest(digest: &String) -> bool {
-    digest.starts_with("sha256:")
-        && digest.len() == 71
-        && digest[7..].chars().all(|c| c.is_ascii_hexdigit())
+    /// Removes layer `digest` from repository `name`, along with its recorded
+    /// media type.
+    async fn delete_layer(&self, name: String, digest: String) -> Result<()>;
+
+    /// Records the media type manifests of repository `name` reference blob
+    /// `digest` with.
+    async fn set_blob_media_type(
+        &self,
+        name: String,
+        digest: String,
+        media_type: String,
+    ) -> Result<()>;
+
+    /// Media type recorded for blob `digest`, if any.
+    async fn get_blob_media_type(&self, name: String, digest: String) -> Result<Option<String>>;
+
+    /// Removes repository `name` with all its manifests, layers, uploads in
+    /// progress and referrers. Layers shared with other repositories stay
+    /// available to them.
+    async fn delete_repository(&self, name: String) -> Result<()>;
+
+    /// Repositories holding layers or manifests, sorted by name.
+    async fn list_repositories(&self) -> Result<Vec<String>>;
+
+    /// Digests of the layers stored in repository `name`.
+    async fn list_layers(&self, name: String) -> Result<Vec<String>>;
+
+    /// Tags of the manifests stored in repository `name`, sorted.
+    async fn list_tags(&self, name: String) -> Result<Vec<String>>;
+
+    /// References, tags and digests, the manifests of repository `name` are
+    /// stored under.
+    async fn list_manifests(&self, name: String) -> Result<Vec<String>>;
+
+    /// Records `referrer` in the referrers index of manifest `subject`.
+    async fn add_referrer(&self, name: String, subject: String, referrer: Referrer) -> Result<()>;
+
+    /// Removes manifest `digest` from the referrers index of manifest `subject`.
+    async fn remove_referrer(&self, name: String, subject: String, digest: String) -> Result<()>;
+
+    /// Manifests referring to manifest `subject`, as recorded in the index.
+    async fn list_referrers(&self, name: String, subject: String) -> Result<Vec<Referrer>>;
+
+    /// Empties the referrers index of repository `name`.
+    async fn clear_referrers(&self, name: String) -> Result<()>;
+
+    /// Referrers of manifest `subject`, only those of `artifact_type` if given.
+    async fn find_referrers(
+        &self,
+        name: String,
+        subject: String,
+        artifact_type: Option<String>,
+    ) -> Result<Vec<Referrer>> {
+        let mut referrers = self.list_referrers(name, subject).await?;
+
+        if let Some(artifact_type) = artifact_type {
+            referrers.retain(|referrer| referrer.artifact_type.as_ref() == Some(&artifact_type));
+        }
+
+        Ok(referrers)
+    }
+
+    /// Reconstructs the referrers index of repository `name` by scanning all
+    /// its manifests.
+    async fn rebuild_referrers(&self, name: String) -> Result<()> {
+        self.clear_referrers(name.clone()).await?;
+
+        for reference in self.list_manifests(name.clone()).await? {
+            let details = self.get_manifest(name.clone(), reference).await?;
+
+            if let Some(subject) = &details.manifest.subject {
+                let referrer = Referrer::new(
+                    &details.manifest,
+                    details.digest.clone(),
+                    details.content.len() as u64,
+                );
+
+                self.add_referrer(name.clone(), subject.digest.clone(), referrer)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes the manifests of repository `name` pushed by tag only
+    /// addressable by their digest. Storages that always do so on push have
+    /// nothing to link.
+    async fn link_manifest_digests(&self, _name: String) -> Result<()> {
+        Ok(())
+    }
+
+    /// Bytes used by the layers and manifests of repository `name`, uploads
+    /// in progress excluded.
+    async fn repository_size(&self, name: String) -> Result<u64>;
+
+    /// Checks the storage can currently be used, cheaply enough to be called
+    /// on every readiness probe.
+    async fn health_check(&self) -> Result<()>;
+
+    /// Algorithm the digests of closed uploads and updated manifests are
+    /// computed with.
+    fn digest_algorithm(&self) -> DigestAlgorithm;
+
+    /// Re-hashes a stored layer to check it still matches its digest.
+    async fn verify_blob(&self, name: String, digest: String) -> Result<BlobVerification> {
+        let mut stream = self.get_layer(name, digest.clone()).await?;
+
+        let mut hasher = DigestAlgorithm::of(&digest).unwrap_or_default().hasher();
+        while let Some(bytes) = stream.next().await {
+            hasher.update(&bytes?);
+        }
+
+        let computed_digest = hasher.finalize();
+
+        Ok(BlobVerification {
+            digest,
+            computed_digest,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -107,7 +425,50 @@ pub mod tests {
     use futures::{StreamExt, TryStreamExt};
     use rand::Rng;
 
-    use super::{is_sha256_digest, Result, Storage};
+    use sha2::{Digest, Sha256};
+
+    use super::{is_not_found, Referrer, Result, Storage, UploadState};
+    use crate::storage::is_digest;
+    use crate::storage::types::manifest::OCI_IMAGE_MANIFEST_MEDIA_TYPE;
+
+    /// Compact image manifest, deliberately not in the pretty-printed form the
+    /// registry used to normalize manifests to.
+    pub const TEST_MANIFEST: &str = concat!(
+        r#"{"schemaVersion":2,"mediaType":"application/vnd.docker.distribution.manifest.v2+json","#,
+        r#""config":{"mediaType":"application/vnd.docker.container.image.v1+json","size":1469,"#,
+        r#""digest":"sha256:e7d92cdc71feacf90708cb59182d0df1b911f8ae022d29e8e95d75ca6a99776a"},"#,
+        r#""layers":[{"mediaType":"application/vnd.docker.image.rootfs.diff.tar.gzip","size":2818413,"#,
+        r#""digest":"sha256:2408cc74d12b6cd092bb8b516ba7d5e290f485d3eb9672efc00f0583730179e8"}]}"#,
+    );
+
+    /// Uploads `content` as a single-chunk layer and returns its digest.
+    pub async fn upload_layer(
+        storage: &Arc<dyn Storage>,
+        name: &str,
+        content: &[u8],
+    ) -> Result<String> {
+        let upload_container = storage.create_upload_container(name.to_string()).await?;
+
+        let stream = futures::stream::iter(vec![Ok(Bytes::copy_from_slice(content))]);
+        storage
+            .write_upload_container(
+                name.to_string(),
+                upload_container.uuid.clone(),
+                Box::pin(stream),
+                (0, content.len() as u64),
+            )
+            .await?;
+
+        let upload_details = storage
+            .close_upload_container(name.to_string(), upload_container.uuid, None)
+            .await?;
+
+        Ok(upload_details.digest)
+    }
+
+    pub fn sha256_digest(content: &[u8]) -> String {
+        format!("sha256:{}", hex::encode(Sha256::digest(content)))
+    }
 
     pub async fn test_upload_layer(storage: Arc<dyn Storage>) -> Result<()> {
         let name = "test".to_string();
@@ -136,10 +497,15 @@ pub mod tests {
         assert_eq!(upload_status.size, (chunk_size * chunk_count) as u64);
 
         let upload_details = storage
-            .close_upload_container(name.clone(), uuid.clone())
+            .close_upload_container(name.clone(), uuid.clone(), None)
             .await?;
 
-        assert!(is_sha256_digest(&upload_details.digest));
+        assert!(is_digest(&upload_details.digest));
+        assert!(
+            storage
+                .blob_exists(name.clone(), upload_details.digest.clone())
+                .await?
+        );
 
         let layer = storage
             .get_layer(name.clone(), upload_details.digest.clone())
@@ -157,4 +523,325 @@ pub mod tests {
 
         Ok(())
     }
+
+    pub async fn test_get_missing_layer(storage: Arc<dyn Storage>) -> Result<()> {
+        let name = "test".to_string();
+        let digest =
+            "sha256:0000000000000000000000000000000000000000000000000000000000000000".to_string();
+
+        assert!(storage
+            .get_image_layer_info(name.clone(), digest.clone())
+            .await?
+            .is_none());
+        assert!(!storage.blob_exists(name.clone(), digest.clone()).await?);
+
+        match storage.get_layer(name, digest).await {
+            Err(e) => assert!(is_not_found(&e), "unexpected error: {}", e),
+            Ok(_) => panic!("missing layer returned a stream"),
+        }
+
+        Ok(())
+    }
+
+    pub async fn test_upload_state_round_trip(storage: Arc<dyn Storage>) -> Result<()> {
+        let name = "test".to_string();
+
+        let upload_container = storage.create_upload_container(name.clone()).await?;
+        let state = UploadState::decode(&upload_container.state)?;
+
+        assert_eq!(state.name, name);
+        assert_eq!(state.uuid, upload_container.uuid);
+        assert!(state.created_at > 0);
+        assert_eq!(state.encode()?, upload_container.state);
+
+        Ok(())
+    }
+
+    pub async fn test_list_uploads(storage: Arc<dyn Storage>) -> Result<()> {
+        assert!(storage.list_uploads(None).await?.is_empty());
+
+        let upload_container = storage.create_upload_container("test".to_string()).await?;
+        let stream = futures::stream::iter(vec![Ok(Bytes::from_static(b"partial"))]);
+        storage
+            .write_upload_container(
+                "test".to_string(),
+                upload_container.uuid.clone(),
+                Box::pin(stream),
+                (0, 6),
+            )
+            .await?;
+        storage.create_upload_container("other".to_string()).await?;
+
+        let uploads = storage.list_uploads(Some("test".to_string())).await?;
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].name, "test");
+        assert_eq!(uploads[0].uuid, upload_container.uuid);
+        assert_eq!(uploads[0].size, b"partial".len() as u64);
+
+        assert_eq!(storage.list_uploads(None).await?.len(), 2);
+
+        storage
+            .delete_upload_container("test".to_string(), upload_container.uuid.clone())
+            .await?;
+        assert!(storage
+            .list_uploads(Some("test".to_string()))
+            .await?
+            .is_empty());
+        assert!(
+            !storage
+                .check_upload_container_validity("test".to_string(), upload_container.uuid)
+                .await?
+        );
+
+        Ok(())
+    }
+
+    pub async fn test_update_manifest(storage: Arc<dyn Storage>) -> Result<()> {
+        let name = "test".to_string();
+        let reference = "latest".to_string();
+
+        let details = storage
+            .update_manifest(name.clone(), reference.clone(), TEST_MANIFEST.as_bytes())
+            .await?;
+        assert_eq!(details.digest, sha256_digest(TEST_MANIFEST.as_bytes()));
+
+        assert!(
+            storage
+                .manifest_exists(name.clone(), details.digest.clone())
+                .await?
+        );
+        assert!(
+            !storage
+                .manifest_exists(name.clone(), "missing".to_string())
+                .await?
+        );
+
+        let manifest = storage.get_manifest(name, reference).await?;
+        assert_eq!(manifest.digest, details.digest);
+        assert_eq!(manifest.content, TEST_MANIFEST.as_bytes());
+
+        Ok(())
+    }
+
+    /// Uploads a layer to the `test` repository and checks it's listed and
+    /// verified, returning its digest so backends can then corrupt it.
+    pub async fn test_verify_blob(storage: Arc<dyn Storage>) -> Result<String> {
+        let digest = upload_layer(&storage, "test", b"layer content").await?;
+
+        assert_eq!(storage.list_repositories().await?, vec!["test".to_string()]);
+        assert_eq!(
+            storage.list_layers("test".to_string()).await?,
+            vec![digest.clone()]
+        );
+
+        let verification = storage
+            .verify_blob("test".to_string(), digest.clone())
+            .await?;
+        assert!(verification.is_valid());
+
+        Ok(digest)
+    }
+
+    pub async fn test_list_tags(storage: Arc<dyn Storage>) -> Result<()> {
+        assert!(storage.list_tags("test".to_string()).await?.is_empty());
+
+        for reference in ["v1", "latest"] {
+            storage
+                .update_manifest(
+                    "test".to_string(),
+                    reference.to_string(),
+                    TEST_MANIFEST.as_bytes(),
+                )
+                .await?;
+        }
+        storage
+            .update_manifest(
+                "test".to_string(),
+                sha256_digest(TEST_MANIFEST.as_bytes()),
+                TEST_MANIFEST.as_bytes(),
+            )
+            .await?;
+
+        assert_eq!(
+            storage.list_tags("test".to_string()).await?,
+            vec!["latest".to_string(), "v1".to_string()]
+        );
+
+        Ok(())
+    }
+
+    /// Pushes a manifest along with three referrers to the `test` repository,
+    /// indexing them the way the API does.
+    pub async fn test_referrers(storage: Arc<dyn Storage>) -> Result<()> {
+        let name = "test".to_string();
+        let subject = storage
+            .update_manifest(name.clone(), "latest".to_string(), TEST_MANIFEST.as_bytes())
+            .await?
+            .digest;
+
+        let mut referrers = Vec::new();
+        for (tag, artifact_type) in [
+            ("signature-1", "application/vnd.example.signature"),
+            ("sbom", "application/vnd.example.sbom"),
+            ("signature-2", "application/vnd.example.signature"),
+        ] {
+            let content = serde_json::to_vec(&serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": OCI_IMAGE_MANIFEST_MEDIA_TYPE,
+                "artifactType": artifact_type,
+                "config": {
+                    "mediaType": "application/vnd.oci.empty.v1+json",
+                    "size": 2,
+                    "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                },
+                "layers": [],
+                "subject": {
+                    "mediaType": OCI_IMAGE_MANIFEST_MEDIA_TYPE,
+                    "size": TEST_MANIFEST.len(),
+                    "digest": subject,
+                },
+                "annotations": { "tag": tag },
+            }))?;
+
+            let details = storage
+                .update_manifest(name.clone(), tag.to_string(), &content)
+                .await?;
+            let manifest = serde_json::from_slice(&content)?;
+            let referrer = Referrer::new(&manifest, details.digest, content.len() as u64);
+
+            storage
+                .add_referrer(name.clone(), subject.clone(), referrer.clone())
+                .await?;
+            referrers.push(referrer);
+        }
+
+        let sorted = |mut referrers: Vec<Referrer>| {
+            referrers.sort_by(|a, b| a.digest.cmp(&b.digest));
+            referrers
+        };
+
+        assert_eq!(
+            sorted(
+                storage
+                    .find_referrers(name.clone(), subject.clone(), None)
+                    .await?
+            ),
+            sorted(referrers.clone())
+        );
+
+        let signatures = storage
+            .find_referrers(
+                name.clone(),
+                subject.clone(),
+                Some("application/vnd.example.signature".to_string()),
+            )
+            .await?;
+        assert_eq!(
+            sorted(signatures),
+            sorted(vec![referrers[0].clone(), referrers[2].clone()])
+        );
+
+        storage.clear_referrers(name.clone()).await?;
+        assert!(storage
+            .list_referrers(name.clone(), subject.clone())
+            .await?
+            .is_empty());
+
+        storage.rebuild_referrers(name.clone()).await?;
+        assert_eq!(
+            sorted(
+                storage
+                    .list_referrers(name.clone(), subject.clone())
+                    .await?
+            ),
+            sorted(referrers.clone())
+        );
+
+        storage
+            .remove_referrer(name.clone(), subject.clone(), referrers[1].digest.clone())
+            .await?;
+        assert_eq!(storage.list_referrers(name, subject).await?.len(), 2);
+
+        Ok(())
+    }
+
+    pub async fn test_delete_repository(storage: Arc<dyn Storage>) -> Result<()> {
+        let digest = upload_layer(&storage, "test", b"shared layer").await?;
+        upload_layer(&storage, "other", b"shared layer").await?;
+        storage
+            .update_manifest(
+                "test".to_string(),
+                "latest".to_string(),
+                TEST_MANIFEST.as_bytes(),
+            )
+            .await?;
+
+        storage.delete_repository("test".to_string()).await?;
+
+        assert_eq!(storage.list_repositories().await?, vec!["other"]);
+        assert!(storage.list_tags("test".to_string()).await?.is_empty());
+        assert!(storage
+            .get_image_layer_info("test".to_string(), digest.clone())
+            .await?
+            .is_none());
+        assert!(storage
+            .verify_blob("other".to_string(), digest)
+            .await?
+            .is_valid());
+
+        let result = storage.delete_repository("test".to_string()).await;
+        assert!(matches!(result, Err(e) if is_not_found(&e)));
+
+        Ok(())
+    }
+
+    pub async fn test_delete_nested_repository(storage: Arc<dyn Storage>) -> Result<()> {
+        let parent = upload_layer(&storage, "a", b"parent layer").await?;
+        let nested = upload_layer(&storage, "a/b", b"nested layer").await?;
+        storage
+            .update_manifest(
+                "a/b".to_string(),
+                "latest".to_string(),
+                TEST_MANIFEST.as_bytes(),
+            )
+            .await?;
+
+        storage.delete_repository("a".to_string()).await?;
+
+        assert!(storage
+            .get_image_layer_info("a".to_string(), parent)
+            .await?
+            .is_none());
+        assert!(storage
+            .get_image_layer_info("a/b".to_string(), nested)
+            .await?
+            .is_some());
+        assert_eq!(storage.list_tags("a/b".to_string()).await?, vec!["latest"]);
+
+        let result = storage.delete_repository("a/..".to_string()).await;
+        assert!(matches!(result, Err(e) if is_not_found(&e)));
+
+        Ok(())
+    }
+
+    pub async fn test_repository_size(storage: Arc<dyn Storage>) -> Result<()> {
+        assert_eq!(storage.repository_size("test".to_string()).await?, 0);
+
+        upload_layer(&storage, "test", b"layer content").await?;
+        storage
+            .update_manifest(
+                "test".to_string(),
+                "latest".to_string(),
+                TEST_MANIFEST.as_bytes(),
+            )
+            .await?;
+        upload_layer(&storage, "other", b"other layer content").await?;
+
+        assert_eq!(
+            storage.repository_size("test".to_string()).await?,
+            (b"layer content".len() + TEST_MANIFEST.len()) as u64
+        );
+
+        Ok(())
+    }
 }
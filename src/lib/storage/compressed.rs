@@ -0,0 +1,536 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
+use flate2::{
+    write::{GzEncoder, MultiGzDecoder},
+    Compression,
+};
+use futures::{Stream, StreamExt, TryStreamExt};
+
+use super::{
+    base::{check_upload_digest, ImageLayerInfo, Result, Storage, UploadContainer},
+    types::referrer::Referrer,
+    DigestAlgorithm, Error, ManifestDetails, ManifestSummary, StorageError, UpdateManifestDetails,
+    UploadDetails, UploadInfo, UploadStatus,
+};
+
+/// Bytes of the uncompressed size stored ahead of each layer.
+const SIZE_HEADER_SIZE: usize = 8;
+
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+/// Gzip-compresses layers before they reach the wrapped storage, trading CPU
+/// time on every push and pull for disk space. Worth it for layers pushed
+/// uncompressed, while already compressed ones only get slightly larger.
+///
+/// Digests and sizes reported to clients are those of the uncompressed
+/// content. Uploads are compressed as they're received, then read twice once
+/// complete to hash their content and store them under its digest, prefixed
+/// by their uncompressed size so it's known without decompressing them.
+/// Manifests are stored as is, and layers stored before enabling compression
+/// can't be read anymore.
+///
+/// Sizes of uploads in progress are tracked in memory, so an upload resumed
+/// after a restart reports its compressed size.
+pub struct CompressedStorage {
+    backend: Arc<dyn Storage>,
+    level: Compression,
+    upload_sizes: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl CompressedStorage {
+    pub fn new(backend: Arc<dyn Storage>) -> CompressedStorage {
+        CompressedStorage::with_level(backend, Compression::default())
+    }
+
+    pub fn with_level(backend: Arc<dyn Storage>, level: Compression) -> CompressedStorage {
+        CompressedStorage {
+            backend,
+            level,
+            upload_sizes: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Compresses `stream` as a single gzip member.
+fn compress_stream(level: Compression, stream: ByteStream) -> ByteStream {
+    let state = (stream, Some(GzEncoder::new(Vec::new(), level)));
+
+    Box::pin(futures::stream::try_unfold(
+        state,
+        |(mut stream, mut encoder)| async move {
+            loop {
+                let current = match encoder.as_mut() {
+                    Some(current) => current,
+                    None => return Result::Ok(None),
+                };
+
+                let compressed = match stream.next().await {
+                    Some(bytes) => {
+                        current.write_all(&bytes?)?;
+                        std::mem::take(current.get_mut())
+                    }
+                    None => encoder.take().unwrap().finish()?,
+                };
+
+                if !compressed.is_empty() {
+                    return Ok(Some((Bytes::from(compressed), (stream, encoder))));
+                }
+            }
+        },
+    ))
+}
+
+/// Decompresses `stream`, made of any number of gzip members. Empty uploads
+/// were never written to, so an empty stream is empty content.
+fn decompress_stream(stream: ByteStream) -> ByteStream {
+    let state = (stream, Some(MultiGzDecoder::new(Vec::new())), false);
+
+    Box::pin(futures::stream::try_unfold(
+        state,
+        |(mut stream, mut decoder, mut received)| async move {
+            loop {
+                let current = match decoder.as_mut() {
+                    Some(current) => current,
+                    None => return Result::Ok(None),
+                };
+
+                let decompressed = match stream.next().await {
+                    Some(bytes) => {
+                        let bytes = bytes?;
+                        received |= !bytes.is_empty();
+                        current.write_all(&bytes)?;
+                        std::mem::take(current.get_mut())
+                    }
+                    None if received => decoder.take().unwrap().finish()?,
+                    None => return Ok(None),
+                };
+
+                if !decompressed.is_empty() {
+                    let state = (stream, decoder, received);
+                    return Ok(Some((Bytes::from(decompressed), state)));
+                }
+            }
+        },
+    ))
+}
+
+/// Splits the uncompressed size stored ahead of a layer from its content.
+async fn read_size_header(mut stream: ByteStream) -> Result<(u64, ByteStream)> {
+    let mut header = BytesMut::new();
+    while header.len() < SIZE_HEADER_SIZE {
+        match stream.next().await {
+            Some(bytes) => header.extend_from_slice(&bytes?),
+            None => return Err(Error::from("Truncated compressed layer")),
+        }
+    }
+
+    let size = header.get_u64();
+    let rest = futures::stream::once(async move { Ok(header.freeze()) });
+
+    Ok((size, Box::pin(rest.chain(stream))))
+}
+
+#[async_trait]
+impl Storage for CompressedStorage {
+    async fn get_image_layer_info(
+        &self,
+        name: String,
+        digest: String,
+    ) -> Result<Option<ImageLayerInfo>> {
+        if !self
+            .backend
+            .blob_exists(name.clone(), digest.clone())
+            .await?
+        {
+            return Ok(None);
+        }
+
+        let stream = self.backend.get_layer(name, digest).await?;
+        let (size, _) = read_size_header(stream).await?;
+
+        Ok(Some(ImageLayerInfo { size }))
+    }
+
+    async fn blob_exists(&self, name: String, digest: String) -> Result<bool> {
+        self.backend.blob_exists(name, digest).await
+    }
+
+    async fn get_shared_blob_size(&self, digest: String) -> Result<Option<u64>> {
+        self.backend.get_shared_blob_size(digest).await
+    }
+
+    async fn get_layer(&self, name: String, digest: String) -> Result<ByteStream> {
+        let stream = self.backend.get_layer(name, digest).await?;
+        let (_, stream) = read_size_header(stream).await?;
+
+        Ok(decompress_stream(stream))
+    }
+
+    async fn create_upload_container(&self, name: String) -> Result<UploadContainer> {
+        self.backend.create_upload_container(name).await
+    }
+
+    async fn check_upload_container_validity(&self, name: String, uuid: String) -> Result<bool> {
+        self.backend
+            .check_upload_container_validity(name, uuid)
+            .await
+    }
+
+    async fn write_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        stream: ByteStream,
+        range: (u64, u64),
+    ) -> Result<UploadStatus> {
+        // The stored bytes don't map to the plaintext ones, so chunks can
+        // only be appended, at the end of what the backend holds
+        let uploaded = self
+            .get_upload_status(name.clone(), uuid.clone())
+            .await?
+            .size;
+        if range.0 != uploaded {
+            return Err(Box::new(StorageError::RangeInvalid {
+                start: range.0,
+                size: uploaded,
+            }));
+        }
+        let stored = self
+            .backend
+            .get_upload_status(name.clone(), uuid.clone())
+            .await?
+            .size;
+
+        let written = Arc::new(AtomicU64::new(0));
+        let counter = written.clone();
+        let stream = stream.inspect_ok(move |bytes| {
+            counter.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        });
+
+        self.backend
+            .write_upload_container(
+                name.clone(),
+                uuid.clone(),
+                compress_stream(self.level, Box::pin(stream)),
+                (stored, stored),
+            )
+            .await?;
+
+        let mut upload_sizes = self.upload_sizes.lock().unwrap();
+        let size = upload_sizes.entry((name, uuid)).or_insert(0);
+        *size += written.load(Ordering::Relaxed);
+
+        Ok(UploadStatus { size: *size })
+    }
+
+    async fn get_upload_status(&self, name: String, uuid: String) -> Result<UploadStatus> {
+        let size = self
+            .upload_sizes
+            .lock()
+            .unwrap()
+            .get(&(name.clone(), uuid.clone()))
+            .copied();
+
+        match size {
+            Some(size) => Ok(UploadStatus { size }),
+            None => self.backend.get_upload_status(name, uuid).await,
+        }
+    }
+
+    async fn list_uploads(&self, name: Option<String>) -> Result<Vec<UploadInfo>> {
+        let uploads = self.backend.list_uploads(name).await?;
+        let upload_sizes = self.upload_sizes.lock().unwrap();
+
+        Ok(uploads
+            .into_iter()
+            .map(|upload| {
+                let size = upload_sizes
+                    .get(&(upload.name.clone(), upload.uuid.clone()))
+                    .copied()
+                    .unwrap_or(upload.size);
+
+                UploadInfo { size, ..upload }
+            })
+            .collect())
+    }
+
+    async fn delete_upload_container(&self, name: String, uuid: String) -> Result<()> {
+        self.upload_sizes
+            .lock()
+            .unwrap()
+            .remove(&(name.clone(), uuid.clone()));
+
+        self.backend.delete_upload_container(name, uuid).await
+    }
+
+    async fn close_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: Option<String>,
+    ) -> Result<UploadDetails> {
+        self.upload_sizes
+            .lock()
+            .unwrap()
+            .remove(&(name.clone(), uuid.clone()));
+
+        // The backend can only address the upload by the digest of its
+        // compressed content, so it's copied under the digest of its content
+        let compressed = self
+            .backend
+            .close_upload_container(name.clone(), uuid, None)
+            .await?;
+
+        let mut hasher = digest
+            .as_deref()
+            .and_then(DigestAlgorithm::of)
+            .unwrap_or_else(|| self.digest_algorithm())
+            .hasher();
+        let mut size = 0;
+
+        let stream = self
+            .backend
+            .get_layer(name.clone(), compressed.digest.clone())
+            .await?;
+        let mut content = decompress_stream(stream);
+        while let Some(bytes) = content.next().await {
+            let bytes = bytes?;
+            hasher.update(&bytes);
+            size += bytes.len() as u64;
+        }
+
+        let actual = hasher.finalize();
+
+        if let Err(e) = check_upload_digest(digest.as_deref(), &actual) {
+            self.backend.delete_layer(name, compressed.digest).await?;
+            return Err(e);
+        }
+
+        let stream = self
+            .backend
+            .get_layer(name.clone(), compressed.digest.clone())
+            .await?;
+        let header =
+            futures::stream::once(async move { Ok(Bytes::from(size.to_be_bytes().to_vec())) });
+
+        let upload_container = self.backend.create_upload_container(name.clone()).await?;
+        self.backend
+            .write_upload_container(
+                name.clone(),
+                upload_container.uuid.clone(),
+                Box::pin(header.chain(stream)),
+                (0, 0),
+            )
+            .await?;
+
+        let details = self
+            .backend
+            .store_upload_container(name.clone(), upload_container.uuid, actual)
+            .await?;
+        self.backend.delete_layer(name, compressed.digest).await?;
+
+        Ok(details)
+    }
+
+    async fn store_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: String,
+    ) -> Result<UploadDetails> {
+        self.upload_sizes
+            .lock()
+            .unwrap()
+            .remove(&(name.clone(), uuid.clone()));
+
+        self.backend
+            .store_upload_container(name, uuid, digest)
+            .await
+    }
+
+    async fn get_manifest_summary(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<ManifestSummary> {
+        self.backend.get_manifest_summary(name, reference).await
+    }
+
+    async fn manifest_exists(&self, name: String, reference: String) -> Result<bool> {
+        self.backend.manifest_exists(name, reference).await
+    }
+
+    async fn get_manifest(&self, name: String, reference: String) -> Result<ManifestDetails> {
+        self.backend.get_manifest(name, reference).await
+    }
+
+    async fn get_manifest_raw(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<(Bytes, String, String)> {
+        self.backend.get_manifest_raw(name, reference).await
+    }
+
+    async fn update_manifest(
+        &self,
+        name: String,
+        reference: String,
+        content: &[u8],
+    ) -> Result<UpdateManifestDetails> {
+        self.backend.update_manifest(name, reference, content).await
+    }
+
+    async fn delete_manifest(&self, name: String, reference: String) -> Result<()> {
+        self.backend.delete_manifest(name, reference).await
+    }
+
+    async fn delete_layer(&self, name: String, digest: String) -> Result<()> {
+        self.backend.delete_layer(name, digest).await
+    }
+
+    async fn set_blob_media_type(
+        &self,
+        name: String,
+        digest: String,
+        media_type: String,
+    ) -> Result<()> {
+        self.backend
+            .set_blob_media_type(name, digest, media_type)
+            .await
+    }
+
+    async fn get_blob_media_type(&self, name: String, digest: String) -> Result<Option<String>> {
+        self.backend.get_blob_media_type(name, digest).await
+    }
+
+    async fn delete_repository(&self, name: String) -> Result<()> {
+        self.backend.delete_repository(name).await
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        self.backend.list_repositories().await
+    }
+
+    async fn list_layers(&self, name: String) -> Result<Vec<String>> {
+        self.backend.list_layers(name).await
+    }
+
+    async fn list_tags(&self, name: String) -> Result<Vec<String>> {
+        self.backend.list_tags(name).await
+    }
+
+    async fn list_manifests(&self, name: String) -> Result<Vec<String>> {
+        self.backend.list_manifests(name).await
+    }
+
+    async fn add_referrer(&self, name: String, subject: String, referrer: Referrer) -> Result<()> {
+        self.backend.add_referrer(name, subject, referrer).await
+    }
+
+    async fn remove_referrer(&self, name: String, subject: String, digest: String) -> Result<()> {
+        self.backend.remove_referrer(name, subject, digest).await
+    }
+
+    async fn list_referrers(&self, name: String, subject: String) -> Result<Vec<Referrer>> {
+        self.backend.list_referrers(name, subject).await
+    }
+
+    async fn clear_referrers(&self, name: String) -> Result<()> {
+        self.backend.clear_referrers(name).await
+    }
+
+    async fn link_manifest_digests(&self, name: String) -> Result<()> {
+        self.backend.link_manifest_digests(name).await
+    }
+
+    async fn repository_size(&self, name: String) -> Result<u64> {
+        self.backend.repository_size(name).await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.backend.health_check().await
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.backend.digest_algorithm()
+    }
+}
+
+#[cfg(test)]
+fn compressed_local_storage(path: &std::path::Path) -> (Arc<dyn Storage>, CompressedStorage) {
+    let backend: Arc<dyn Storage> = Arc::new(super::LocalStorage::new(path));
+    let storage = CompressedStorage::new(backend.clone());
+
+    (backend, storage)
+}
+
+#[tokio::test]
+async fn test_upload_layer() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let (_, storage) = compressed_local_storage(temp_dir.path());
+
+    super::tests::test_upload_layer(Arc::new(storage)).await
+}
+
+#[tokio::test]
+async fn test_layer_round_trip_stores_compressed() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let (backend, storage) = compressed_local_storage(temp_dir.path());
+    let storage: Arc<dyn Storage> = Arc::new(storage);
+
+    let content = b"uncompressed tarball ".repeat(4096);
+
+    // Pushed in two chunks, stored as two gzip members until complete
+    let upload_container = storage.create_upload_container("test".to_string()).await?;
+    for (start, chunk) in [(0, &content[..1000]), (1000, &content[1000..])] {
+        let stream = futures::stream::iter(vec![Ok(Bytes::copy_from_slice(chunk))]);
+        storage
+            .write_upload_container(
+                "test".to_string(),
+                upload_container.uuid.clone(),
+                Box::pin(stream),
+                (start, start + chunk.len() as u64),
+            )
+            .await?;
+    }
+    let details = storage
+        .close_upload_container("test".to_string(), upload_container.uuid, None)
+        .await?;
+    assert_eq!(details.digest, super::tests::sha256_digest(&content));
+
+    let pulled = storage
+        .get_layer("test".to_string(), details.digest.clone())
+        .await?
+        .map_ok(|bytes| bytes.to_vec())
+        .try_concat()
+        .await?;
+    assert_eq!(pulled, content);
+
+    let info = storage
+        .get_image_layer_info("test".to_string(), details.digest.clone())
+        .await?;
+    assert_eq!(info.map(|info| info.size), Some(content.len() as u64));
+
+    // Only the uncompressed digest remains, holding far fewer bytes
+    assert_eq!(
+        backend.list_layers("test".to_string()).await?,
+        vec![details.digest.clone()]
+    );
+    let stored = backend
+        .get_image_layer_info("test".to_string(), details.digest)
+        .await?;
+    assert!(stored.is_some_and(|stored| stored.size < content.len() as u64 / 10));
+
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use std::{fmt, str::FromStr};
+
+use sha2::{Digest, Sha256, Sha512};
+
+/// Hash function content is addressed with, SHA-256 unless configured
+/// otherwise. Content stored under other algorithms can still be looked up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    pub const ALL: [DigestAlgorithm; 2] = [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512];
+
+    /// Prefix of the digests, e.g. `sha256`.
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// Number of hex characters of the digests.
+    fn hex_len(self) -> usize {
+        match self {
+            DigestAlgorithm::Sha256 => 64,
+            DigestAlgorithm::Sha512 => 128,
+        }
+    }
+
+    /// Algorithm `digest` was computed with, `None` when it isn't a digest.
+    pub fn of(digest: &str) -> Option<DigestAlgorithm> {
+        let (name, hex) = digest.split_once(':')?;
+
+        DigestAlgorithm::ALL.into_iter().find(|algorithm| {
+            algorithm.name() == name
+                && hex.len() == algorithm.hex_len()
+                && hex.chars().all(|c| c.is_ascii_hexdigit())
+        })
+    }
+
+    pub fn hasher(self) -> DigestHasher {
+        match self {
+            DigestAlgorithm::Sha256 => DigestHasher::Sha256(Sha256::new()),
+            DigestAlgorithm::Sha512 => DigestHasher::Sha512(Sha512::new()),
+        }
+    }
+
+    /// `<algorithm>:<hex>` digest of `content`.
+    pub fn digest<C>(self, content: C) -> String
+    where
+        C: AsRef<[u8]>,
+    {
+        let mut hasher = self.hasher();
+        hasher.update(content);
+        hasher.finalize()
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<DigestAlgorithm, String> {
+        DigestAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name() == name)
+            .ok_or_else(|| format!("Unsupported digest algorithm '{}'", name))
+    }
+}
+
+/// Incremental hashing with a `DigestAlgorithm`.
+#[derive(Clone)]
+pub enum DigestHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl DigestHasher {
+    pub fn update<C>(&mut self, content: C)
+    where
+        C: AsRef<[u8]>,
+    {
+        match self {
+            DigestHasher::Sha256(hasher) => hasher.update(content),
+            DigestHasher::Sha512(hasher) => hasher.update(content),
+        }
+    }
+
+    /// `<algorithm>:<hex>` digest of the content hashed so far.
+    pub fn finalize(self) -> String {
+        match self {
+            DigestHasher::Sha256(hasher) => format!("sha256:{}", hex::encode(hasher.finalize())),
+            DigestHasher::Sha512(hasher) => format!("sha512:{}", hex::encode(hasher.finalize())),
+        }
+    }
+}
+
+pub fn is_digest(digest: &str) -> bool {
+    DigestAlgorithm::of(digest).is_some()
+}
+
+/// Lowercase form of a `<algorithm>:<hex>` digest, the one content is stored
+/// under, or `None` when `digest` isn't one.
+pub fn canonical_digest(digest: &str) -> Option<String> {
+    let digest = digest.to_ascii_lowercase();
+    is_digest(&digest).then_some(digest)
+}
+
+#[test]
+fn test_digest_algorithm() {
+    let sha256 = DigestAlgorithm::Sha256.digest(b"content");
+    let sha512 = DigestAlgorithm::Sha512.digest(b"content");
+
+    assert_eq!(
+        sha256,
+        "sha256:ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73"
+    );
+    assert!(sha512.starts_with("sha512:") && sha512.len() == 7 + 128);
+
+    assert_eq!(DigestAlgorithm::of(&sha256), Some(DigestAlgorithm::Sha256));
+    assert_eq!(DigestAlgorithm::of(&sha512), Some(DigestAlgorithm::Sha512));
+    assert_eq!(
+        DigestAlgorithm::of(&sha512.replace("sha512", "sha256")),
+        None
+    );
+    assert_eq!(DigestAlgorithm::of("sha256:xyz"), None);
+    assert_eq!(DigestAlgorithm::of("latest"), None);
+
+    assert_eq!(
+        canonical_digest(&sha512.to_uppercase().replace("SHA512", "sha512")),
+        Some(sha512)
+    );
+    assert_eq!("sha512".parse(), Ok(DigestAlgorithm::Sha512));
+    assert!("md5".parse::<DigestAlgorithm>().is_err());
+}
@@ -0,0 +1,634 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use futures::{Stream, StreamExt, TryStreamExt};
+use rand::Rng;
+use serde_json::Value;
+
+use super::{
+    base::{check_upload_digest, ImageLayerInfo, Result, Storage, UploadContainer},
+    is_digest,
+    types::{manifest::Manifest, referrer::Referrer},
+    DigestAlgorithm, Error, ManifestDetails, ManifestSummary, StorageError, UpdateManifestDetails,
+    UploadDetails, UploadInfo, UploadStatus,
+};
+
+/// Bytes of plaintext sealed in each record.
+const SEGMENT_SIZE: usize = 64 * 1024;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// Length prefix, nonce and authentication tag added to every record.
+const RECORD_OVERHEAD: usize = 4 + NONCE_SIZE + TAG_SIZE;
+
+/// Field of the JSON envelope manifests are stored in.
+const ENVELOPE_FIELD: &str = "encrypted";
+
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+/// Encrypts layers and manifests with ChaCha20-Poly1305 before they reach the
+/// wrapped storage, which only ever sees ciphertext.
+///
+/// Content is sealed in records of up to 64 KiB of plaintext, each prefixed
+/// by its length and a random nonce. Digests are still computed over the
+/// plaintext, so layers are moved to their plaintext digest once uploaded,
+/// at the cost of writing them twice. Manifests are stored in a JSON envelope
+/// so backends parsing them still accept them, along with a copy under their
+/// digest when pushed by tag. Manifests stored before enabling encryption are
+/// served as is, but such layers can't be read anymore.
+///
+/// Tags, repository names and the referrers index aren't encrypted. Sizes of
+/// uploads in progress are tracked in memory, so an upload resumed after a
+/// restart reports the bytes received since then only.
+pub struct EncryptedStorage {
+    backend: Arc<dyn Storage>,
+    cipher: ChaCha20Poly1305,
+    upload_sizes: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl EncryptedStorage {
+    pub fn new(backend: Arc<dyn Storage>, key: &[u8; 32]) -> EncryptedStorage {
+        EncryptedStorage {
+            backend,
+            cipher: ChaCha20Poly1305::new(key.into()),
+            upload_sizes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Decrypts a manifest stored by `update_manifest`, returning the
+    /// plaintext details, its digest computed with `algorithm`.
+    fn open_manifest(
+        &self,
+        details: ManifestDetails,
+        algorithm: DigestAlgorithm,
+    ) -> Result<ManifestDetails> {
+        let envelope = match details.manifest.extra.get(ENVELOPE_FIELD) {
+            Some(Value::String(envelope)) => envelope,
+            _ => return Ok(details),
+        };
+
+        let mut record = Bytes::from(base64::decode(envelope)?);
+        let content = open_record(&self.cipher, &mut record)?
+            .ok_or_else(|| Error::from("Truncated encrypted manifest"))?;
+
+        Ok(ManifestDetails {
+            manifest: serde_json::from_slice::<Manifest>(&content)?,
+            digest: algorithm.digest(&content),
+            content,
+        })
+    }
+}
+
+/// Number of plaintext bytes stored in `size` bytes of records holding full
+/// segments but the last one.
+fn plaintext_size(size: u64) -> u64 {
+    let record_size = (SEGMENT_SIZE + RECORD_OVERHEAD) as u64;
+    let records = size.div_ceil(record_size);
+
+    size.saturating_sub(records * RECORD_OVERHEAD as u64)
+}
+
+fn seal_record(cipher: &ChaCha20Poly1305, plaintext: &[u8]) -> Result<Bytes> {
+    let nonce = rand::thread_rng().gen::<[u8; NONCE_SIZE]>();
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| Error::from("Failed to encrypt content"))?;
+
+    let mut record = BytesMut::with_capacity(RECORD_OVERHEAD + plaintext.len());
+    record.put_u32(ciphertext.len() as u32);
+    record.put_slice(&nonce);
+    record.put_slice(&ciphertext);
+
+    Ok(record.freeze())
+}
+
+/// Decrypts the record at the start of `buffer`, consuming it, or returns
+/// `None` when `buffer` doesn't hold a whole record yet.
+fn open_record<B: Buf>(cipher: &ChaCha20Poly1305, buffer: &mut B) -> Result<Option<Bytes>> {
+    if buffer.remaining() < 4 {
+        return Ok(None);
+    }
+
+    let length = u32::from_be_bytes(buffer.chunk()[..4].try_into()?) as usize;
+    if buffer.remaining() < 4 + NONCE_SIZE + length {
+        return Ok(None);
+    }
+
+    buffer.advance(4);
+    let record = buffer.copy_to_bytes(NONCE_SIZE + length);
+    let (nonce, ciphertext) = record.split_at(NONCE_SIZE);
+
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::from("Failed to decrypt content, the key may be wrong"))?;
+
+    Ok(Some(Bytes::from(plaintext)))
+}
+
+/// Seals `stream` in records, all holding a full segment but the last one.
+fn encrypt_stream(cipher: ChaCha20Poly1305, stream: ByteStream) -> ByteStream {
+    let state = (stream, BytesMut::new(), false);
+
+    Box::pin(futures::stream::try_unfold(
+        state,
+        move |(mut stream, mut buffer, mut done)| {
+            let cipher = cipher.clone();
+            async move {
+                loop {
+                    if buffer.len() >= SEGMENT_SIZE || (done && !buffer.is_empty()) {
+                        let length = buffer.len().min(SEGMENT_SIZE);
+                        let record = seal_record(&cipher, &buffer.split_to(length))?;
+                        return Ok(Some((record, (stream, buffer, done))));
+                    }
+
+                    if done {
+                        return Ok(None);
+                    }
+
+                    match stream.next().await {
+                        Some(bytes) => buffer.extend_from_slice(&bytes?),
+                        None => done = true,
+                    }
+                }
+            }
+        },
+    ))
+}
+
+fn decrypt_stream(cipher: ChaCha20Poly1305, stream: ByteStream) -> ByteStream {
+    let state = (stream, BytesMut::new(), false);
+
+    Box::pin(futures::stream::try_unfold(
+        state,
+        move |(mut stream, mut buffer, mut done)| {
+            let cipher = cipher.clone();
+            async move {
+                loop {
+                    if let Some(plaintext) = open_record(&cipher, &mut buffer)? {
+                        return Ok(Some((plaintext, (stream, buffer, done))));
+                    }
+
+                    if done {
+                        if buffer.is_empty() {
+                            return Ok(None);
+                        }
+
+                        return Err(Error::from("Truncated encrypted content"));
+                    }
+
+                    match stream.next().await {
+                        Some(bytes) => buffer.extend_from_slice(&bytes?),
+                        None => done = true,
+                    }
+                }
+            }
+        },
+    ))
+}
+
+#[async_trait]
+impl Storage for EncryptedStorage {
+    async fn get_image_layer_info(
+        &self,
+        name: String,
+        digest: String,
+    ) -> Result<Option<ImageLayerInfo>> {
+        let info = self.backend.get_image_layer_info(name, digest).await?;
+
+        Ok(info.map(|info| ImageLayerInfo {
+            size: plaintext_size(info.size),
+        }))
+    }
+
+    async fn blob_exists(&self, name: String, digest: String) -> Result<bool> {
+        self.backend.blob_exists(name, digest).await
+    }
+
+    async fn get_shared_blob_size(&self, digest: String) -> Result<Option<u64>> {
+        self.backend.get_shared_blob_size(digest).await
+    }
+
+    async fn get_layer(&self, name: String, digest: String) -> Result<ByteStream> {
+        let stream = self.backend.get_layer(name, digest).await?;
+        Ok(decrypt_stream(self.cipher.clone(), stream))
+    }
+
+    async fn create_upload_container(&self, name: String) -> Result<UploadContainer> {
+        self.backend.create_upload_container(name).await
+    }
+
+    async fn check_upload_container_validity(&self, name: String, uuid: String) -> Result<bool> {
+        self.backend
+            .check_upload_container_validity(name, uuid)
+            .await
+    }
+
+    async fn write_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        stream: ByteStream,
+        range: (u64, u64),
+    ) -> Result<UploadStatus> {
+        // The stored bytes don't map to the plaintext ones, so chunks can
+        // only be appended, at the end of what the backend holds
+        let uploaded = self
+            .get_upload_status(name.clone(), uuid.clone())
+            .await?
+            .size;
+        if range.0 != uploaded {
+            return Err(Box::new(StorageError::RangeInvalid {
+                start: range.0,
+                size: uploaded,
+            }));
+        }
+        let stored = self
+            .backend
+            .get_upload_status(name.clone(), uuid.clone())
+            .await?
+            .size;
+
+        let written = Arc::new(AtomicU64::new(0));
+        let counter = written.clone();
+        let stream = stream.inspect_ok(move |bytes| {
+            counter.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        });
+
+        self.backend
+            .write_upload_container(
+                name.clone(),
+                uuid.clone(),
+                encrypt_stream(self.cipher.clone(), Box::pin(stream)),
+                (stored, stored),
+            )
+            .await?;
+
+        let mut upload_sizes = self.upload_sizes.lock().unwrap();
+        let size = upload_sizes.entry((name, uuid)).or_insert(0);
+        *size += written.load(Ordering::Relaxed);
+
+        Ok(UploadStatus { size: *size })
+    }
+
+    async fn get_upload_status(&self, name: String, uuid: String) -> Result<UploadStatus> {
+        let size = self
+            .upload_sizes
+            .lock()
+            .unwrap()
+            .get(&(name.clone(), uuid.clone()))
+            .copied();
+
+        match size {
+            Some(size) => Ok(UploadStatus { size }),
+            None => {
+                let status = self.backend.get_upload_status(name, uuid).await?;
+                Ok(UploadStatus {
+                    size: plaintext_size(status.size),
+                })
+            }
+        }
+    }
+
+    async fn list_uploads(&self, name: Option<String>) -> Result<Vec<UploadInfo>> {
+        let uploads = self.backend.list_uploads(name).await?;
+        let upload_sizes = self.upload_sizes.lock().unwrap();
+
+        Ok(uploads
+            .into_iter()
+            .map(|upload| {
+                let size = upload_sizes
+                    .get(&(upload.name.clone(), upload.uuid.clone()))
+                    .copied()
+                    .unwrap_or_else(|| plaintext_size(upload.size));
+
+                UploadInfo { size, ..upload }
+            })
+            .collect())
+    }
+
+    async fn delete_upload_container(&self, name: String, uuid: String) -> Result<()> {
+        self.upload_sizes
+            .lock()
+            .unwrap()
+            .remove(&(name.clone(), uuid.clone()));
+
+        self.backend.delete_upload_container(name, uuid).await
+    }
+
+    async fn close_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: Option<String>,
+    ) -> Result<UploadDetails> {
+        self.upload_sizes
+            .lock()
+            .unwrap()
+            .remove(&(name.clone(), uuid.clone()));
+
+        // The backend can only address the upload by the digest of its
+        // ciphertext, so it's rewritten under the digest of its plaintext
+        let sealed = self
+            .backend
+            .close_upload_container(name.clone(), uuid, None)
+            .await?;
+
+        let hasher = Arc::new(Mutex::new(
+            digest
+                .as_deref()
+                .and_then(DigestAlgorithm::of)
+                .unwrap_or_else(|| self.digest_algorithm())
+                .hasher(),
+        ));
+        let plaintext = self
+            .get_layer(name.clone(), sealed.digest.clone())
+            .await?
+            .inspect_ok({
+                let hasher = hasher.clone();
+                move |bytes| hasher.lock().unwrap().update(bytes)
+            });
+
+        let upload_container = self.backend.create_upload_container(name.clone()).await?;
+        self.backend
+            .write_upload_container(
+                name.clone(),
+                upload_container.uuid.clone(),
+                encrypt_stream(self.cipher.clone(), Box::pin(plaintext)),
+                (0, 0),
+            )
+            .await?;
+
+        let actual = hasher.lock().unwrap().clone().finalize();
+
+        if let Err(e) = check_upload_digest(digest.as_deref(), &actual) {
+            self.backend
+                .delete_upload_container(name.clone(), upload_container.uuid)
+                .await?;
+            self.backend.delete_layer(name, sealed.digest).await?;
+            return Err(e);
+        }
+
+        let details = self
+            .backend
+            .store_upload_container(name.clone(), upload_container.uuid, actual)
+            .await?;
+        self.backend.delete_layer(name, sealed.digest).await?;
+
+        Ok(details)
+    }
+
+    async fn store_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: String,
+    ) -> Result<UploadDetails> {
+        self.upload_sizes
+            .lock()
+            .unwrap()
+            .remove(&(name.clone(), uuid.clone()));
+
+        self.backend
+            .store_upload_container(name, uuid, digest)
+            .await
+    }
+
+    async fn get_manifest_summary(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<ManifestSummary> {
+        let details = self.get_manifest(name, reference).await?;
+
+        Ok(ManifestSummary {
+            digest: details.digest,
+            size: details.content.len() as u64,
+        })
+    }
+
+    async fn manifest_exists(&self, name: String, reference: String) -> Result<bool> {
+        self.backend.manifest_exists(name, reference).await
+    }
+
+    async fn get_manifest(&self, name: String, reference: String) -> Result<ManifestDetails> {
+        let algorithm = DigestAlgorithm::of(&reference).unwrap_or(self.digest_algorithm());
+        let details = self.backend.get_manifest(name, reference).await?;
+        self.open_manifest(details, algorithm)
+    }
+
+    async fn update_manifest(
+        &self,
+        name: String,
+        reference: String,
+        content: &[u8],
+    ) -> Result<UpdateManifestDetails> {
+        let digest = self.digest_algorithm().digest(content);
+
+        let envelope = serde_json::json!({
+            "schemaVersion": 2,
+            ENVELOPE_FIELD: base64::encode(seal_record(&self.cipher, content)?),
+        })
+        .to_string();
+
+        self.backend
+            .update_manifest(name.clone(), reference.clone(), envelope.as_bytes())
+            .await?;
+
+        // The backend links the manifest to the digest of the envelope only
+        if !is_digest(&reference) {
+            self.backend
+                .update_manifest(name, digest.clone(), envelope.as_bytes())
+                .await?;
+        }
+
+        Ok(UpdateManifestDetails { digest })
+    }
+
+    async fn delete_manifest(&self, name: String, reference: String) -> Result<()> {
+        self.backend.delete_manifest(name, reference).await
+    }
+
+    async fn delete_layer(&self, name: String, digest: String) -> Result<()> {
+        self.backend.delete_layer(name, digest).await
+    }
+
+    async fn set_blob_media_type(
+        &self,
+        name: String,
+        digest: String,
+        media_type: String,
+    ) -> Result<()> {
+        self.backend
+            .set_blob_media_type(name, digest, media_type)
+            .await
+    }
+
+    async fn get_blob_media_type(&self, name: String, digest: String) -> Result<Option<String>> {
+        self.backend.get_blob_media_type(name, digest).await
+    }
+
+    async fn delete_repository(&self, name: String) -> Result<()> {
+        self.backend.delete_repository(name).await
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        self.backend.list_repositories().await
+    }
+
+    async fn list_layers(&self, name: String) -> Result<Vec<String>> {
+        self.backend.list_layers(name).await
+    }
+
+    async fn list_tags(&self, name: String) -> Result<Vec<String>> {
+        self.backend.list_tags(name).await
+    }
+
+    async fn list_manifests(&self, name: String) -> Result<Vec<String>> {
+        self.backend.list_manifests(name).await
+    }
+
+    async fn add_referrer(&self, name: String, subject: String, referrer: Referrer) -> Result<()> {
+        self.backend.add_referrer(name, subject, referrer).await
+    }
+
+    async fn remove_referrer(&self, name: String, subject: String, digest: String) -> Result<()> {
+        self.backend.remove_referrer(name, subject, digest).await
+    }
+
+    async fn list_referrers(&self, name: String, subject: String) -> Result<Vec<Referrer>> {
+        self.backend.list_referrers(name, subject).await
+    }
+
+    async fn clear_referrers(&self, name: String) -> Result<()> {
+        self.backend.clear_referrers(name).await
+    }
+
+    async fn link_manifest_digests(&self, name: String) -> Result<()> {
+        self.backend.link_manifest_digests(name).await
+    }
+
+    async fn repository_size(&self, name: String) -> Result<u64> {
+        self.backend.repository_size(name).await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.backend.health_check().await
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.backend.digest_algorithm()
+    }
+}
+
+#[cfg(test)]
+fn encrypted_local_storage(path: &std::path::Path) -> (Arc<dyn Storage>, EncryptedStorage) {
+    let backend: Arc<dyn Storage> = Arc::new(super::LocalStorage::new(path));
+    let storage = EncryptedStorage::new(backend.clone(), &[7; 32]);
+
+    (backend, storage)
+}
+
+#[tokio::test]
+async fn test_upload_layer() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let (_, storage) = encrypted_local_storage(temp_dir.path());
+
+    super::tests::test_upload_layer(Arc::new(storage)).await
+}
+
+#[tokio::test]
+async fn test_update_manifest() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let (_, storage) = encrypted_local_storage(temp_dir.path());
+
+    super::tests::test_update_manifest(Arc::new(storage)).await
+}
+
+#[tokio::test]
+async fn test_layer_round_trip_stores_ciphertext() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let (backend, storage) = encrypted_local_storage(temp_dir.path());
+    let storage: Arc<dyn Storage> = Arc::new(storage);
+
+    let content = (0..3 * SEGMENT_SIZE + 123)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+
+    // Pushed in two chunks not aligned on segments
+    let upload_container = storage.create_upload_container("test".to_string()).await?;
+    for (start, chunk) in [(0, &content[..1000]), (1000, &content[1000..])] {
+        let stream = futures::stream::iter(vec![Ok(Bytes::copy_from_slice(chunk))]);
+        storage
+            .write_upload_container(
+                "test".to_string(),
+                upload_container.uuid.clone(),
+                Box::pin(stream),
+                (start, start + chunk.len() as u64),
+            )
+            .await?;
+    }
+    let details = storage
+        .close_upload_container("test".to_string(), upload_container.uuid, None)
+        .await?;
+    assert_eq!(details.digest, super::tests::sha256_digest(&content));
+
+    let pulled = storage
+        .get_layer("test".to_string(), details.digest.clone())
+        .await?
+        .map_ok(|bytes| bytes.to_vec())
+        .try_concat()
+        .await?;
+    assert_eq!(pulled, content);
+
+    let info = storage
+        .get_image_layer_info("test".to_string(), details.digest.clone())
+        .await?;
+    assert_eq!(info.map(|info| info.size), Some(content.len() as u64));
+
+    // Only the plaintext digest remains, holding ciphertext
+    assert_eq!(
+        backend.list_layers("test".to_string()).await?,
+        vec![details.digest.clone()]
+    );
+    let stored = backend
+        .get_layer("test".to_string(), details.digest)
+        .await?
+        .map_ok(|bytes| bytes.to_vec())
+        .try_concat()
+        .await?;
+    assert_ne!(stored, content);
+    assert!(!stored.windows(64).any(|window| window == &content[..64]));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_manifest_pulled_by_digest() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let (backend, storage) = encrypted_local_storage(temp_dir.path());
+
+    let manifest = super::tests::TEST_MANIFEST.as_bytes();
+    let details = storage
+        .update_manifest("test".to_string(), "latest".to_string(), manifest)
+        .await?;
+
+    let pulled = storage
+        .get_manifest("test".to_string(), details.digest.clone())
+        .await?;
+    assert_eq!(pulled.content, manifest);
+
+    let stored = backend
+        .get_manifest("test".to_string(), "latest".to_string())
+        .await?;
+    assert!(stored.manifest.layers.is_none());
+
+    Ok(())
+}
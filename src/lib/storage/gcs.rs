@@ -0,0 +1,868 @@
+use std::{collections::HashMap, pin::Pin};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use google_cloud_storage::{
+    client::Client,
+    http::{
+        objects::{
+            compose::{ComposeObjectRequest, ComposingTargets},
+            delete::DeleteObjectRequest,
+            download::Range,
+            get::GetObjectRequest,
+            list::ListObjectsRequest,
+            rewrite::RewriteObjectRequest,
+            upload::{Media, UploadObjectRequest, UploadType},
+            Object, SourceObjects,
+        },
+        Error as GcsError,
+    },
+};
+use sync_wrapper::SyncWrapper;
+use uuid::Uuid;
+
+use super::{
+    base::{check_upload_digest, ImageLayerInfo, Result, Storage, UploadContainer},
+    canonical_digest, is_digest,
+    layout::{is_repository_entry, is_repository_name},
+    retry::RetryPolicy,
+    types::{
+        manifest::{manifest_content_type, Manifest},
+        referrer::Referrer,
+    },
+    DigestAlgorithm, Error, ManifestDetails, ManifestSummary, StorageError, UpdateManifestDetails,
+    UploadDetails, UploadInfo, UploadState, UploadStatus,
+};
+
+/// Object metadata holding the digest of a stored manifest.
+const MANIFEST_DIGEST_METADATA: &str = "digest";
+
+pub struct GcsStorage {
+    pub bucket: String,
+    pub retry: RetryPolicy,
+    /// Algorithm the digests of uploaded layers and pushed manifests are
+    /// computed with.
+    pub digest_algorithm: DigestAlgorithm,
+    client: Client,
+}
+
+impl GcsStorage {
+    pub fn new<S>(bucket: S, client: Client) -> GcsStorage
+    where
+        S: AsRef<str>,
+    {
+        GcsStorage {
+            bucket: bucket.as_ref().to_owned(),
+            retry: RetryPolicy::default(),
+            digest_algorithm: DigestAlgorithm::default(),
+            client,
+        }
+    }
+
+    fn get_upload_file_path(&self, name: &str, uuid: &str) -> String {
+        format!("uploads/{}/{}", name, uuid)
+    }
+
+    fn get_layer_file_path(&self, name: &str, digest: &str) -> String {
+        format!("layers/{}/{}", name, digest)
+    }
+
+    fn get_media_type_file_path(&self, name: &str, digest: &str) -> String {
+        format!("media-types/{}/{}", name, digest)
+    }
+
+    fn get_referrer_file_path(&self, name: &str, subject: &str, digest: &str) -> String {
+        format!("referrers/{}/{}/{}", name, subject, digest)
+    }
+
+    fn get_manifest_file_path(&self, name: &str, reference: &str) -> String {
+        format!("manifests/{}/{}", name, reference)
+    }
+
+    async fn get_object(&self, key: &str) -> std::result::Result<Object, GcsError> {
+        self.retry
+            .retry(is_transient_error, || async {
+                self.client
+                    .get_object(&GetObjectRequest {
+                        bucket: self.bucket.clone(),
+                        object: key.to_string(),
+                        ..Default::default()
+                    })
+                    .await
+            })
+            .await
+    }
+
+    async fn download(
+        &self,
+        key: &str,
+    ) -> std::result::Result<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>, GcsError> {
+        let stream = self
+            .retry
+            .retry(is_transient_error, || async {
+                self.client
+                    .download_streamed_object(
+                        &GetObjectRequest {
+                            bucket: self.bucket.clone(),
+                            object: key.to_string(),
+                            ..Default::default()
+                        },
+                        &Range::default(),
+                    )
+                    .await
+            })
+            .await?;
+
+        Ok(Box::pin(stream.map(|bytes| match bytes {
+            Ok(bytes) => Ok(bytes),
+            Err(e) => Err(Error::from(format!("Failed to read data: {}", e))),
+        })))
+    }
+
+    async fn download_bytes(&self, key: &str) -> Result<Vec<u8>> {
+        let mut stream = self.download(key).await?;
+
+        let mut content = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+
+        Ok(content)
+    }
+
+    async fn upload(&self, key: &str, content: Vec<u8>) -> Result<Object> {
+        self.upload_with_metadata(key, content, None).await
+    }
+
+    /// Uploads `content` to `key`, along with custom `metadata` if given.
+    async fn upload_with_metadata(
+        &self,
+        key: &str,
+        content: Vec<u8>,
+        metadata: Option<HashMap<String, String>>,
+    ) -> Result<Object> {
+        let upload_type = match metadata {
+            Some(metadata) => UploadType::Multipart(Box::new(Object {
+                name: key.to_string(),
+                metadata: Some(metadata),
+                ..Default::default()
+            })),
+            None => UploadType::Simple(Media::new(key.to_string())),
+        };
+
+        Ok(self
+            .retry
+            .retry(is_transient_error, || async {
+                self.client
+                    .upload_object(
+                        &UploadObjectRequest {
+                            bucket: self.bucket.clone(),
+                            ..Default::default()
+                        },
+                        content.clone(),
+                        &upload_type,
+                    )
+                    .await
+            })
+            .await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.retry
+            .retry(is_transient_error, || async {
+                self.client
+                    .delete_object(&DeleteObjectRequest {
+                        bucket: self.bucket.clone(),
+                        object: key.to_string(),
+                        ..Default::default()
+                    })
+                    .await
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Copies `source` to `destination` server-side, rewrites of large objects
+    /// taking several calls.
+    async fn rewrite(&self, source: &str, destination: &str) -> Result<()> {
+        let mut rewrite_token = None;
+
+        loop {
+            let response = self
+                .retry
+                .retry(is_transient_error, || async {
+                    self.client
+                        .rewrite_object(&RewriteObjectRequest {
+                            source_bucket: self.bucket.clone(),
+                            source_object: source.to_string(),
+                            destination_bucket: self.bucket.clone(),
+                            destination_object: destination.to_string(),
+                            rewrite_token: rewrite_token.clone(),
+                            ..Default::default()
+                        })
+                        .await
+                })
+                .await?;
+
+            if response.done {
+                return Ok(());
+            }
+
+            rewrite_token = response.rewrite_token;
+        }
+    }
+
+    /// Lists the objects under `prefix`, along with the sub-prefixes they are
+    /// grouped under when a `delimiter` is given.
+    async fn list_objects(
+        &self,
+        prefix: String,
+        delimiter: Option<String>,
+    ) -> Result<(Vec<Object>, Vec<String>)> {
+        let mut objects = Vec::new();
+        let mut prefixes = Vec::new();
+        let mut page_token = None;
+
+        loop {
+            let response = self
+                .retry
+                .retry(is_transient_error, || async {
+                    self.client
+                        .list_objects(&ListObjectsRequest {
+                            bucket: self.bucket.clone(),
+                            prefix: Some(prefix.clone()),
+                            delimiter: delimiter.clone(),
+                            page_token: page_token.clone(),
+                            ..Default::default()
+                        })
+                        .await
+                })
+                .await?;
+
+            objects.extend(response.items.unwrap_or_default());
+            prefixes.extend(response.prefixes.unwrap_or_default());
+
+            match response.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => break,
+            }
+        }
+
+        Ok((objects, prefixes))
+    }
+}
+
+/// Rate limiting, server-side failures and connection errors are worth
+/// retrying; other error responses such as a 404 are not.
+fn is_transient_error(error: &GcsError) -> bool {
+    match error {
+        GcsError::Response(response) => response.is_retriable(),
+        GcsError::HttpClient(e) => e.is_timeout() || e.is_connect(),
+        _ => false,
+    }
+}
+
+fn is_missing_object(error: &GcsError) -> bool {
+    matches!(error, GcsError::Response(response) if response.code == 404)
+}
+
+#[async_trait]
+impl Storage for GcsStorage {
+    async fn get_image_layer_info(
+        &self,
+        name: String,
+        digest: String,
+    ) -> Result<Option<ImageLayerInfo>> {
+        let key = self.get_layer_file_path(&name, &digest);
+
+        match self.get_object(&key).await {
+            Ok(object) => Ok(Some(ImageLayerInfo {
+                size: object.size as u64,
+            })),
+            Err(e) if is_missing_object(&e) => Ok(None),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    async fn blob_exists(&self, name: String, digest: String) -> Result<bool> {
+        match self
+            .get_object(&self.get_layer_file_path(&name, &digest))
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e) if is_missing_object(&e) => Ok(false),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    async fn get_layer(
+        &self,
+        name: String,
+        digest: String,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>> {
+        let key = self.get_layer_file_path(&name, &digest);
+
+        match self.download(&key).await {
+            Ok(stream) => Ok(stream),
+            Err(e) if is_missing_object(&e) => Err(Box::new(StorageError::NotFound(format!(
+                "layer '{}'",
+                digest
+            )))),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    async fn create_upload_container(&self, name: String) -> Result<UploadContainer> {
+        let uuid = Uuid::new_v4().to_string();
+
+        self.upload(&self.get_upload_file_path(&name, &uuid), Vec::new())
+            .await?;
+
+        let state = UploadState::new(name, uuid.clone());
+
+        Ok(UploadContainer {
+            uuid,
+            state: state.encode()?,
+        })
+    }
+
+    async fn check_upload_container_validity(&self, name: String, uuid: String) -> Result<bool> {
+        let key = self.get_upload_file_path(&name, &uuid);
+
+        match self.get_object(&key).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_missing_object(&e) => Ok(false),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    async fn write_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        stream: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
+        range: (u64, u64),
+    ) -> Result<UploadStatus> {
+        // Composed chunks aren't rewritten, so chunks can only be appended
+        let uploaded = self
+            .get_upload_status(name.clone(), uuid.clone())
+            .await?
+            .size;
+        if range.0 != uploaded {
+            return Err(Box::new(StorageError::RangeInvalid {
+                start: range.0,
+                size: uploaded,
+            }));
+        }
+
+        let key = self.get_upload_file_path(&name, &uuid);
+        let chunk_key = format!("{}.{}", key, Uuid::new_v4());
+
+        // The client requires a `Sync` body, which the request stream isn't
+        // although it's only ever polled from one place
+        let mut stream = SyncWrapper::new(stream);
+        let body = futures::stream::poll_fn(move |cx| stream.get_mut().poll_next_unpin(cx));
+
+        // The body stream can only be consumed once, so this upload isn't retried
+        self.client
+            .upload_streamed_object(
+                &UploadObjectRequest {
+                    bucket: self.bucket.clone(),
+                    ..Default::default()
+                },
+                body,
+                &UploadType::Simple(Media::new(chunk_key.clone())),
+            )
+            .await?;
+
+        // Objects are immutable, chunks are appended by composing the upload
+        // with the chunk into a new version of the upload
+        let composed = self
+            .retry
+            .retry(is_transient_error, || async {
+                self.client
+                    .compose_object(&ComposeObjectRequest {
+                        bucket: self.bucket.clone(),
+                        destination_object: key.clone(),
+                        composing_targets: ComposingTargets {
+                            destination: None,
+                            source_objects: vec![
+                                SourceObjects {
+                                    name: key.clone(),
+                                    ..Default::default()
+                                },
+                                SourceObjects {
+                                    name: chunk_key.clone(),
+                                    ..Default::default()
+                                },
+                            ],
+                        },
+                        ..Default::default()
+                    })
+                    .await
+            })
+            .await?;
+
+        self.delete(&chunk_key).await?;
+
+        Ok(UploadStatus {
+            size: composed.size as u64,
+        })
+    }
+
+    async fn get_upload_status(&self, name: String, uuid: String) -> Result<UploadStatus> {
+        let key = self.get_upload_file_path(&name, &uuid);
+        let object = self.get_object(&key).await?;
+
+        Ok(UploadStatus {
+            size: object.size as u64,
+        })
+    }
+
+    async fn list_uploads(&self, name: Option<String>) -> Result<Vec<UploadInfo>> {
+        let prefix = match &name {
+            Some(name) => format!("uploads/{}/", name),
+            None => "uploads/".to_string(),
+        };
+        let (objects, _) = self.list_objects(prefix, None).await?;
+
+        Ok(objects
+            .into_iter()
+            .filter_map(|object| {
+                let (name, uuid) = object.name.strip_prefix("uploads/")?.rsplit_once('/')?;
+
+                // Chunks being appended are stored next to their upload
+                if uuid.contains('.') {
+                    return None;
+                }
+
+                Some(UploadInfo {
+                    name: name.to_string(),
+                    uuid: uuid.to_string(),
+                    size: object.size as u64,
+                    created_at: object
+                        .time_created
+                        .map(|time| time.unix_timestamp().max(0) as u64),
+                })
+            })
+            .collect())
+    }
+
+    async fn delete_upload_container(&self, name: String, uuid: String) -> Result<()> {
+        let key = self.get_upload_file_path(&name, &uuid);
+
+        // Chunks being appended are stored next to the upload
+        let (chunks, _) = self.list_objects(format!("{}.", key), None).await?;
+        for chunk in chunks {
+            self.delete(&chunk.name).await?;
+        }
+        self.delete(&key).await?;
+
+        Ok(())
+    }
+
+    async fn close_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: Option<String>,
+    ) -> Result<UploadDetails> {
+        let key = self.get_upload_file_path(&name, &uuid);
+
+        let mut hasher = digest
+            .as_deref()
+            .and_then(DigestAlgorithm::of)
+            .unwrap_or(self.digest_algorithm)
+            .hasher();
+
+        let mut stream = self.download(&key).await?;
+        while let Some(chunk) = stream.next().await {
+            hasher.update(&chunk?);
+        }
+
+        let actual = hasher.finalize();
+
+        if let Err(e) = check_upload_digest(digest.as_deref(), &actual) {
+            self.delete_upload_container(name, uuid).await?;
+            return Err(e);
+        }
+
+        self.store_upload_container(name, uuid, actual).await
+    }
+
+    async fn store_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: String,
+    ) -> Result<UploadDetails> {
+        let key = self.get_upload_file_path(&name, &uuid);
+
+        self.rewrite(&key, &self.get_layer_file_path(&name, &digest))
+            .await?;
+        self.delete(&key).await?;
+
+        Ok(UploadDetails { digest })
+    }
+
+    async fn get_manifest_summary(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<ManifestSummary> {
+        let key = self.get_manifest_file_path(&name, &reference);
+
+        let object = match self.get_object(&key).await {
+            Ok(object) => object,
+            Err(e) if is_missing_object(&e) => {
+                return Err(Box::new(StorageError::NotFound(format!(
+                    "manifest '{}'",
+                    reference
+                ))))
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        // Manifests stored before their digest was recorded are hashed
+        let recorded = object
+            .metadata
+            .and_then(|mut metadata| metadata.remove(MANIFEST_DIGEST_METADATA));
+        let digest = match canonical_digest(&reference).or(recorded) {
+            Some(digest) => digest,
+            None => self
+                .digest_algorithm
+                .digest(self.download_bytes(&key).await?),
+        };
+
+        Ok(ManifestSummary {
+            digest,
+            size: object.size as u64,
+        })
+    }
+
+    async fn manifest_exists(&self, name: String, reference: String) -> Result<bool> {
+        match self
+            .get_object(&self.get_manifest_file_path(&name, &reference))
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e) if is_missing_object(&e) => Ok(false),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    async fn get_manifest(&self, name: String, reference: String) -> Result<ManifestDetails> {
+        let content = self
+            .download_bytes(&self.get_manifest_file_path(&name, &reference))
+            .await?;
+
+        let manifest: Manifest = serde_json::from_slice(&content)?;
+
+        let digest = DigestAlgorithm::of(&reference)
+            .unwrap_or(self.digest_algorithm)
+            .digest(&content);
+
+        Ok(ManifestDetails {
+            manifest,
+            digest,
+            content: Bytes::from(content),
+        })
+    }
+
+    async fn get_manifest_raw(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<(Bytes, String, String)> {
+        let content = self
+            .download_bytes(&self.get_manifest_file_path(&name, &reference))
+            .await?;
+
+        let media_type = manifest_content_type(&content)?;
+        let digest = DigestAlgorithm::of(&reference)
+            .unwrap_or(self.digest_algorithm)
+            .digest(&content);
+
+        Ok((Bytes::from(content), media_type, digest))
+    }
+
+    async fn update_manifest(
+        &self,
+        name: String,
+        reference: String,
+        content: &[u8],
+    ) -> Result<UpdateManifestDetails> {
+        let digest = self.digest_algorithm.digest(content);
+
+        self.upload_with_metadata(
+            &self.get_manifest_file_path(&name, &reference),
+            content.to_vec(),
+            Some(HashMap::from([(
+                MANIFEST_DIGEST_METADATA.to_string(),
+                digest.clone(),
+            )])),
+        )
+        .await?;
+
+        Ok(UpdateManifestDetails { digest })
+    }
+
+    async fn delete_manifest(&self, name: String, reference: String) -> Result<()> {
+        self.delete(&self.get_manifest_file_path(&name, &reference))
+            .await
+    }
+
+    async fn delete_layer(&self, name: String, digest: String) -> Result<()> {
+        self.delete(&self.get_layer_file_path(&name, &digest))
+            .await?;
+
+        match self
+            .delete(&self.get_media_type_file_path(&name, &digest))
+            .await
+        {
+            Err(e) if !e.downcast_ref::<GcsError>().is_some_and(is_missing_object) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn set_blob_media_type(
+        &self,
+        name: String,
+        digest: String,
+        media_type: String,
+    ) -> Result<()> {
+        self.upload(
+            &self.get_media_type_file_path(&name, &digest),
+            media_type.into_bytes(),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_blob_media_type(&self, name: String, digest: String) -> Result<Option<String>> {
+        let key = self.get_media_type_file_path(&name, &digest);
+
+        match self.get_object(&key).await {
+            Ok(_) => Ok(Some(String::from_utf8(self.download_bytes(&key).await?)?)),
+            Err(e) if is_missing_object(&e) => Ok(None),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    async fn delete_repository(&self, name: String) -> Result<()> {
+        // Any other name could match the keys of other repositories
+        if !is_repository_name(&name) {
+            return Err(Box::new(StorageError::NotFound(format!(
+                "repository '{}'",
+                name
+            ))));
+        }
+
+        let mut keys = Vec::new();
+        for root in ["layers", "manifests", "uploads", "referrers", "media-types"] {
+            let prefix = format!("{}/{}/", root, name);
+            let (objects, _) = self.list_objects(prefix.clone(), None).await?;
+
+            // Those of the repositories nested under this one are kept
+            keys.extend(
+                objects
+                    .into_iter()
+                    .map(|object| object.name)
+                    .filter(|key| key.strip_prefix(&prefix).is_some_and(is_repository_entry)),
+            );
+        }
+
+        if keys.is_empty() {
+            return Err(Box::new(StorageError::NotFound(format!(
+                "repository '{}'",
+                name
+            ))));
+        }
+
+        for key in keys {
+            self.delete(&key).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        let mut repositories = Vec::new();
+
+        for root in ["layers/", "manifests/"] {
+            let (_, prefixes) = self
+                .list_objects(root.to_string(), Some("/".to_string()))
+                .await?;
+
+            repositories.extend(prefixes.iter().filter_map(|prefix| {
+                prefix
+                    .strip_prefix(root)
+                    .map(|name| name.trim_end_matches('/').to_string())
+            }));
+        }
+
+        repositories.sort();
+        repositories.dedup();
+        Ok(repositories)
+    }
+
+    async fn list_layers(&self, name: String) -> Result<Vec<String>> {
+        let prefix = format!("layers/{}/", name);
+        let (objects, _) = self.list_objects(prefix.clone(), None).await?;
+
+        Ok(objects
+            .into_iter()
+            .filter_map(|object| object.name.strip_prefix(&prefix).map(str::to_string))
+            .collect())
+    }
+
+    async fn list_tags(&self, name: String) -> Result<Vec<String>> {
+        let prefix = format!("manifests/{}/", name);
+        let (objects, _) = self.list_objects(prefix.clone(), None).await?;
+
+        let mut tags = objects
+            .into_iter()
+            .filter_map(|object| object.name.strip_prefix(&prefix).map(str::to_string))
+            .filter(|reference| !is_digest(reference))
+            .collect::<Vec<_>>();
+
+        tags.sort();
+        Ok(tags)
+    }
+
+    async fn list_manifests(&self, name: String) -> Result<Vec<String>> {
+        let prefix = format!("manifests/{}/", name);
+        let (objects, _) = self.list_objects(prefix.clone(), None).await?;
+
+        Ok(objects
+            .into_iter()
+            .filter_map(|object| object.name.strip_prefix(&prefix).map(str::to_string))
+            .collect())
+    }
+
+    async fn add_referrer(&self, name: String, subject: String, referrer: Referrer) -> Result<()> {
+        let key = self.get_referrer_file_path(&name, &subject, &referrer.digest);
+        self.upload(&key, serde_json::to_vec(&referrer)?).await?;
+
+        Ok(())
+    }
+
+    async fn remove_referrer(&self, name: String, subject: String, digest: String) -> Result<()> {
+        self.delete(&self.get_referrer_file_path(&name, &subject, &digest))
+            .await
+    }
+
+    async fn list_referrers(&self, name: String, subject: String) -> Result<Vec<Referrer>> {
+        let (objects, _) = self
+            .list_objects(format!("referrers/{}/{}/", name, subject), None)
+            .await?;
+
+        let mut referrers = Vec::new();
+        for object in objects {
+            referrers.push(serde_json::from_slice(
+                &self.download_bytes(&object.name).await?,
+            )?);
+        }
+
+        Ok(referrers)
+    }
+
+    async fn clear_referrers(&self, name: String) -> Result<()> {
+        let (objects, _) = self
+            .list_objects(format!("referrers/{}/", name), None)
+            .await?;
+
+        for object in objects {
+            self.delete(&object.name).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn repository_size(&self, name: String) -> Result<u64> {
+        let mut size = 0;
+
+        for root in ["layers", "manifests"] {
+            let (objects, _) = self
+                .list_objects(format!("{}/{}/", root, name), None)
+                .await?;
+
+            size += objects.iter().map(|object| object.size as u64).sum::<u64>();
+        }
+
+        Ok(size)
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        let result = self
+            .retry
+            .retry(is_transient_error, || async {
+                self.client
+                    .list_objects(&ListObjectsRequest {
+                        bucket: self.bucket.clone(),
+                        max_results: Some(1),
+                        ..Default::default()
+                    })
+                    .await
+            })
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::from(format!(
+                "Bucket '{}' isn't accessible: {}",
+                self.bucket, e
+            ))),
+        }
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.digest_algorithm
+    }
+}
+
+/// Storage on the GCS emulator `STORAGE_EMULATOR_HOST` points to, in a bucket
+/// named `GCS_TEST_BUCKET` (`rustgistry` by default) the tests expect to exist.
+#[cfg(test)]
+fn emulator_storage() -> GcsStorage {
+    use google_cloud_storage::client::ClientConfig;
+
+    let config = ClientConfig {
+        storage_endpoint: std::env::var("STORAGE_EMULATOR_HOST")
+            .expect("STORAGE_EMULATOR_HOST isn't set"),
+        ..Default::default()
+    }
+    .anonymous();
+
+    GcsStorage::new(
+        std::env::var("GCS_TEST_BUCKET").unwrap_or_else(|_| "rustgistry".to_string()),
+        Client::new(config),
+    )
+}
+
+#[tokio::test]
+#[ignore = "requires a GCS emulator"]
+async fn test_upload_layer() -> Result<()> {
+    use std::sync::Arc;
+
+    super::tests::test_upload_layer(Arc::new(emulator_storage())).await
+}
+
+#[tokio::test]
+#[ignore = "requires a GCS emulator"]
+async fn test_get_missing_layer() -> Result<()> {
+    use std::sync::Arc;
+
+    super::tests::test_get_missing_layer(Arc::new(emulator_storage())).await
+}
+
+#[tokio::test]
+#[ignore = "requires a GCS emulator"]
+async fn test_update_manifest() -> Result<()> {
+    use std::sync::Arc;
+
+    super::tests::test_update_manifest(Arc::new(emulator_storage())).await
+}
@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+
+use super::is_digest;
+
+/// Kind of files a repository holds in a `LocalStorage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Area {
+    Layers,
+    Manifests,
+    Uploads,
+    Referrers,
+    MediaTypes,
+}
+
+impl Area {
+    pub const ALL: [Area; 5] = [
+        Area::Layers,
+        Area::Manifests,
+        Area::Uploads,
+        Area::Referrers,
+        Area::MediaTypes,
+    ];
+}
+
+/// Where a `LocalStorage` places the files of each repository, relative to its
+/// root. Shared blobs always live under `blobs/<digest>`.
+pub trait LayoutStrategy: Send + Sync {
+    /// Directory holding the `area` files of repository `name`.
+    fn directory(&self, area: Area, name: &str) -> PathBuf;
+
+    /// Directory listing the repositories that may hold `area` files, a
+    /// repository actually holding some when its `directory` exists.
+    fn repositories_directory(&self, area: Area) -> PathBuf;
+}
+
+/// `layers/<name>/<digest>`, `manifests/<name>/<reference>`, and so on.
+pub struct DefaultLayout;
+
+impl LayoutStrategy for DefaultLayout {
+    fn directory(&self, area: Area, name: &str) -> PathBuf {
+        self.repositories_directory(area).join(name)
+    }
+
+    fn repositories_directory(&self, area: Area) -> PathBuf {
+        PathBuf::from(match area {
+            Area::Layers => "layers",
+            Area::Manifests => "manifests",
+            Area::Uploads => "uploads",
+            Area::Referrers => "referrers",
+            Area::MediaTypes => "media-types",
+        })
+    }
+}
+
+/// `repositories/<name>/_layers/<digest>`, `repositories/<name>/_manifests/<reference>`,
+/// and so on, the repository-rooted tree of the Docker distribution registry.
+///
+/// Meant to be used with `LocalStorage::deduplicate`, layers then being links
+/// to the content-addressable blob store.
+pub struct DistributionLayout;
+
+impl LayoutStrategy for DistributionLayout {
+    fn directory(&self, area: Area, name: &str) -> PathBuf {
+        self.repositories_directory(area)
+            .join(name)
+            .join(match area {
+                Area::Layers => "_layers",
+                Area::Manifests => "_manifests",
+                Area::Uploads => "_uploads",
+                Area::Referrers => "_referrers",
+                Area::MediaTypes => "_media_types",
+            })
+    }
+
+    fn repositories_directory(&self, _area: Area) -> PathBuf {
+        PathBuf::from("repositories")
+    }
+}
+
+/// Whether `name` follows the distribution grammar for repository names:
+/// `/`-separated components of lowercase alphanumerics, joined by `.`, `_`,
+/// `__` or runs of `-`. Components such as `.` or `..` are thereby rejected,
+/// which would otherwise resolve outside of the repository's directories.
+pub fn is_repository_name(name: &str) -> bool {
+    name.len() <= 255 && name.split('/').all(is_name_component)
+}
+
+/// Whether `path`, relative to a repository's directory in one of its areas,
+/// belongs to that repository rather than to one nested under it. Only
+/// referrers are grouped in subdirectories, named after their subject digest.
+pub fn is_repository_entry(path: &str) -> bool {
+    match path.split_once('/') {
+        Some((directory, _)) => is_digest(directory),
+        None => true,
+    }
+}
+
+fn is_name_component(component: &str) -> bool {
+    let mut separator = String::new();
+    let mut alphanumerics = false;
+
+    for c in component.chars() {
+        match c {
+            'a'..='z' | '0'..='9' => {
+                let valid_separator = separator.is_empty()
+                    || [".", "_", "__"].contains(&separator.as_str())
+                    || separator.chars().all(|c| c == '-');
+                if !valid_separator {
+                    return false;
+                }
+
+                separator.clear();
+                alphanumerics = true;
+            }
+            '.' | '_' | '-' if alphanumerics => separator.push(c),
+            _ => return false,
+        }
+    }
+
+    alphanumerics && separator.is_empty()
+}
+
+#[test]
+fn test_is_repository_name() {
+    for name in ["test", "library/ubuntu", "a.b_c__d--e", "team-a/app1/v2"] {
+        assert!(is_repository_name(name), "{}", name);
+    }
+
+    for name in [
+        "", "..", ".", "../x", "x/..", "x//y", "Test", "a..b", "a___b", "-a", "a-", "a b", "te\nst",
+    ] {
+        assert!(!is_repository_name(name), "{}", name);
+    }
+}
+
+#[test]
+fn test_is_repository_entry() {
+    let digest = format!("sha256:{}", "a".repeat(64));
+
+    for path in ["latest", digest.as_str(), &format!("{}/{}", digest, digest)] {
+        assert!(is_repository_entry(path), "{}", path);
+    }
+
+    for path in [
+        "b/latest",
+        &format!("b/{}", digest),
+        &format!("b/{}/{}", digest, digest),
+    ] {
+        assert!(!is_repository_entry(path), "{}", path);
+    }
+}
@@ -1,30 +1,56 @@
-use std::{ffi::OsStr, fs, path::PathBuf, pin::Pin, time::SystemTime};
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::SystemTime,
+};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::{Stream, StreamExt};
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 use tokio::{
     fs::{File, OpenOptions},
-    io::AsyncWriteExt,
+    io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufWriter},
 };
 use tokio_util::codec::{BytesCodec, FramedRead};
 use uuid::Uuid;
 
-use crate::utils;
-
 use super::{
-    base::{ImageLayerInfo, Result, Storage, UploadContainer},
-    is_sha256_digest,
-    types::manifest::Manifest,
-    Error, ManifestDetails, ManifestSummary, UpdateManifestDetails, UploadDetails, UploadStatus,
+    base::{check_upload_digest, ImageLayerInfo, Result, Storage, UploadContainer},
+    canonical_digest, is_digest,
+    layout::{is_repository_name, Area, DefaultLayout, LayoutStrategy},
+    types::{
+        manifest::{manifest_content_type, Manifest},
+        referrer::Referrer,
+    },
+    DigestAlgorithm, Error, ManifestDetails, ManifestSummary, StorageError, UpdateManifestDetails,
+    UploadDetails, UploadInfo, UploadState, UploadStatus,
 };
 
 pub struct LocalStorage {
     pub path: PathBuf,
+    /// Store each layer once under `blobs/<digest>`, repositories holding hard
+    /// links to it. Layers stored before enabling it are only shared once
+    /// `deduplicate_layers` has run.
+    pub deduplicate: bool,
+    /// Where the files of each repository are placed, see `migrate` to switch
+    /// an existing store to another layout.
+    pub layout: Box<dyn LayoutStrategy>,
+    /// Bytes of upload chunks gathered before writing them to disk, 0 writing
+    /// every chunk as it arrives.
+    pub write_buffer_size: usize,
+    /// Bytes read from disk at once when streaming a layer.
+    pub read_buffer_size: usize,
+    /// Algorithm the digests of uploaded layers and pushed manifests are
+    /// computed with.
+    pub digest_algorithm: DigestAlgorithm,
 }
 
+/// Default `LocalStorage` read and write buffer size.
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
 impl LocalStorage {
     pub fn new<S>(path: S) -> LocalStorage
     where
@@ -32,43 +58,293 @@ impl LocalStorage {
     {
         LocalStorage {
             path: PathBuf::from(path.as_ref()),
+            deduplicate: false,
+            layout: Box::new(DefaultLayout),
+            write_buffer_size: DEFAULT_BUFFER_SIZE,
+            read_buffer_size: DEFAULT_BUFFER_SIZE,
+            digest_algorithm: DigestAlgorithm::default(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct UploadState {
-    name: String,
-    uuid: String,
-    created_at: u64,
-}
-
 impl LocalStorage {
-    fn get_upload_file_path(&self, name: &String, uuid: &String) -> PathBuf {
-        let mut path = self.path.clone();
-        path.push("uploads");
-        path.push(name);
-        path.push(uuid);
+    /// Creates the store and the directories of each area if missing, then
+    /// checks files can be written there, so that an unusable path is
+    /// reported when starting rather than on the first push.
+    pub fn prepare(&self) -> Result<()> {
+        let directories = Area::ALL
+            .iter()
+            .map(|area| self.path.join(self.layout.repositories_directory(*area)));
+
+        for directory in std::iter::once(self.path.clone()).chain(directories) {
+            if let Err(e) = fs::create_dir_all(&directory) {
+                return Err(Error::from(format!(
+                    "Failed to create '{}': {}",
+                    directory.display(),
+                    e
+                )));
+            }
+        }
 
-        path
+        self.check_writable()
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        let probe = self.path.join(format!(".write-test-{}", Uuid::new_v4()));
+        if let Err(e) = fs::write(&probe, b"").and_then(|_| fs::remove_file(&probe)) {
+            return Err(Error::from(format!(
+                "Storage path '{}' isn't writable: {}",
+                self.path.display(),
+                e
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn get_directory_path(&self, area: Area, name: &str) -> PathBuf {
+        self.path.join(self.layout.directory(area, name))
+    }
+
+    fn get_upload_file_path(&self, name: &str, uuid: &str) -> PathBuf {
+        self.get_directory_path(Area::Uploads, name).join(uuid)
     }
 
-    fn get_layer_file_path(&self, name: &String, digest: &String) -> PathBuf {
+    fn get_layer_file_path(&self, name: &str, digest: &str) -> PathBuf {
+        self.get_directory_path(Area::Layers, name).join(digest)
+    }
+
+    fn get_blob_file_path(&self, digest: &str) -> PathBuf {
         let mut path = self.path.clone();
-        path.push("layers");
-        path.push(name);
+        path.push("blobs");
         path.push(digest);
 
         path
     }
 
-    fn get_manifest_file_path(&self, name: &String, reference: &String) -> PathBuf {
-        let mut path = self.path.clone();
-        path.push("manifests");
-        path.push(name);
-        path.push(reference);
+    fn get_manifest_file_path(&self, name: &str, reference: &str) -> PathBuf {
+        self.get_directory_path(Area::Manifests, name)
+            .join(reference)
+    }
 
-        path
+    fn get_media_type_file_path(&self, name: &str, digest: &str) -> PathBuf {
+        self.get_directory_path(Area::MediaTypes, name).join(digest)
+    }
+
+    fn get_referrers_directory_path(&self, name: &str) -> PathBuf {
+        self.get_directory_path(Area::Referrers, name)
+    }
+
+    /// Repositories holding `area` files.
+    fn list_area_repositories(&self, area: Area) -> Result<Vec<String>> {
+        let path = self.path.join(self.layout.repositories_directory(area));
+
+        Ok(self
+            .list_directory(&path, true)?
+            .into_iter()
+            .filter(|name| self.get_directory_path(area, name).is_dir())
+            .collect())
+    }
+
+    /// Names of the directories (or files) directly under `path`, empty when
+    /// `path` doesn't exist.
+    fn list_directory(&self, path: &Path, directories: bool) -> Result<Vec<String>> {
+        if !path.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_dir() == directories && !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    /// Total size of the regular files directly under `path`, symlinks such as
+    /// manifest digest links excluded.
+    fn directory_size(&self, path: &Path) -> Result<u64> {
+        if !path.is_dir() {
+            return Ok(0);
+        }
+
+        let mut size = 0;
+        for entry in fs::read_dir(path)? {
+            let metadata = fs::symlink_metadata(entry?.path())?;
+            if metadata.is_file() {
+                size += metadata.len();
+            }
+        }
+
+        Ok(size)
+    }
+
+    /// Moves `source` to the shared blob store unless the blob is already there,
+    /// then hard links it to `layer_path`.
+    fn link_blob(&self, source: &Path, digest: &str, layer_path: &Path) -> Result<()> {
+        let blob_path = self.get_blob_file_path(digest);
+        fs::create_dir_all(blob_path.parent().unwrap())?;
+
+        if blob_path.is_file() {
+            if source != layer_path {
+                fs::remove_file(source)?;
+            }
+        } else {
+            commit_file(source, &blob_path)?;
+        }
+
+        if layer_path.exists() {
+            fs::remove_file(layer_path)?;
+        }
+
+        fs::create_dir_all(layer_path.parent().unwrap())?;
+        fs::hard_link(&blob_path, layer_path)?;
+
+        Ok(())
+    }
+
+    /// Replaces the layers of every repository by links to the shared blob
+    /// store, migrating a store written without `deduplicate`.
+    pub fn deduplicate_layers(&self) -> Result<()> {
+        for name in self.list_area_repositories(Area::Layers)? {
+            let layers_path = self.get_directory_path(Area::Layers, &name);
+            for digest in self.list_directory(&layers_path, false)? {
+                let layer_path = self.get_layer_file_path(&name, &digest);
+                self.link_blob(&layer_path, &digest, &layer_path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the shared blobs no repository links to anymore, returning
+    /// their digests.
+    #[cfg(unix)]
+    pub fn prune_blobs(&self) -> Result<Vec<String>> {
+        let mut pruned = Vec::new();
+        for digest in self.list_directory(&self.path.join("blobs"), false)? {
+            if self.prune_blob(&digest)? {
+                pruned.push(digest);
+            }
+        }
+
+        Ok(pruned)
+    }
+
+    /// Deletes shared blob `digest` if no repository links to it anymore.
+    #[cfg(unix)]
+    fn prune_blob(&self, digest: &str) -> Result<bool> {
+        use std::os::unix::fs::MetadataExt;
+
+        let blob_path = self.get_blob_file_path(digest);
+        if !blob_path.is_file() || blob_path.metadata()?.nlink() > 1 {
+            return Ok(false);
+        }
+
+        fs::remove_file(blob_path)?;
+        Ok(true)
+    }
+
+    /// Moves the files of every repository to their place in `layout`, then
+    /// switches to it. The store must not be served while migrating.
+    pub fn migrate(&mut self, layout: Box<dyn LayoutStrategy>) -> Result<()> {
+        for area in Area::ALL {
+            for name in self.list_area_repositories(area)? {
+                let source = self.get_directory_path(area, &name);
+                let destination = self.path.join(layout.directory(area, &name));
+                if source == destination {
+                    continue;
+                }
+
+                if destination.exists() {
+                    return Err(Error::from(format!(
+                        "Cannot migrate '{}' to '{}', which already exists",
+                        source.display(),
+                        destination.display()
+                    )));
+                }
+
+                fs::create_dir_all(destination.parent().unwrap())?;
+                fs::rename(&source, &destination)?;
+
+                if area == Area::Manifests {
+                    self.relink_manifests(&destination)?;
+                }
+
+                self.remove_empty_parents(&source);
+            }
+        }
+
+        self.layout = layout;
+
+        Ok(())
+    }
+
+    /// Removes the directories `path` leaves empty, up to the storage root.
+    fn remove_empty_parents(&self, path: &Path) {
+        let mut parent = path.parent();
+        while let Some(directory) = parent {
+            if directory == self.path || fs::remove_dir(directory).is_err() {
+                break;
+            }
+            parent = directory.parent();
+        }
+    }
+
+    /// Removes the entries of the repository whose area directory is
+    /// `directory`, leaving those of the repositories nested under it.
+    /// Returns whether there were any.
+    fn remove_repository_entries(&self, directory: &Path) -> Result<bool> {
+        let mut removed = false;
+
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            // Referrers are grouped in directories named after their subject
+            if !entry.file_type()?.is_dir() {
+                fs::remove_file(&path)?;
+            } else if is_digest(&entry.file_name().to_string_lossy()) {
+                fs::remove_dir_all(&path)?;
+            } else {
+                continue;
+            }
+
+            removed = true;
+        }
+
+        if fs::remove_dir(directory).is_ok() {
+            self.remove_empty_parents(directory);
+        }
+
+        Ok(removed)
+    }
+
+    /// Points the digest links of the manifests moved to `path` to their new
+    /// location.
+    fn relink_manifests(&self, path: &Path) -> Result<()> {
+        for entry in fs::read_dir(path)? {
+            let link_path = entry?.path();
+            if !link_path.is_symlink() {
+                continue;
+            }
+
+            let target = fs::read_link(&link_path)?;
+            let target = match target.file_name() {
+                Some(file_name) => path.join(file_name),
+                None => continue,
+            };
+
+            fs::remove_file(&link_path)?;
+            self.create_symlink(&target, &link_path)?;
+        }
+
+        Ok(())
     }
 
     fn create_symlink(&self, target: &PathBuf, path: &PathBuf) -> Result<()> {
@@ -92,6 +368,109 @@ impl LocalStorage {
     }
 }
 
+/// Unique temporary path next to `path`, to be renamed over it. Dotfiles are
+/// left out of listings, should one outlive a crash.
+/// Digest of the manifest at `path`, as named by the digest link in its
+/// directory pointing to it.
+fn digest_link_to(path: &Path) -> Result<Option<String>> {
+    let Some(parent) = path.parent() else {
+        return Ok(None);
+    };
+
+    for entry in fs::read_dir(parent)? {
+        let link_path = entry?.path();
+        let file_name = link_path.file_name().unwrap_or_default().to_string_lossy();
+
+        if is_digest(&file_name) && link_path.read_link().is_ok_and(|target| target == path) {
+            return Ok(Some(file_name.into_owned()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn temp_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default();
+
+    path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        Uuid::new_v4()
+    ))
+}
+
+/// `EXDEV`, returned by `rename` across filesystems on Linux and macOS alike.
+const EXDEV: i32 = 18;
+
+/// Moves `source` to `destination`, which holds either its previous content
+/// or all of `source` even if the process dies meanwhile: `source` is first
+/// moved (or copied, across filesystems) to a temporary file next to
+/// `destination` and synced, then renamed over it.
+fn commit_file(source: &Path, destination: &Path) -> Result<()> {
+    commit_file_with(source, destination, |from, to| fs::rename(from, to))
+}
+
+fn commit_file_with<R>(source: &Path, destination: &Path, rename: R) -> Result<()>
+where
+    R: Fn(&Path, &Path) -> io::Result<()>,
+{
+    let directory = destination.parent().unwrap();
+    fs::create_dir_all(directory)?;
+
+    let temp_path = temp_path_for(destination);
+
+    let result = (|| -> Result<()> {
+        match rename(source, &temp_path) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(EXDEV) => {
+                fs::copy(source, &temp_path)?;
+            }
+            Err(e) => return Err(Box::new(e)),
+        }
+
+        fs::File::open(&temp_path)?.sync_all()?;
+        rename(&temp_path, destination)?;
+
+        #[cfg(unix)]
+        fs::File::open(directory)?.sync_all()?;
+
+        Ok(())
+    })();
+
+    if result.is_err() {
+        fs::remove_file(&temp_path).ok();
+        return result;
+    }
+
+    // Only once committed, so a failure leaves the upload to retry
+    if source.exists() {
+        fs::remove_file(source)?;
+    }
+
+    Ok(())
+}
+
+/// Writes `stream` to `writer` through a buffer of `capacity` bytes, saving a
+/// write for every small chunk.
+async fn write_stream<W>(
+    writer: &mut W,
+    mut stream: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
+    capacity: usize,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = BufWriter::with_capacity(capacity, writer);
+
+    while let Some(bytes) = stream.next().await {
+        writer.write_all(&bytes?).await?;
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
 #[async_trait]
 impl Storage for LocalStorage {
     async fn get_image_layer_info(
@@ -112,6 +491,23 @@ impl Storage for LocalStorage {
         }))
     }
 
+    async fn blob_exists(&self, name: String, digest: String) -> Result<bool> {
+        Ok(self.get_layer_file_path(&name, &digest).is_file())
+    }
+
+    async fn get_shared_blob_size(&self, digest: String) -> Result<Option<u64>> {
+        // Any other name could resolve outside of the blob store
+        if !self.deduplicate || !is_digest(&digest) {
+            return Ok(None);
+        }
+
+        match fs::metadata(self.get_blob_file_path(&digest)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
     async fn get_layer(
         &self,
         name: String,
@@ -120,13 +516,18 @@ impl Storage for LocalStorage {
         let path = self.get_layer_file_path(&name, &digest);
 
         if !path.is_file() {
-            return Err(Error::from("layer not found"));
+            return Err(Box::new(StorageError::NotFound(format!(
+                "layer '{}'",
+                digest
+            ))));
         }
 
         let stream = File::open(&path).await.map(|file| {
-            FramedRead::new(file, BytesCodec::new()).map(|bytes| match bytes {
-                Ok(bytes) => Ok(bytes.freeze()),
-                Err(e) => Err(Error::from(format!("Failed to read layer file: {}", e))),
+            FramedRead::with_capacity(file, BytesCodec::new(), self.read_buffer_size).map(|bytes| {
+                match bytes {
+                    Ok(bytes) => Ok(bytes.freeze()),
+                    Err(e) => Err(Error::from(format!("Failed to read layer file: {}", e))),
+                }
             })
         })?;
 
@@ -154,22 +555,12 @@ impl Storage for LocalStorage {
             )));
         }
 
-        let state = UploadState {
-            name,
-            uuid: uuid.clone(),
-            created_at: SystemTime::now().elapsed().unwrap_or_default().as_secs(),
-        };
+        let state = UploadState::new(name, uuid.clone());
 
-        match serde_json::to_string(&state) {
-            Ok(state_json) => Ok(UploadContainer {
-                uuid,
-                state: base64::encode(state_json),
-            }),
-            Err(e) => Err(Error::from(format!(
-                "Failed to serialize upload container state: {}",
-                e
-            ))),
-        }
+        Ok(UploadContainer {
+            uuid,
+            state: state.encode()?,
+        })
     }
 
     async fn check_upload_container_validity(&self, name: String, uuid: String) -> Result<bool> {
@@ -181,17 +572,27 @@ impl Storage for LocalStorage {
         &self,
         name: String,
         uuid: String,
-        mut stream: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
-        _range: (u64, u64),
+        stream: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
+        range: (u64, u64),
     ) -> Result<UploadStatus> {
         let path = self.get_upload_file_path(&name, &uuid);
-        let mut file = OpenOptions::new().append(true).open(path).await?;
-
-        while let Some(bytes) = stream.next().await {
-            file.write_all(&bytes?).await?;
+        let mut file = OpenOptions::new().write(true).open(path).await?;
+
+        // A chunk starting before the end is a retry, overwriting what the
+        // previous attempt wrote from there
+        let size = file.metadata().await?.len();
+        if range.0 > size {
+            return Err(Box::new(StorageError::RangeInvalid {
+                start: range.0,
+                size,
+            }));
+        }
+        if range.0 < size {
+            file.set_len(range.0).await?;
         }
+        file.seek(SeekFrom::Start(range.0)).await?;
 
-        file.flush().await?;
+        write_stream(&mut file, stream, self.write_buffer_size).await?;
 
         let metadata = file.metadata().await?;
         Ok(UploadStatus {
@@ -199,10 +600,74 @@ impl Storage for LocalStorage {
         })
     }
 
-    async fn close_upload_container(&self, name: String, uuid: String) -> Result<UploadDetails> {
+    async fn get_upload_status(&self, name: String, uuid: String) -> Result<UploadStatus> {
         let path = self.get_upload_file_path(&name, &uuid);
+        let metadata = fs::metadata(path)?;
 
-        let mut hasher = Sha256::new();
+        Ok(UploadStatus {
+            size: metadata.len(),
+        })
+    }
+
+    async fn list_uploads(&self, name: Option<String>) -> Result<Vec<UploadInfo>> {
+        let names = match name {
+            Some(name) => vec![name],
+            None => self.list_area_repositories(Area::Uploads)?,
+        };
+
+        let mut uploads = Vec::new();
+        for name in names {
+            let path = self.get_directory_path(Area::Uploads, &name);
+
+            for uuid in self.list_directory(&path, false)? {
+                let metadata = fs::metadata(path.join(&uuid))?;
+                let created_at = metadata
+                    .created()
+                    .or_else(|_| metadata.modified())
+                    .ok()
+                    .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                    .map(|duration| duration.as_secs());
+
+                uploads.push(UploadInfo {
+                    name: name.clone(),
+                    uuid,
+                    size: metadata.len(),
+                    created_at,
+                });
+            }
+        }
+
+        Ok(uploads)
+    }
+
+    async fn delete_upload_container(&self, name: String, uuid: String) -> Result<()> {
+        let path = self.get_upload_file_path(&name, &uuid);
+
+        if !path.is_file() {
+            return Err(Box::new(StorageError::NotFound(format!(
+                "upload '{}'",
+                uuid
+            ))));
+        }
+
+        fs::remove_file(path)?;
+
+        Ok(())
+    }
+
+    async fn close_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: Option<String>,
+    ) -> Result<UploadDetails> {
+        let path = self.get_upload_file_path(&name, &uuid);
+
+        let mut hasher = digest
+            .as_deref()
+            .and_then(DigestAlgorithm::of)
+            .unwrap_or(self.digest_algorithm)
+            .hasher();
 
         File::open(&path)
             .await
@@ -216,13 +681,30 @@ impl Storage for LocalStorage {
             })
             .await;
 
-        let hash = hex::encode(hasher.finalize());
-        let digest = format!("sha256:{}", hash);
+        let actual = hasher.finalize();
+
+        if let Err(e) = check_upload_digest(digest.as_deref(), &actual) {
+            self.delete_upload_container(name, uuid).await?;
+            return Err(e);
+        }
+
+        self.store_upload_container(name, uuid, actual).await
+    }
 
+    async fn store_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: String,
+    ) -> Result<UploadDetails> {
+        let path = self.get_upload_file_path(&name, &uuid);
         let layer_path = self.get_layer_file_path(&name, &digest);
-        fs::create_dir_all(layer_path.parent().unwrap())?;
 
-        fs::rename(path, layer_path)?;
+        if self.deduplicate {
+            self.link_blob(&path, &digest, &layer_path)?;
+        } else {
+            commit_file(&path, &layer_path)?;
+        }
 
         Ok(UploadDetails { digest })
     }
@@ -233,29 +715,36 @@ impl Storage for LocalStorage {
         reference: String,
     ) -> Result<ManifestSummary> {
         let mut path = self.get_manifest_file_path(&name, &reference);
-        if path.is_symlink() && is_sha256_digest(&reference) {
+        if path.is_symlink() && is_digest(&reference) {
             path = path.read_link()?;
         }
 
         if !path.is_file() {
-            return Err(Error::from("Manifest not found"));
+            return Err(Box::new(StorageError::NotFound(format!(
+                "manifest '{}'",
+                reference
+            ))));
         }
 
-        let manifest_content = fs::read_to_string(&path)?;
-
-        let mut hasher = Sha256::new();
-        hasher.update(&manifest_content);
-        let hash = hex::encode(hasher.finalize());
-        let digest = format!("sha256:{}", hash);
+        // Tags are resolved through the digest link pointing to them, only
+        // manifests stored before digest links existed being read
+        let digest = match canonical_digest(&reference).or(digest_link_to(&path)?) {
+            Some(digest) => digest,
+            None => self.digest_algorithm.digest(fs::read(&path)?),
+        };
 
         let size = path.metadata()?.len();
 
         Ok(ManifestSummary { digest, size })
     }
 
+    async fn manifest_exists(&self, name: String, reference: String) -> Result<bool> {
+        Ok(self.get_manifest_file_path(&name, &reference).is_file())
+    }
+
     async fn get_manifest(&self, name: String, reference: String) -> Result<ManifestDetails> {
         let mut path = self.get_manifest_file_path(&name, &reference);
-        if path.is_symlink() && is_sha256_digest(&reference) {
+        if path.is_symlink() && is_digest(&reference) {
             path = path.read_link()?;
         }
 
@@ -263,45 +752,98 @@ impl Storage for LocalStorage {
             return Err(Error::from("Manifest not found"));
         }
 
-        let manifest_content = fs::read_to_string(&path)?;
-        let manifest: Manifest = serde_json::from_str(&manifest_content)?;
+        let content = fs::read(&path)?;
+        let manifest: Manifest = serde_json::from_slice(&content)?;
 
-        let mut hasher = Sha256::new();
-        hasher.update(&manifest_content);
-        let hash = hex::encode(hasher.finalize());
-        let digest = format!("sha256:{}", hash);
+        let digest = DigestAlgorithm::of(&reference)
+            .unwrap_or(self.digest_algorithm)
+            .digest(&content);
 
-        Ok(ManifestDetails { manifest, digest })
+        Ok(ManifestDetails {
+            manifest,
+            digest,
+            content: Bytes::from(content),
+        })
+    }
+
+    async fn get_manifest_raw(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<(Bytes, String, String)> {
+        let mut path = self.get_manifest_file_path(&name, &reference);
+        if path.is_symlink() && is_digest(&reference) {
+            path = path.read_link()?;
+        }
+
+        if !path.is_file() {
+            return Err(Error::from("Manifest not found"));
+        }
+
+        let content = fs::read(&path)?;
+        let media_type = manifest_content_type(&content)?;
+        let digest = DigestAlgorithm::of(&reference)
+            .unwrap_or(self.digest_algorithm)
+            .digest(&content);
+
+        Ok((Bytes::from(content), media_type, digest))
     }
 
     async fn update_manifest(
         &self,
         name: String,
         reference: String,
-        manifest: Manifest,
+        content: &[u8],
     ) -> Result<UpdateManifestDetails> {
-        let json = utils::to_json_normalized(&manifest)?;
-
         let mut path = self.get_manifest_file_path(&name, &reference);
-        if path.is_symlink() && is_sha256_digest(&reference) {
+        if path.is_symlink() && is_digest(&reference) {
             path = path.read_link()?;
         }
 
         let parent = path.parent().unwrap();
+        let digest = self.digest_algorithm.digest(content);
+        let symlink_path = parent.join(&digest);
+        // Pushes by digest write the manifest where its digest link would be
+        let by_digest = symlink_path == path;
+
+        // Retried pushes leave the store untouched
+        let unchanged = (by_digest || symlink_path.read_link().is_ok_and(|target| target == path))
+            && fs::read(&path).is_ok_and(|stored| stored == content);
+        if unchanged {
+            return Ok(UpdateManifestDetails { digest });
+        }
+
+        // Both the manifest and its digest link are replaced by renames, so
+        // that concurrent pulls see either the previous or the new ones
         fs::create_dir_all(parent)?;
-        fs::write(&path, &json)?;
+        let temp_path = temp_path_for(&path);
+        if let Err(e) = fs::write(&temp_path, content).and_then(|_| fs::rename(&temp_path, &path)) {
+            fs::remove_file(&temp_path).ok();
+            return Err(Box::new(e));
+        }
 
-        let mut hasher = Sha256::new();
-        hasher.update(json.as_bytes());
-        let hash = hex::encode(hasher.finalize());
-        let digest = format!("sha256:{}", hash);
+        if by_digest {
+            return Ok(UpdateManifestDetails { digest });
+        }
 
-        let symlink_path = parent.join(&digest);
-        if symlink_path.exists() {
-            fs::remove_file(&symlink_path)?;
+        let temp_symlink_path = temp_path_for(&symlink_path);
+        self.create_symlink(&path, &temp_symlink_path)?;
+        if let Err(e) = fs::rename(&temp_symlink_path, &symlink_path) {
+            fs::remove_file(&temp_symlink_path).ok();
+            return Err(Box::new(e));
         }
 
-        self.create_symlink(&path, &symlink_path)?;
+        // Links to the manifest the tag pointed to before now point to content
+        // they aren't the digest of
+        for entry in fs::read_dir(parent)? {
+            let link_path = entry?.path();
+            if link_path != symlink_path
+                && is_digest(&link_path.file_name().unwrap_or_default().to_string_lossy())
+                && link_path.read_link().is_ok_and(|target| target == path)
+            {
+                fs::remove_file(link_path)?;
+            }
+        }
 
         Ok(UpdateManifestDetails { digest })
     }
@@ -317,6 +859,181 @@ impl Storage for LocalStorage {
 
         Ok(())
     }
+
+    async fn delete_layer(&self, name: String, digest: String) -> Result<()> {
+        let path = self.get_layer_file_path(&name, &digest);
+
+        if !path.is_file() {
+            return Err(Box::new(StorageError::NotFound(format!(
+                "layer '{}'",
+                digest
+            ))));
+        }
+
+        fs::remove_file(path)?;
+
+        let media_type_path = self.get_media_type_file_path(&name, &digest);
+        if media_type_path.is_file() {
+            fs::remove_file(media_type_path)?;
+        }
+
+        Ok(())
+    }
+
+    async fn set_blob_media_type(
+        &self,
+        name: String,
+        digest: String,
+        media_type: String,
+    ) -> Result<()> {
+        let path = self.get_media_type_file_path(&name, &digest);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, media_type)?;
+
+        Ok(())
+    }
+
+    async fn get_blob_media_type(&self, name: String, digest: String) -> Result<Option<String>> {
+        match fs::read_to_string(self.get_media_type_file_path(&name, &digest)) {
+            Ok(media_type) => Ok(Some(media_type)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    async fn delete_repository(&self, name: String) -> Result<()> {
+        // Any other name could resolve to a directory outside the repository's
+        if !is_repository_name(&name) {
+            return Err(Box::new(StorageError::NotFound(format!(
+                "repository '{}'",
+                name
+            ))));
+        }
+
+        let layers = self.list_layers(name.clone()).await?;
+
+        let mut removed = false;
+        for area in Area::ALL {
+            let directory = self.get_directory_path(area, &name);
+            if directory.is_dir() {
+                removed |= self.remove_repository_entries(&directory)?;
+            }
+        }
+
+        if !removed {
+            return Err(Box::new(StorageError::NotFound(format!(
+                "repository '{}'",
+                name
+            ))));
+        }
+
+        // Layers are hard links to the shared blobs, which only go away with
+        // the last repository linking to them
+        #[cfg(unix)]
+        if self.deduplicate {
+            for digest in layers {
+                self.prune_blob(&digest)?;
+            }
+        }
+
+        #[cfg(not(unix))]
+        drop(layers);
+
+        Ok(())
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        let mut repositories = self.list_area_repositories(Area::Layers)?;
+        repositories.extend(self.list_area_repositories(Area::Manifests)?);
+
+        repositories.sort();
+        repositories.dedup();
+        Ok(repositories)
+    }
+
+    async fn list_layers(&self, name: String) -> Result<Vec<String>> {
+        let path = self.get_directory_path(Area::Layers, &name);
+        self.list_directory(&path, false)
+    }
+
+    async fn list_tags(&self, name: String) -> Result<Vec<String>> {
+        let path = self.get_directory_path(Area::Manifests, &name);
+
+        Ok(self
+            .list_directory(&path, false)?
+            .into_iter()
+            .filter(|reference| !is_digest(reference))
+            .collect())
+    }
+
+    async fn list_manifests(&self, name: String) -> Result<Vec<String>> {
+        let path = self.get_directory_path(Area::Manifests, &name);
+        self.list_directory(&path, false)
+    }
+
+    async fn add_referrer(&self, name: String, subject: String, referrer: Referrer) -> Result<()> {
+        let path = self.get_referrers_directory_path(&name).join(subject);
+        fs::create_dir_all(&path)?;
+        fs::write(path.join(&referrer.digest), serde_json::to_vec(&referrer)?)?;
+
+        Ok(())
+    }
+
+    async fn remove_referrer(&self, name: String, subject: String, digest: String) -> Result<()> {
+        let path = self
+            .get_referrers_directory_path(&name)
+            .join(subject)
+            .join(digest);
+
+        if path.is_file() {
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+
+    async fn list_referrers(&self, name: String, subject: String) -> Result<Vec<Referrer>> {
+        let path = self.get_referrers_directory_path(&name).join(subject);
+
+        let mut referrers = Vec::new();
+        for digest in self.list_directory(&path, false)? {
+            referrers.push(serde_json::from_slice(&fs::read(path.join(digest))?)?);
+        }
+
+        Ok(referrers)
+    }
+
+    async fn clear_referrers(&self, name: String) -> Result<()> {
+        let path = self.get_referrers_directory_path(&name);
+
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        }
+
+        Ok(())
+    }
+
+    async fn repository_size(&self, name: String) -> Result<u64> {
+        let layers = self.directory_size(&self.get_directory_path(Area::Layers, &name))?;
+        let manifests = self.directory_size(&self.get_directory_path(Area::Manifests, &name))?;
+
+        Ok(layers + manifests)
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        if !self.path.is_dir() {
+            return Err(Error::from(format!(
+                "Storage path '{}' isn't a directory",
+                self.path.display()
+            )));
+        }
+
+        self.check_writable()
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.digest_algorithm
+    }
 }
 
 #[tokio::test]
@@ -329,3 +1046,365 @@ async fn test_upload_layer() -> Result<()> {
 
     super::tests::test_upload_layer(storage).await
 }
+
+#[tokio::test]
+async fn test_get_missing_layer() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = tempfile::tempdir()?;
+    let storage = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    super::tests::test_get_missing_layer(storage).await
+}
+
+#[tokio::test]
+async fn test_upload_state_round_trip() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = tempfile::tempdir()?;
+    let storage = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    super::tests::test_upload_state_round_trip(storage).await
+}
+
+#[tokio::test]
+async fn test_list_uploads() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = tempfile::tempdir()?;
+    let storage = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    super::tests::test_list_uploads(storage).await
+}
+
+#[tokio::test]
+async fn test_update_manifest() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = tempfile::tempdir()?;
+    let storage = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    super::tests::test_update_manifest(storage).await
+}
+
+#[tokio::test]
+async fn test_list_tags() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = tempfile::tempdir()?;
+    let storage = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    super::tests::test_list_tags(storage).await
+}
+
+#[tokio::test]
+async fn test_referrers() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = tempfile::tempdir()?;
+    let storage = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    super::tests::test_referrers(storage).await
+}
+
+#[tokio::test]
+async fn test_delete_repository() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = tempfile::tempdir()?;
+    let storage = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    super::tests::test_delete_repository(storage).await
+}
+
+#[tokio::test]
+async fn test_delete_nested_repository() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = tempfile::tempdir()?;
+    let storage = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    super::tests::test_delete_nested_repository(storage).await
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_delete_deduplicated_repository() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = tempfile::tempdir()?;
+    let mut storage = LocalStorage::new(temp_dir.path());
+    storage.deduplicate = true;
+
+    super::tests::test_delete_repository(Arc::new(storage)).await?;
+
+    assert_eq!(fs::read_dir(temp_dir.path().join("blobs"))?.count(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_repository_size() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = tempfile::tempdir()?;
+    let storage = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    super::tests::test_repository_size(storage).await
+}
+
+#[tokio::test]
+async fn test_verify_blob() -> Result<()> {
+    use std::sync::Arc;
+
+    let temp_dir = tempfile::tempdir()?;
+    let storage = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    let digest = super::tests::test_verify_blob(storage.clone()).await?;
+
+    fs::write(storage.get_layer_file_path("test", &digest), "corrupted")?;
+
+    let verification = storage.verify_blob("test".to_string(), digest).await?;
+    assert!(!verification.is_valid());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_deduplicate_layers() -> Result<()> {
+    use std::{os::unix::fs::MetadataExt, sync::Arc};
+
+    let temp_dir = tempfile::tempdir()?;
+    let mut storage = LocalStorage::new(temp_dir.path());
+    storage.deduplicate = true;
+    let storage = Arc::new(storage);
+
+    let first = super::tests::upload_layer(&(storage.clone() as _), "first", b"shared").await?;
+    let second = super::tests::upload_layer(&(storage.clone() as _), "second", b"shared").await?;
+    assert_eq!(first, second);
+
+    assert_eq!(fs::read_dir(temp_dir.path().join("blobs"))?.count(), 1);
+
+    let blob = storage.get_blob_file_path(&first).metadata()?;
+    assert_eq!(blob.nlink(), 3);
+    for name in ["first", "second"] {
+        let layer = storage.get_layer_file_path(name, &first).metadata()?;
+        assert_eq!(layer.ino(), blob.ino());
+    }
+
+    fs::remove_file(storage.get_layer_file_path("first", &first))?;
+    assert!(storage.prune_blobs()?.is_empty());
+
+    fs::remove_file(storage.get_layer_file_path("second", &first))?;
+    assert_eq!(storage.prune_blobs()?, vec![first]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_migrate_layout() -> Result<()> {
+    use std::sync::Arc;
+
+    use super::DistributionLayout;
+
+    let temp_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+
+    let digest = super::tests::upload_layer(&storage, "test", b"migrated").await?;
+    let details = storage
+        .update_manifest(
+            "test".to_string(),
+            "latest".to_string(),
+            super::tests::TEST_MANIFEST.as_bytes(),
+        )
+        .await?;
+    let upload_container = storage
+        .create_upload_container("pending".to_string())
+        .await?;
+    drop(storage);
+
+    let mut storage = LocalStorage::new(temp_dir.path());
+    storage.migrate(Box::new(DistributionLayout))?;
+
+    assert!(!temp_dir.path().join("layers").exists());
+    assert!(!temp_dir.path().join("manifests").exists());
+    assert!(temp_dir
+        .path()
+        .join("repositories/test/_layers")
+        .join(&digest)
+        .is_file());
+    assert!(temp_dir
+        .path()
+        .join("repositories/pending/_uploads")
+        .join(&upload_container.uuid)
+        .is_file());
+
+    assert_migrated_readable(&storage, &digest, &details.digest).await?;
+
+    storage.migrate(Box::new(DefaultLayout))?;
+    assert!(!temp_dir.path().join("repositories").exists());
+    assert_migrated_readable(&storage, &digest, &details.digest).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+async fn assert_migrated_readable(
+    storage: &LocalStorage,
+    digest: &str,
+    manifest_digest: &str,
+) -> Result<()> {
+    assert_eq!(storage.list_repositories().await?, vec!["test".to_string()]);
+    assert_eq!(storage.list_layers("test".to_string()).await?, vec![digest]);
+    assert_eq!(storage.list_tags("test".to_string()).await?, vec!["latest"]);
+
+    let manifest = storage
+        .get_manifest("test".to_string(), manifest_digest.to_string())
+        .await?;
+    assert_eq!(manifest.content, super::tests::TEST_MANIFEST.as_bytes());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_buffer_batches_small_chunks() -> Result<()> {
+    use std::task::{Context, Poll};
+
+    /// Counts the writes reaching it, each being a syscall on a file.
+    #[derive(Default)]
+    struct CountingWriter {
+        content: Vec<u8>,
+        writes: usize,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes += 1;
+            self.content.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    let chunks = || {
+        Box::pin(futures::stream::iter(
+            (0..1000).map(|i| Ok(Bytes::from(vec![i as u8]))),
+        )) as Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>
+    };
+
+    let mut unbuffered = CountingWriter::default();
+    write_stream(&mut unbuffered, chunks(), 0).await?;
+
+    let mut buffered = CountingWriter::default();
+    write_stream(&mut buffered, chunks(), DEFAULT_BUFFER_SIZE).await?;
+
+    assert_eq!(unbuffered.writes, 1000);
+    assert_eq!(buffered.writes, 1);
+    assert_eq!(buffered.content, unbuffered.content);
+
+    Ok(())
+}
+
+#[test]
+fn test_commit_file_across_filesystems() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let source = temp_dir.path().join("uploads/test/upload");
+    let destination = temp_dir.path().join("layers/test/layer");
+    fs::create_dir_all(source.parent().unwrap())?;
+    fs::write(&source, "layer content")?;
+
+    // Moving the upload out fails as it would across mounts
+    commit_file_with(&source, &destination, |from, to| {
+        if from == source {
+            Err(io::Error::from_raw_os_error(EXDEV))
+        } else {
+            fs::rename(from, to)
+        }
+    })?;
+
+    assert_eq!(fs::read_to_string(&destination)?, "layer content");
+    assert!(!source.exists());
+
+    // No temporary file is left behind
+    let entries = fs::read_dir(destination.parent().unwrap())?.count();
+    assert_eq!(entries, 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_prepare() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+
+    let storage = LocalStorage::new(temp_dir.path().join("registry"));
+    storage.prepare()?;
+    for area in ["uploads", "layers", "manifests", "referrers", "media-types"] {
+        assert!(storage.path.join(area).is_dir(), "{}", area);
+    }
+    assert!(storage.list_directory(&storage.path, false)?.is_empty());
+
+    // Nothing can be created under a file
+    let file = temp_dir.path().join("file");
+    fs::write(&file, b"")?;
+    let error = LocalStorage::new(file.join("registry"))
+        .prepare()
+        .unwrap_err();
+    assert!(error.to_string().contains("Failed to create"), "{}", error);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health_check() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    LocalStorage::new(temp_dir.path()).health_check().await?;
+
+    let missing = LocalStorage::new(temp_dir.path().join("missing"));
+    assert!(missing.health_check().await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_retag_removes_previous_digest_link() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let storage = LocalStorage::new(temp_dir.path());
+    let name = "test".to_string();
+
+    let previous = storage
+        .update_manifest(name.clone(), "latest".to_string(), b"{\"schemaVersion\":2}")
+        .await?;
+    let current = storage
+        .update_manifest(
+            name.clone(),
+            "latest".to_string(),
+            b"{ \"schemaVersion\": 2 }",
+        )
+        .await?;
+
+    // The previous digest would otherwise serve the current manifest
+    assert!(
+        !storage
+            .manifest_exists(name.clone(), previous.digest)
+            .await?
+    );
+
+    let summary = storage
+        .get_manifest_summary(name, "latest".to_string())
+        .await?;
+    assert_eq!(summary.digest, current.digest);
+
+    Ok(())
+}
@@ -1,8 +1,26 @@
 mod base;
+mod compressed;
+mod digest;
+mod encrypted;
+mod gcs;
+mod layout;
 mod local;
+mod redis_index;
+mod retry;
 mod s3;
+#[cfg(test)]
+pub(crate) mod s3_mock;
+mod tiered;
 pub mod types;
 
 pub use base::*;
+pub use compressed::*;
+pub use digest::*;
+pub use encrypted::*;
+pub use gcs::*;
+pub use layout::*;
 pub use local::*;
+pub use redis_index::*;
+pub use retry::*;
 pub use s3::*;
+pub use tiered::*;
@@ -0,0 +1,402 @@
+use std::{pin::Pin, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+use redis::{aio::ConnectionManager, AsyncCommands};
+
+use super::{
+    base::{ImageLayerInfo, Result, Storage, UploadContainer},
+    is_digest,
+    types::referrer::Referrer,
+    DigestAlgorithm, ManifestDetails, ManifestSummary, UpdateManifestDetails, UploadDetails,
+    UploadInfo, UploadStatus,
+};
+
+/// Keeps the repositories and their tags in Redis sets so listing them doesn't
+/// scan the backend, every other operation being forwarded to it.
+///
+/// The index is built from a scan of the backend the first time it's queried,
+/// unless a previous run already built it, and then maintained on every push
+/// and deletion going through this storage. Listings fall back to scanning the
+/// backend whenever Redis can't be reached.
+pub struct RedisIndexedStorage {
+    backend: Arc<dyn Storage>,
+    connection: ConnectionManager,
+    /// Prepended to every key, to share a Redis database between registries.
+    pub prefix: String,
+}
+
+impl RedisIndexedStorage {
+    pub async fn new(backend: Arc<dyn Storage>, client: redis::Client) -> Result<Self> {
+        Ok(RedisIndexedStorage {
+            backend,
+            connection: ConnectionManager::new(client).await?,
+            prefix: "rustgistry:".to_string(),
+        })
+    }
+
+    fn indexed_key(&self) -> String {
+        format!("{}indexed", self.prefix)
+    }
+
+    fn repositories_key(&self) -> String {
+        format!("{}repositories", self.prefix)
+    }
+
+    fn tags_key(&self, name: &str) -> String {
+        format!("{}tags:{}", self.prefix, name)
+    }
+
+    /// Replaces the index with the repositories and tags found in the backend.
+    pub async fn rebuild(&self) -> Result<()> {
+        let mut connection = self.connection.clone();
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+
+        let old_repositories: Vec<String> = connection.smembers(self.repositories_key()).await?;
+        for name in &old_repositories {
+            pipeline.del(self.tags_key(name)).ignore();
+        }
+        pipeline.del(self.repositories_key()).ignore();
+
+        for name in self.backend.list_repositories().await? {
+            let tags = self.backend.list_tags(name.clone()).await?;
+            if !tags.is_empty() {
+                pipeline.sadd(self.tags_key(&name), tags).ignore();
+            }
+
+            pipeline.sadd(self.repositories_key(), name).ignore();
+        }
+
+        pipeline.set(self.indexed_key(), 1).ignore();
+        pipeline.query_async::<_, ()>(&mut connection).await?;
+
+        Ok(())
+    }
+
+    async fn ensure_indexed(&self) -> Result<()> {
+        let mut connection = self.connection.clone();
+
+        let indexed: bool = connection.exists(self.indexed_key()).await?;
+        if !indexed {
+            self.rebuild().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn members(&self, key: String) -> Result<Vec<String>> {
+        self.ensure_indexed().await?;
+
+        let mut connection = self.connection.clone();
+        let mut members: Vec<String> = connection.smembers(key).await?;
+
+        members.sort();
+        Ok(members)
+    }
+
+    async fn add_repository(&self, name: &str, tag: Option<&str>) -> Result<()> {
+        let mut connection = self.connection.clone();
+        let mut pipeline = redis::pipe();
+
+        pipeline.sadd(self.repositories_key(), name).ignore();
+        if let Some(tag) = tag {
+            pipeline.sadd(self.tags_key(name), tag).ignore();
+        }
+
+        pipeline.query_async::<_, ()>(&mut connection).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for RedisIndexedStorage {
+    async fn get_image_layer_info(
+        &self,
+        name: String,
+        digest: String,
+    ) -> Result<Option<ImageLayerInfo>> {
+        self.backend.get_image_layer_info(name, digest).await
+    }
+
+    async fn blob_exists(&self, name: String, digest: String) -> Result<bool> {
+        self.backend.blob_exists(name, digest).await
+    }
+
+    async fn get_shared_blob_size(&self, digest: String) -> Result<Option<u64>> {
+        self.backend.get_shared_blob_size(digest).await
+    }
+
+    async fn get_layer(
+        &self,
+        name: String,
+        digest: String,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>> {
+        self.backend.get_layer(name, digest).await
+    }
+
+    async fn create_upload_container(&self, name: String) -> Result<UploadContainer> {
+        self.backend.create_upload_container(name).await
+    }
+
+    async fn check_upload_container_validity(&self, name: String, uuid: String) -> Result<bool> {
+        self.backend
+            .check_upload_container_validity(name, uuid)
+            .await
+    }
+
+    async fn write_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        stream: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
+        range: (u64, u64),
+    ) -> Result<UploadStatus> {
+        self.backend
+            .write_upload_container(name, uuid, stream, range)
+            .await
+    }
+
+    async fn get_upload_status(&self, name: String, uuid: String) -> Result<UploadStatus> {
+        self.backend.get_upload_status(name, uuid).await
+    }
+
+    async fn list_uploads(&self, name: Option<String>) -> Result<Vec<UploadInfo>> {
+        self.backend.list_uploads(name).await
+    }
+
+    async fn delete_upload_container(&self, name: String, uuid: String) -> Result<()> {
+        self.backend.delete_upload_container(name, uuid).await
+    }
+
+    async fn close_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: Option<String>,
+    ) -> Result<UploadDetails> {
+        let details = self
+            .backend
+            .close_upload_container(name.clone(), uuid, digest)
+            .await?;
+
+        if let Err(e) = self.add_repository(&name, None).await {
+            eprintln!("{}", e);
+        }
+
+        Ok(details)
+    }
+
+    async fn store_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: String,
+    ) -> Result<UploadDetails> {
+        let details = self
+            .backend
+            .store_upload_container(name.clone(), uuid, digest)
+            .await?;
+
+        if let Err(e) = self.add_repository(&name, None).await {
+            eprintln!("{}", e);
+        }
+
+        Ok(details)
+    }
+
+    async fn get_manifest_summary(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<ManifestSummary> {
+        self.backend.get_manifest_summary(name, reference).await
+    }
+
+    async fn manifest_exists(&self, name: String, reference: String) -> Result<bool> {
+        self.backend.manifest_exists(name, reference).await
+    }
+
+    async fn get_manifest(&self, name: String, reference: String) -> Result<ManifestDetails> {
+        self.backend.get_manifest(name, reference).await
+    }
+
+    async fn get_manifest_raw(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<(Bytes, String, String)> {
+        self.backend.get_manifest_raw(name, reference).await
+    }
+
+    async fn update_manifest(
+        &self,
+        name: String,
+        reference: String,
+        content: &[u8],
+    ) -> Result<UpdateManifestDetails> {
+        let details = self
+            .backend
+            .update_manifest(name.clone(), reference.clone(), content)
+            .await?;
+
+        let tag = Some(reference.as_str()).filter(|reference| !is_digest(reference));
+        if let Err(e) = self.add_repository(&name, tag).await {
+            eprintln!("{}", e);
+        }
+
+        Ok(details)
+    }
+
+    async fn delete_manifest(&self, name: String, reference: String) -> Result<()> {
+        self.backend
+            .delete_manifest(name.clone(), reference.clone())
+            .await?;
+
+        let mut connection = self.connection.clone();
+        if let Err(e) = connection
+            .srem::<_, _, ()>(self.tags_key(&name), reference)
+            .await
+        {
+            eprintln!("{}", e);
+        }
+
+        Ok(())
+    }
+
+    async fn delete_layer(&self, name: String, digest: String) -> Result<()> {
+        self.backend.delete_layer(name, digest).await
+    }
+
+    async fn set_blob_media_type(
+        &self,
+        name: String,
+        digest: String,
+        media_type: String,
+    ) -> Result<()> {
+        self.backend
+            .set_blob_media_type(name, digest, media_type)
+            .await
+    }
+
+    async fn get_blob_media_type(&self, name: String, digest: String) -> Result<Option<String>> {
+        self.backend.get_blob_media_type(name, digest).await
+    }
+
+    async fn delete_repository(&self, name: String) -> Result<()> {
+        self.backend.delete_repository(name.clone()).await?;
+
+        let mut connection = self.connection.clone();
+        let mut pipeline = redis::pipe();
+        pipeline
+            .srem(self.repositories_key(), &name)
+            .ignore()
+            .del(self.tags_key(&name))
+            .ignore();
+
+        if let Err(e) = pipeline.query_async::<_, ()>(&mut connection).await {
+            eprintln!("{}", e);
+        }
+
+        Ok(())
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        match self.members(self.repositories_key()).await {
+            Ok(repositories) => Ok(repositories),
+            Err(e) => {
+                eprintln!("{}", e);
+                self.backend.list_repositories().await
+            }
+        }
+    }
+
+    async fn list_layers(&self, name: String) -> Result<Vec<String>> {
+        self.backend.list_layers(name).await
+    }
+
+    async fn list_tags(&self, name: String) -> Result<Vec<String>> {
+        match self.members(self.tags_key(&name)).await {
+            Ok(tags) => Ok(tags),
+            Err(e) => {
+                eprintln!("{}", e);
+                self.backend.list_tags(name).await
+            }
+        }
+    }
+
+    async fn list_manifests(&self, name: String) -> Result<Vec<String>> {
+        self.backend.list_manifests(name).await
+    }
+
+    async fn add_referrer(&self, name: String, subject: String, referrer: Referrer) -> Result<()> {
+        self.backend.add_referrer(name, subject, referrer).await
+    }
+
+    async fn remove_referrer(&self, name: String, subject: String, digest: String) -> Result<()> {
+        self.backend.remove_referrer(name, subject, digest).await
+    }
+
+    async fn list_referrers(&self, name: String, subject: String) -> Result<Vec<Referrer>> {
+        self.backend.list_referrers(name, subject).await
+    }
+
+    async fn clear_referrers(&self, name: String) -> Result<()> {
+        self.backend.clear_referrers(name).await
+    }
+
+    async fn link_manifest_digests(&self, name: String) -> Result<()> {
+        self.backend.link_manifest_digests(name).await
+    }
+
+    async fn repository_size(&self, name: String) -> Result<u64> {
+        self.backend.repository_size(name).await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.backend.health_check().await
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.backend.digest_algorithm()
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires a Redis server"]
+async fn test_listings_served_from_index() -> Result<()> {
+    use super::LocalStorage;
+
+    let temp_dir = tempfile::tempdir()?;
+    let backend: Arc<dyn Storage> = Arc::new(LocalStorage::new(temp_dir.path()));
+    backend
+        .update_manifest(
+            "existing".to_string(),
+            "v1".to_string(),
+            super::tests::TEST_MANIFEST.as_bytes(),
+        )
+        .await?;
+
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+    let mut storage = RedisIndexedStorage::new(backend, redis::Client::open(url)?).await?;
+    storage.prefix = format!("rustgistry-test-{}:", uuid::Uuid::new_v4());
+    let storage: Arc<dyn Storage> = Arc::new(storage);
+
+    // Built from the backend on first use
+    assert_eq!(storage.list_tags("existing".to_string()).await?, vec!["v1"]);
+
+    super::tests::test_list_tags(storage.clone()).await?;
+    assert_eq!(
+        storage.list_repositories().await?,
+        vec!["existing".to_string(), "test".to_string()]
+    );
+
+    storage
+        .delete_manifest("test".to_string(), "v1".to_string())
+        .await?;
+    assert_eq!(storage.list_tags("test".to_string()).await?, vec!["latest"]);
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use std::{future::Future, time::Duration};
+
+use rand::Rng;
+
+/// Exponential backoff with full jitter applied to transient storage failures.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every following attempt.
+    pub base_delay: Duration,
+    /// Upper bound of a single delay.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+
+        let millis = delay.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+
+    /// Runs `operation` until it succeeds, fails with an error `is_transient`
+    /// rejects, or `max_attempts` is reached.
+    pub async fn retry<T, E, F, Fut>(
+        &self,
+        is_transient: fn(&E) -> bool,
+        mut operation: F,
+    ) -> std::result::Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = std::result::Result<T, E>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            match operation().await {
+                Err(e) if attempt + 1 < self.max_attempts && is_transient(&e) => {
+                    tokio::time::sleep(self.delay(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_retry_transient_errors() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(1),
+    };
+
+    let calls = AtomicU32::new(0);
+    let result: std::result::Result<(), &str> = policy
+        .retry(
+            |e| *e == "transient",
+            || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async { Err("transient") }
+            },
+        )
+        .await;
+    assert_eq!(result, Err("transient"));
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    let calls = AtomicU32::new(0);
+    let result: std::result::Result<(), &str> = policy
+        .retry(
+            |e| *e == "transient",
+            || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async { Err("fatal") }
+            },
+        )
+        .await;
+    assert_eq!(result, Err("fatal"));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
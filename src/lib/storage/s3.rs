@@ -1,28 +1,79 @@
-use std::{path::PathBuf, pin::Pin, time::SystemTime};
+use std::{collections::HashMap, path::PathBuf, pin::Pin};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::{Stream, StreamExt};
+use hyper::StatusCode;
 use rusoto_core::{Region, RusotoError};
 use rusoto_s3::{
-    CopyObjectRequest, DeleteObjectRequest, GetObjectError, GetObjectRequest, HeadObjectError,
-    HeadObjectRequest, PutObjectRequest, S3Client, StreamingBody, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CopyObjectRequest, CreateMultipartUploadRequest, DeleteObjectRequest,
+    GetObjectError, GetObjectRequest, HeadBucketRequest, HeadObjectError, HeadObjectRequest,
+    ListObjectsV2Request, Object, PutObjectRequest, S3Client, UploadPartCopyRequest, S3,
 };
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
-use crate::utils;
-
 use super::{
-    base::{ImageLayerInfo, Result, Storage, UploadContainer},
-    types::manifest::Manifest,
-    Error, ManifestDetails, ManifestSummary, UpdateManifestDetails, UploadDetails, UploadStatus,
+    base::{check_upload_digest, ImageLayerInfo, Result, Storage, UploadContainer},
+    canonical_digest, is_digest,
+    layout::{is_repository_entry, is_repository_name},
+    retry::RetryPolicy,
+    types::{
+        manifest::{manifest_content_type, Manifest},
+        referrer::Referrer,
+    },
+    DigestAlgorithm, Error, ManifestDetails, ManifestSummary, StorageError, UpdateManifestDetails,
+    UploadDetails, UploadInfo, UploadState, UploadStatus,
 };
 
+/// Server-side encryption S3 applies to the objects written by `S3Storage`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum S3Encryption {
+    /// Left to the default encryption of the bucket.
+    #[default]
+    None,
+    /// SSE-S3, with keys managed by S3.
+    S3,
+    /// SSE-KMS, with the given KMS key or the AWS managed one.
+    Kms { key_id: Option<String> },
+}
+
+impl S3Encryption {
+    fn server_side_encryption(&self) -> Option<String> {
+        match self {
+            S3Encryption::None => None,
+            S3Encryption::S3 => Some("AES256".to_string()),
+            S3Encryption::Kms { .. } => Some("aws:kms".to_string()),
+        }
+    }
+
+    fn ssekms_key_id(&self) -> Option<String> {
+        match self {
+            S3Encryption::Kms { key_id } => key_id.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// Size of the parts chunked uploads are aggregated into.
+pub const DEFAULT_MULTIPART_PART_SIZE: usize = 16 * 1024 * 1024;
+
+/// Smallest part S3 accepts in a multipart upload, the last one excepted.
+pub const MIN_MULTIPART_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Object metadata holding the digest of a stored manifest.
+const MANIFEST_DIGEST_METADATA: &str = "digest";
+
 pub struct S3Storage {
     pub bucket: String,
     pub region: Region,
+    pub retry: RetryPolicy,
+    /// Applied to every object written, copies included.
+    pub encryption: S3Encryption,
+    /// Algorithm the digests of uploaded layers and pushed manifests are
+    /// computed with.
+    pub digest_algorithm: DigestAlgorithm,
+    multipart_part_size: usize,
     client: S3Client,
 }
 
@@ -32,15 +83,64 @@ impl S3Storage {
         S: AsRef<str>,
     {
         let client = S3Client::new(region.clone());
+        S3Storage::with_client(bucket, region, client)
+    }
 
+    pub fn with_client<S>(bucket: S, region: Region, client: S3Client) -> S3Storage
+    where
+        S: AsRef<str>,
+    {
         S3Storage {
             bucket: bucket.as_ref().to_owned(),
             region,
+            retry: RetryPolicy::default(),
+            encryption: S3Encryption::default(),
+            digest_algorithm: DigestAlgorithm::default(),
+            multipart_part_size: DEFAULT_MULTIPART_PART_SIZE,
             client,
         }
     }
 
-    fn get_upload_file_path(&self, name: &String, uuid: &String) -> String {
+    /// Checks the bucket exists and is accessible with the credentials in use.
+    pub async fn check_bucket(&self) -> Result<()> {
+        let result = self
+            .retry
+            .retry(is_transient_error, || {
+                self.client.head_bucket(HeadBucketRequest {
+                    bucket: self.bucket.clone(),
+                    ..Default::default()
+                })
+            })
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::from(format!(
+                "Bucket '{}' isn't accessible: {}",
+                self.bucket, e
+            ))),
+        }
+    }
+
+    pub fn multipart_part_size(&self) -> usize {
+        self.multipart_part_size
+    }
+
+    /// Sets the size incoming chunks are buffered up to before being stored
+    /// as a part, which can't be below the S3 minimum.
+    pub fn set_multipart_part_size(&mut self, size: usize) -> Result<()> {
+        if size < MIN_MULTIPART_PART_SIZE {
+            return Err(Error::from(format!(
+                "Multipart part size must be at least {} bytes",
+                MIN_MULTIPART_PART_SIZE
+            )));
+        }
+
+        self.multipart_part_size = size;
+        Ok(())
+    }
+
+    fn get_upload_file_path(&self, name: &str, uuid: &str) -> String {
         ["uploads", name, uuid]
             .iter()
             .collect::<PathBuf>()
@@ -49,7 +149,122 @@ impl S3Storage {
             .to_owned()
     }
 
-    fn get_layer_file_path(&self, name: &String, digest: &String) -> String {
+    fn get_upload_part_path(upload_key: &str, index: usize) -> String {
+        format!("{}.{:05}", upload_key, index)
+    }
+
+    /// Keys and sizes of the parts written to an upload so far, in order.
+    async fn list_upload_parts(&self, upload_key: &str) -> Result<Vec<(String, u64)>> {
+        let (objects, _) = self.list_objects(format!("{}.", upload_key), None).await?;
+
+        let mut parts = objects
+            .into_iter()
+            .filter_map(|object| Some((object.key?, object.size.unwrap_or(0) as u64)))
+            .collect::<Vec<_>>();
+
+        parts.sort();
+        Ok(parts)
+    }
+
+    async fn put_upload_part(&self, upload_key: &str, index: usize, part: Vec<u8>) -> Result<()> {
+        let key = S3Storage::get_upload_part_path(upload_key, index);
+
+        self.retry
+            .retry(is_transient_error, || {
+                self.client.put_object(PutObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    body: Some(part.clone().into()),
+                    server_side_encryption: self.encryption.server_side_encryption(),
+                    ssekms_key_id: self.encryption.ssekms_key_id(),
+                    ..Default::default()
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Assembles the parts into a single object with a multipart upload,
+    /// aborting it if any of them can't be copied.
+    async fn assemble_parts(&self, key: &str, parts: &[(String, u64)]) -> Result<()> {
+        let upload_id = self
+            .retry
+            .retry(is_transient_error, || {
+                self.client
+                    .create_multipart_upload(CreateMultipartUploadRequest {
+                        bucket: self.bucket.clone(),
+                        key: key.to_string(),
+                        server_side_encryption: self.encryption.server_side_encryption(),
+                        ssekms_key_id: self.encryption.ssekms_key_id(),
+                        ..Default::default()
+                    })
+            })
+            .await?
+            .upload_id
+            .ok_or_else(|| Error::from("Missing upload id in response"))?;
+
+        let result = self.copy_parts(key, &upload_id, parts).await;
+        if result.is_err() {
+            let _ = self
+                .client
+                .abort_multipart_upload(AbortMultipartUploadRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.to_string(),
+                    upload_id,
+                    ..Default::default()
+                })
+                .await;
+        }
+
+        result
+    }
+
+    async fn copy_parts(&self, key: &str, upload_id: &str, parts: &[(String, u64)]) -> Result<()> {
+        let mut completed_parts = Vec::new();
+
+        for (index, (part_key, _)) in parts.iter().enumerate() {
+            let part_number = index as i64 + 1;
+
+            let output = self
+                .retry
+                .retry(is_transient_error, || {
+                    self.client.upload_part_copy(UploadPartCopyRequest {
+                        bucket: self.bucket.clone(),
+                        copy_source: format!("{}/{}", self.bucket, part_key),
+                        key: key.to_string(),
+                        part_number,
+                        upload_id: upload_id.to_string(),
+                        ..Default::default()
+                    })
+                })
+                .await?;
+
+            completed_parts.push(CompletedPart {
+                e_tag: output.copy_part_result.and_then(|result| result.e_tag),
+                part_number: Some(part_number),
+            });
+        }
+
+        self.retry
+            .retry(is_transient_error, || {
+                self.client
+                    .complete_multipart_upload(CompleteMultipartUploadRequest {
+                        bucket: self.bucket.clone(),
+                        key: key.to_string(),
+                        upload_id: upload_id.to_string(),
+                        multipart_upload: Some(CompletedMultipartUpload {
+                            parts: Some(completed_parts.clone()),
+                        }),
+                        ..Default::default()
+                    })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    fn get_layer_file_path(&self, name: &str, digest: &str) -> String {
         ["layers", name, digest]
             .iter()
             .collect::<PathBuf>()
@@ -58,7 +273,108 @@ impl S3Storage {
             .to_owned()
     }
 
-    fn get_manifest_file_path(&self, name: &String, reference: &String) -> String {
+    /// Lists the objects under `prefix`, along with the sub-prefixes they are
+    /// grouped under when a `delimiter` is given.
+    async fn list_objects(
+        &self,
+        prefix: String,
+        delimiter: Option<String>,
+    ) -> Result<(Vec<Object>, Vec<String>)> {
+        let mut objects = Vec::new();
+        let mut prefixes = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let output = self
+                .retry
+                .retry(is_transient_error, || {
+                    self.client.list_objects_v2(ListObjectsV2Request {
+                        bucket: self.bucket.clone(),
+                        prefix: Some(prefix.clone()),
+                        delimiter: delimiter.clone(),
+                        continuation_token: continuation_token.clone(),
+                        ..Default::default()
+                    })
+                })
+                .await?;
+
+            objects.extend(output.contents.unwrap_or_default());
+            prefixes.extend(
+                output
+                    .common_prefixes
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|common_prefix| common_prefix.prefix),
+            );
+
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated == Some(true) => {
+                    continuation_token = Some(token)
+                }
+                _ => break,
+            }
+        }
+
+        Ok((objects, prefixes))
+    }
+
+    fn get_media_type_file_path(&self, name: &str, digest: &str) -> String {
+        ["media-types", name, digest]
+            .iter()
+            .collect::<PathBuf>()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    fn get_referrer_file_path(&self, name: &str, subject: &str, digest: &str) -> String {
+        ["referrers", name, subject, digest]
+            .iter()
+            .collect::<PathBuf>()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    async fn get_object_bytes(&self, key: &str) -> Result<Vec<u8>> {
+        let result = self
+            .retry
+            .retry(is_transient_error, || {
+                self.client.get_object(GetObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.to_string(),
+                    ..Default::default()
+                })
+            })
+            .await?;
+
+        let mut stream = result
+            .body
+            .ok_or_else(|| Error::from("Missing body in response"))?;
+
+        let mut content = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+
+        Ok(content)
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<()> {
+        self.retry
+            .retry(is_transient_error, || {
+                self.client.delete_object(DeleteObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.to_string(),
+                    ..Default::default()
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    fn get_manifest_file_path(&self, name: &str, reference: &str) -> String {
         ["manifests", name, reference]
             .iter()
             .collect::<PathBuf>()
@@ -66,13 +382,70 @@ impl S3Storage {
             .unwrap()
             .to_owned()
     }
+
+    async fn object_exists(&self, key: &str) -> Result<bool> {
+        match self
+            .retry
+            .retry(is_transient_error, || {
+                self.client.head_object(HeadObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.to_string(),
+                    ..Default::default()
+                })
+            })
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e) if is_missing_object(&e) => Ok(false),
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-struct UploadState {
-    name: String,
-    uuid: String,
-    created_at: u64,
+/// Throttling, server-side failures and connection errors are worth retrying;
+/// service errors such as `NoSuchKey` are not.
+fn is_transient_error<E>(error: &RusotoError<E>) -> bool {
+    match error {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(response) => {
+            response.status.is_server_error()
+                || response.status == StatusCode::TOO_MANY_REQUESTS
+                || ["SlowDown", "RequestTimeout", "Throttling"]
+                    .iter()
+                    .any(|code| response.body_as_str().contains(code))
+        }
+        _ => false,
+    }
+}
+
+/// HEAD responses carry no error document, so rusoto can't tell `NoSuchKey`
+/// apart and reports a missing object as an unknown 404 response.
+fn is_missing_object<E>(error: &RusotoError<E>) -> bool {
+    matches!(error, RusotoError::Unknown(response) if response.status == StatusCode::NOT_FOUND)
+}
+
+/// Seconds since the Unix epoch of an S3 timestamp, e.g. `2009-10-12T17:50:30.000Z`.
+fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let (date, time) = timestamp.trim_end_matches('Z').split_once('T')?;
+
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+    let mut time = time.splitn(3, ':');
+    let hours = time.next()?.parse::<i64>().ok()?;
+    let minutes = time.next()?.parse::<i64>().ok()?;
+    let seconds = time.next()?.split('.').next()?.parse::<i64>().ok()?;
+
+    // Days from the civil date, March-based years putting leap days last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    u64::try_from(days * 86400 + hours * 3600 + minutes * 60 + seconds).ok()
 }
 
 #[async_trait]
@@ -85,27 +458,32 @@ impl Storage for S3Storage {
         let key = self.get_layer_file_path(&name, &digest);
 
         let result = self
-            .client
-            .get_object(GetObjectRequest {
-                bucket: self.bucket.clone(),
-                key: key.clone(),
-                ..Default::default()
+            .retry
+            .retry(is_transient_error, || {
+                self.client.head_object(HeadObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    ..Default::default()
+                })
             })
             .await;
         let result = match result {
             Ok(output) => output,
-            Err(e) => {
-                if let RusotoError::Service(GetObjectError::NoSuchKey(_)) = e {
-                    return Ok(None);
-                } else {
-                    return Err(Error::from(e.to_string()));
-                }
-            }
+            Err(e) if is_missing_object(&e) => return Ok(None),
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
         };
 
-        Ok(Some(ImageLayerInfo {
-            size: result.content_length.unwrap_or(0) as u64,
-        }))
+        let size = result
+            .content_length
+            .ok_or_else(|| Error::from(format!("Missing size of layer '{}'", digest)))?;
+
+        Ok(Some(ImageLayerInfo { size: size as u64 }))
+    }
+
+    async fn blob_exists(&self, name: String, digest: String) -> Result<bool> {
+        self.object_exists(&self.get_layer_file_path(&name, &digest))
+            .await
     }
 
     async fn get_layer(
@@ -116,17 +494,22 @@ impl Storage for S3Storage {
         let key = self.get_layer_file_path(&name, &digest);
 
         let result = self
-            .client
-            .get_object(GetObjectRequest {
-                bucket: self.bucket.clone(),
-                key: key.clone(),
-                ..Default::default()
+            .retry
+            .retry(is_transient_error, || {
+                self.client.get_object(GetObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    ..Default::default()
+                })
             })
             .await;
         let result = match result {
             Ok(output) => output,
             Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
-                return Ok(Box::pin(futures::stream::empty()))
+                return Err(Box::new(StorageError::NotFound(format!(
+                    "layer '{}'",
+                    digest
+                ))))
             }
             Err(e) => return Err(Box::new(e)),
         };
@@ -143,20 +526,20 @@ impl Storage for S3Storage {
 
     async fn create_upload_container(&self, name: String) -> Result<UploadContainer> {
         let uuid = Uuid::new_v4().to_string();
-        let created_at = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
 
         let key = self.get_upload_file_path(&name, &uuid);
 
         match self
-            .client
-            .put_object(PutObjectRequest {
-                bucket: self.bucket.clone(),
-                key: key.clone(),
-                body: None,
-                ..Default::default()
+            .retry
+            .retry(is_transient_error, || {
+                self.client.put_object(PutObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    body: None,
+                    server_side_encryption: self.encryption.server_side_encryption(),
+                    ssekms_key_id: self.encryption.ssekms_key_id(),
+                    ..Default::default()
+                })
             })
             .await
         {
@@ -164,118 +547,222 @@ impl Storage for S3Storage {
             Err(e) => return Err(Box::new(e)),
         }
 
-        let state = UploadState {
-            name: name.clone(),
-            uuid: uuid.clone(),
-            created_at,
-        };
+        let state = UploadState::new(name, uuid.clone());
 
-        let state_json = serde_json::to_string(&state)?;
         Ok(UploadContainer {
             uuid,
-            state: state_json,
+            state: state.encode()?,
         })
     }
 
     async fn check_upload_container_validity(&self, name: String, uuid: String) -> Result<bool> {
-        let key = self.get_upload_file_path(&name, &uuid);
-
-        match self
-            .client
-            .head_object(HeadObjectRequest {
-                bucket: self.bucket.clone(),
-                key: key.clone(),
-                ..Default::default()
-            })
+        self.object_exists(&self.get_upload_file_path(&name, &uuid))
             .await
-        {
-            Ok(_) => Ok(true),
-            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false),
-            Err(e) => Err(Box::new(e)),
-        }
     }
 
     async fn write_upload_container(
         &self,
         name: String,
         uuid: String,
-        stream: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
-        _range: (u64, u64),
+        mut stream: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
+        range: (u64, u64),
     ) -> Result<UploadStatus> {
         let key = self.get_upload_file_path(&name, &uuid);
 
-        let tmp_file = tempfile::NamedTempFile::new()?;
+        // Chunks are stored as parts of the final object, a part shorter than
+        // the part size being the last one and filled up by the next chunks
+        let parts = self.list_upload_parts(&key).await?;
 
-        let byte_stream = stream.map(move |b| match b {
-            Ok(b) => Ok(b),
-            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
-        });
-
-        self.client
-            .put_object(PutObjectRequest {
-                bucket: self.bucket.clone(),
-                key: key.clone(),
-                body: Some(StreamingBody::new(byte_stream)),
-                ..Default::default()
-            })
-            .await?;
-        tmp_file.close()?;
+        // Parts aren't rewritten, so chunks can only be appended
+        let uploaded = parts.iter().map(|(_, size)| size).sum::<u64>();
+        if range.0 != uploaded {
+            return Err(Box::new(StorageError::RangeInvalid {
+                start: range.0,
+                size: uploaded,
+            }));
+        }
+        let mut index = parts.len();
+        let mut buffer = Vec::new();
+        if let Some((last_key, last_size)) = parts.last() {
+            if (*last_size as usize) < self.multipart_part_size {
+                buffer = self.get_object_bytes(last_key).await?;
+                index -= 1;
+            }
+        }
 
-        let request = HeadObjectRequest {
-            bucket: self.bucket.clone(),
-            key: key.clone(),
-            ..Default::default()
-        };
+        let mut size = parts[..index].iter().map(|(_, size)| size).sum::<u64>();
+
+        while let Some(chunk) = stream.next().await {
+            buffer.extend_from_slice(&chunk?);
+
+            while buffer.len() >= self.multipart_part_size {
+                let rest = buffer.split_off(self.multipart_part_size);
+                let part = std::mem::replace(&mut buffer, rest);
+
+                size += part.len() as u64;
+                self.put_upload_part(&key, index, part).await?;
+                index += 1;
+            }
+        }
+
+        if !buffer.is_empty() {
+            size += buffer.len() as u64;
+            self.put_upload_part(&key, index, buffer).await?;
+        }
+
+        Ok(UploadStatus { size })
+    }
+
+    async fn get_upload_status(&self, name: String, uuid: String) -> Result<UploadStatus> {
+        if !self
+            .check_upload_container_validity(name.clone(), uuid.clone())
+            .await?
+        {
+            return Err(Box::new(StorageError::NotFound(format!(
+                "upload '{}'",
+                uuid
+            ))));
+        }
+
+        let key = self.get_upload_file_path(&name, &uuid);
+        let parts = self.list_upload_parts(&key).await?;
 
-        let result = self.client.head_object(request).await?;
         Ok(UploadStatus {
-            size: result.content_length.unwrap_or(0) as u64,
+            size: parts.iter().map(|(_, size)| size).sum(),
         })
     }
 
-    async fn close_upload_container(&self, name: String, uuid: String) -> Result<UploadDetails> {
+    async fn list_uploads(&self, name: Option<String>) -> Result<Vec<UploadInfo>> {
+        let prefix = match &name {
+            Some(name) => format!("uploads/{}/", name),
+            None => "uploads/".to_string(),
+        };
+        let (objects, _) = self.list_objects(prefix, None).await?;
+
+        let mut uploads = Vec::new();
+        let mut part_sizes = HashMap::new();
+        for object in objects {
+            let key = match object.key {
+                Some(key) => key,
+                None => continue,
+            };
+            let (name, file) = match key
+                .strip_prefix("uploads/")
+                .and_then(|key| key.rsplit_once('/'))
+            {
+                Some(path) => path,
+                None => continue,
+            };
+
+            match file.split_once('.') {
+                Some((uuid, _)) => {
+                    *part_sizes
+                        .entry((name.to_string(), uuid.to_string()))
+                        .or_insert(0) += object.size.unwrap_or(0) as u64;
+                }
+                None => uploads.push(UploadInfo {
+                    name: name.to_string(),
+                    uuid: file.to_string(),
+                    size: 0,
+                    created_at: object.last_modified.as_deref().and_then(parse_timestamp),
+                }),
+            }
+        }
+
+        for upload in &mut uploads {
+            upload.size = part_sizes
+                .get(&(upload.name.clone(), upload.uuid.clone()))
+                .copied()
+                .unwrap_or(0);
+        }
+
+        Ok(uploads)
+    }
+
+    async fn delete_upload_container(&self, name: String, uuid: String) -> Result<()> {
         let key = self.get_upload_file_path(&name, &uuid);
 
-        let result = self
-            .client
-            .get_object(GetObjectRequest {
-                bucket: self.bucket.clone(),
-                key: key.clone(),
-                ..Default::default()
-            })
-            .await?;
+        for (part_key, _) in self.list_upload_parts(&key).await? {
+            self.delete_object(&part_key).await?;
+        }
+        self.delete_object(&key).await?;
 
-        let mut hasher = Sha256::new();
+        Ok(())
+    }
 
-        let mut stream = result
-            .body
-            .ok_or_else(|| Error::from("Missing body in response"))?;
-        while let Some(chunk) = stream.next().await {
-            let bytes = chunk?;
-            hasher.update(&bytes);
+    async fn close_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: Option<String>,
+    ) -> Result<UploadDetails> {
+        let key = self.get_upload_file_path(&name, &uuid);
+
+        let mut hasher = digest
+            .as_deref()
+            .and_then(DigestAlgorithm::of)
+            .unwrap_or(self.digest_algorithm)
+            .hasher();
+        for (part_key, _) in self.list_upload_parts(&key).await? {
+            hasher.update(self.get_object_bytes(&part_key).await?);
         }
 
-        let hash = hex::encode(hasher.finalize());
-        let digest = format!("sha256:{}", hash);
+        let actual = hasher.finalize();
 
+        if let Err(e) = check_upload_digest(digest.as_deref(), &actual) {
+            self.delete_upload_container(name, uuid).await?;
+            return Err(e);
+        }
+
+        self.store_upload_container(name, uuid, actual).await
+    }
+
+    async fn store_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: String,
+    ) -> Result<UploadDetails> {
+        let key = self.get_upload_file_path(&name, &uuid);
         let layer_key = self.get_layer_file_path(&name, &digest);
 
-        self.client
-            .copy_object(CopyObjectRequest {
-                bucket: self.bucket.clone(),
-                copy_source: format!("{}/{}", self.bucket, key),
-                key: layer_key.clone(),
-                ..Default::default()
-            })
-            .await?;
+        let parts = self.list_upload_parts(&key).await?;
+        match parts.as_slice() {
+            [] => {
+                self.retry
+                    .retry(is_transient_error, || {
+                        self.client.put_object(PutObjectRequest {
+                            bucket: self.bucket.clone(),
+                            key: layer_key.clone(),
+                            body: None,
+                            server_side_encryption: self.encryption.server_side_encryption(),
+                            ssekms_key_id: self.encryption.ssekms_key_id(),
+                            ..Default::default()
+                        })
+                    })
+                    .await?;
+            }
+            [(part_key, _)] => {
+                self.retry
+                    .retry(is_transient_error, || {
+                        self.client.copy_object(CopyObjectRequest {
+                            bucket: self.bucket.clone(),
+                            copy_source: format!("{}/{}", self.bucket, part_key),
+                            key: layer_key.clone(),
+                            server_side_encryption: self.encryption.server_side_encryption(),
+                            ssekms_key_id: self.encryption.ssekms_key_id(),
+                            ..Default::default()
+                        })
+                    })
+                    .await?;
+            }
+            parts => self.assemble_parts(&layer_key, parts).await?,
+        }
 
-        self.client
-            .delete_object(DeleteObjectRequest {
-                bucket: self.bucket.clone(),
-                key: key.clone(),
-                ..Default::default()
-            })
-            .await?;
+        for (part_key, _) in &parts {
+            self.delete_object(part_key).await?;
+        }
+        self.delete_object(&key).await?;
 
         Ok(UploadDetails { digest })
     }
@@ -287,44 +774,59 @@ impl Storage for S3Storage {
     ) -> Result<ManifestSummary> {
         let key = self.get_manifest_file_path(&name, &reference);
 
-        let result = self
-            .client
-            .get_object(GetObjectRequest {
-                bucket: self.bucket.clone(),
-                key: key.clone(),
-                ..Default::default()
+        let result = match self
+            .retry
+            .retry(is_transient_error, || {
+                self.client.head_object(HeadObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    ..Default::default()
+                })
             })
-            .await?;
-
-        let mut stream = result
-            .body
-            .ok_or_else(|| Error::from("Missing body in response"))?;
-
-        let mut manifest_content = String::new();
-        while let Some(chunk) = stream.next().await {
-            let bytes = chunk?;
-            manifest_content.push_str(&String::from_utf8(bytes.to_vec())?);
-        }
+            .await
+        {
+            Ok(result) => result,
+            Err(e) if is_missing_object(&e) => {
+                return Err(Box::new(StorageError::NotFound(format!(
+                    "manifest '{}'",
+                    reference
+                ))))
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
 
-        let mut hasher = Sha256::new();
-        hasher.update(&manifest_content);
-        let hash = hex::encode(hasher.finalize());
-        let digest = format!("sha256:{}", hash);
+        // Manifests stored before their digest was recorded are hashed
+        let recorded = result
+            .metadata
+            .and_then(|mut metadata| metadata.remove(MANIFEST_DIGEST_METADATA));
+        let digest = match canonical_digest(&reference).or(recorded) {
+            Some(digest) => digest,
+            None => self
+                .digest_algorithm
+                .digest(self.get_object_bytes(&key).await?),
+        };
 
         let size = result.content_length.unwrap_or(0) as u64;
 
         Ok(ManifestSummary { digest, size })
     }
 
+    async fn manifest_exists(&self, name: String, reference: String) -> Result<bool> {
+        let key = self.get_manifest_file_path(&name, &reference);
+        self.object_exists(&key).await
+    }
+
     async fn get_manifest(&self, name: String, reference: String) -> Result<ManifestDetails> {
         let key = self.get_manifest_file_path(&name, &reference);
 
         let result = self
-            .client
-            .get_object(GetObjectRequest {
-                bucket: self.bucket.clone(),
-                key: key.clone(),
-                ..Default::default()
+            .retry
+            .retry(is_transient_error, || {
+                self.client.get_object(GetObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    ..Default::default()
+                })
             })
             .await?;
 
@@ -332,55 +834,85 @@ impl Storage for S3Storage {
             .body
             .ok_or_else(|| Error::from("Missing body in response"))?;
 
-        let mut manifest_content = String::new();
+        let mut content = Vec::new();
         while let Some(chunk) = stream.next().await {
-            let bytes = chunk?;
-            manifest_content.push_str(&String::from_utf8(bytes.to_vec())?);
+            content.extend_from_slice(&chunk?);
         }
 
-        let manifest: Manifest = serde_json::from_str(&manifest_content)?;
+        let manifest: Manifest = serde_json::from_slice(&content)?;
 
-        let mut hasher = Sha256::new();
-        hasher.update(&manifest_content);
-        let hash = hex::encode(hasher.finalize());
-        let digest = format!("sha256:{}", hash);
+        let digest = DigestAlgorithm::of(&reference)
+            .unwrap_or(self.digest_algorithm)
+            .digest(&content);
 
-        Ok(ManifestDetails { manifest, digest })
+        Ok(ManifestDetails {
+            manifest,
+            digest,
+            content: Bytes::from(content),
+        })
+    }
+
+    async fn get_manifest_raw(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<(Bytes, String, String)> {
+        let key = self.get_manifest_file_path(&name, &reference);
+
+        let content = self.get_object_bytes(&key).await?;
+        let media_type = manifest_content_type(&content)?;
+        let digest = DigestAlgorithm::of(&reference)
+            .unwrap_or(self.digest_algorithm)
+            .digest(&content);
+
+        Ok((Bytes::from(content), media_type, digest))
     }
 
     async fn update_manifest(
         &self,
         name: String,
         reference: String,
-        manifest: Manifest,
+        content: &[u8],
     ) -> Result<UpdateManifestDetails> {
-        let json = utils::to_json_normalized(&manifest)?;
-
-        let mut hasher = Sha256::new();
-        hasher.update(json.as_bytes());
-        let hash = hex::encode(hasher.finalize());
-        let digest = format!("sha256:{}", hash);
+        let digest = self.digest_algorithm.digest(content);
 
         let key = self.get_manifest_file_path(&name, &reference);
 
-        // fs::write(&path, &json)?;
-        self.client
-            .put_object(PutObjectRequest {
-                bucket: self.bucket.clone(),
-                key: key.clone(),
-                body: Some(json.into_bytes().into()),
-                ..Default::default()
+        self.retry
+            .retry(is_transient_error, || {
+                self.client.put_object(PutObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    body: Some(content.to_vec().into()),
+                    // Lets tags be resolved to their digest with a HEAD request
+                    metadata: Some(HashMap::from([(
+                        MANIFEST_DIGEST_METADATA.to_string(),
+                        digest.clone(),
+                    )])),
+                    server_side_encryption: self.encryption.server_side_encryption(),
+                    ssekms_key_id: self.encryption.ssekms_key_id(),
+                    ..Default::default()
+                })
             })
             .await?;
 
-        self.client
-            .copy_object(CopyObjectRequest {
-                bucket: self.bucket.clone(),
-                copy_source: format!("{}/{}", self.bucket, key),
-                key: key.clone(),
-                ..Default::default()
-            })
-            .await?;
+        // Also addressable by digest, as the local storage does with a symlink
+        if reference != digest {
+            let digest_key = self.get_manifest_file_path(&name, &digest);
+
+            self.retry
+                .retry(is_transient_error, || {
+                    self.client.copy_object(CopyObjectRequest {
+                        bucket: self.bucket.clone(),
+                        copy_source: format!("{}/{}", self.bucket, key),
+                        key: digest_key.clone(),
+                        server_side_encryption: self.encryption.server_side_encryption(),
+                        ssekms_key_id: self.encryption.ssekms_key_id(),
+                        ..Default::default()
+                    })
+                })
+                .await?;
+        }
 
         Ok(UpdateManifestDetails { digest })
     }
@@ -388,14 +920,559 @@ impl Storage for S3Storage {
     async fn delete_manifest(&self, name: String, reference: String) -> Result<()> {
         let key = self.get_manifest_file_path(&name, &reference);
 
-        self.client
-            .delete_object(DeleteObjectRequest {
-                bucket: self.bucket.clone(),
-                key: key.clone(),
-                ..Default::default()
+        self.retry
+            .retry(is_transient_error, || {
+                self.client.delete_object(DeleteObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    ..Default::default()
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_layer(&self, name: String, digest: String) -> Result<()> {
+        let key = self.get_layer_file_path(&name, &digest);
+
+        self.retry
+            .retry(is_transient_error, || {
+                self.client.delete_object(DeleteObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    ..Default::default()
+                })
+            })
+            .await?;
+
+        let media_type_key = self.get_media_type_file_path(&name, &digest);
+        if self.object_exists(&media_type_key).await? {
+            self.delete_object(&media_type_key).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn set_blob_media_type(
+        &self,
+        name: String,
+        digest: String,
+        media_type: String,
+    ) -> Result<()> {
+        let key = self.get_media_type_file_path(&name, &digest);
+
+        self.retry
+            .retry(is_transient_error, || {
+                self.client.put_object(PutObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    body: Some(media_type.clone().into_bytes().into()),
+                    server_side_encryption: self.encryption.server_side_encryption(),
+                    ssekms_key_id: self.encryption.ssekms_key_id(),
+                    ..Default::default()
+                })
             })
             .await?;
 
         Ok(())
     }
+
+    async fn get_blob_media_type(&self, name: String, digest: String) -> Result<Option<String>> {
+        let key = self.get_media_type_file_path(&name, &digest);
+        if !self.object_exists(&key).await? {
+            return Ok(None);
+        }
+
+        Ok(Some(String::from_utf8(self.get_object_bytes(&key).await?)?))
+    }
+
+    async fn delete_repository(&self, name: String) -> Result<()> {
+        // Any other name could match the keys of other repositories
+        if !is_repository_name(&name) {
+            return Err(Box::new(StorageError::NotFound(format!(
+                "repository '{}'",
+                name
+            ))));
+        }
+
+        let mut keys = Vec::new();
+        for root in ["layers", "manifests", "uploads", "referrers", "media-types"] {
+            let prefix = format!("{}/{}/", root, name);
+            let (objects, _) = self.list_objects(prefix.clone(), None).await?;
+
+            // Those of the repositories nested under this one are kept
+            keys.extend(
+                objects
+                    .into_iter()
+                    .filter_map(|object| object.key)
+                    .filter(|key| key.strip_prefix(&prefix).is_some_and(is_repository_entry)),
+            );
+        }
+
+        if keys.is_empty() {
+            return Err(Box::new(StorageError::NotFound(format!(
+                "repository '{}'",
+                name
+            ))));
+        }
+
+        for key in keys {
+            self.delete_object(&key).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        let mut repositories = Vec::new();
+
+        for root in ["layers/", "manifests/"] {
+            let (_, prefixes) = self
+                .list_objects(root.to_string(), Some("/".to_string()))
+                .await?;
+
+            repositories.extend(prefixes.iter().filter_map(|prefix| {
+                prefix
+                    .strip_prefix(root)
+                    .map(|name| name.trim_end_matches('/').to_string())
+            }));
+        }
+
+        repositories.sort();
+        repositories.dedup();
+        Ok(repositories)
+    }
+
+    async fn list_layers(&self, name: String) -> Result<Vec<String>> {
+        let prefix = format!("layers/{}/", name);
+        let (objects, _) = self.list_objects(prefix.clone(), None).await?;
+
+        Ok(objects
+            .into_iter()
+            .filter_map(|object| object.key)
+            .filter_map(|key| key.strip_prefix(&prefix).map(str::to_string))
+            .collect())
+    }
+
+    async fn list_tags(&self, name: String) -> Result<Vec<String>> {
+        let prefix = format!("manifests/{}/", name);
+        let (objects, _) = self.list_objects(prefix.clone(), None).await?;
+
+        let mut tags = objects
+            .into_iter()
+            .filter_map(|object| object.key)
+            .filter_map(|key| key.strip_prefix(&prefix).map(str::to_string))
+            .filter(|reference| !is_digest(reference))
+            .collect::<Vec<_>>();
+
+        tags.sort();
+        Ok(tags)
+    }
+
+    async fn list_manifests(&self, name: String) -> Result<Vec<String>> {
+        let prefix = format!("manifests/{}/", name);
+        let (objects, _) = self.list_objects(prefix.clone(), None).await?;
+
+        Ok(objects
+            .into_iter()
+            .filter_map(|object| object.key)
+            .filter_map(|key| key.strip_prefix(&prefix).map(str::to_string))
+            .collect())
+    }
+
+    async fn link_manifest_digests(&self, name: String) -> Result<()> {
+        for tag in self.list_tags(name.clone()).await? {
+            let tag_key = self.get_manifest_file_path(&name, &tag);
+            let content = self.get_object_bytes(&tag_key).await?;
+            let key = self.get_manifest_file_path(&name, &self.digest_algorithm.digest(&content));
+
+            if self.object_exists(&key).await? {
+                continue;
+            }
+
+            self.retry
+                .retry(is_transient_error, || {
+                    self.client.copy_object(CopyObjectRequest {
+                        bucket: self.bucket.clone(),
+                        copy_source: format!("{}/{}", self.bucket, tag_key),
+                        key: key.clone(),
+                        server_side_encryption: self.encryption.server_side_encryption(),
+                        ssekms_key_id: self.encryption.ssekms_key_id(),
+                        ..Default::default()
+                    })
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn add_referrer(&self, name: String, subject: String, referrer: Referrer) -> Result<()> {
+        let key = self.get_referrer_file_path(&name, &subject, &referrer.digest);
+        let content = serde_json::to_vec(&referrer)?;
+
+        self.retry
+            .retry(is_transient_error, || {
+                self.client.put_object(PutObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    body: Some(content.clone().into()),
+                    server_side_encryption: self.encryption.server_side_encryption(),
+                    ssekms_key_id: self.encryption.ssekms_key_id(),
+                    ..Default::default()
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn remove_referrer(&self, name: String, subject: String, digest: String) -> Result<()> {
+        self.delete_object(&self.get_referrer_file_path(&name, &subject, &digest))
+            .await
+    }
+
+    async fn list_referrers(&self, name: String, subject: String) -> Result<Vec<Referrer>> {
+        let (objects, _) = self
+            .list_objects(format!("referrers/{}/{}/", name, subject), None)
+            .await?;
+
+        let mut referrers = Vec::new();
+        for key in objects.into_iter().filter_map(|object| object.key) {
+            referrers.push(serde_json::from_slice(&self.get_object_bytes(&key).await?)?);
+        }
+
+        Ok(referrers)
+    }
+
+    async fn clear_referrers(&self, name: String) -> Result<()> {
+        let (objects, _) = self
+            .list_objects(format!("referrers/{}/", name), None)
+            .await?;
+
+        for key in objects.into_iter().filter_map(|object| object.key) {
+            self.delete_object(&key).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn repository_size(&self, name: String) -> Result<u64> {
+        let mut size = 0;
+
+        for root in ["layers", "manifests"] {
+            let prefix = format!("{}/{}/", root, name);
+            let (objects, _) = self.list_objects(prefix.clone(), None).await?;
+
+            // Tagged manifests are copies of the one stored under their
+            // digest, which alone is counted
+            size += objects
+                .iter()
+                .filter(|object| {
+                    root != "manifests"
+                        || object
+                            .key
+                            .as_deref()
+                            .and_then(|key| key.strip_prefix(&prefix))
+                            .is_some_and(is_digest)
+                })
+                .map(|object| object.size.unwrap_or(0) as u64)
+                .sum::<u64>();
+        }
+
+        Ok(size)
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.check_bucket().await
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.digest_algorithm
+    }
+}
+
+#[tokio::test]
+async fn test_upload_layer() -> Result<()> {
+    use std::sync::Arc;
+
+    let storage = Arc::new(super::s3_mock::MockS3::default().storage());
+
+    super::tests::test_upload_layer(storage).await
+}
+
+#[tokio::test]
+async fn test_get_missing_layer() -> Result<()> {
+    use std::sync::Arc;
+
+    let storage = Arc::new(super::s3_mock::MockS3::default().storage());
+
+    super::tests::test_get_missing_layer(storage).await
+}
+
+#[tokio::test]
+async fn test_upload_state_round_trip() -> Result<()> {
+    use std::sync::Arc;
+
+    let storage = Arc::new(super::s3_mock::MockS3::default().storage());
+
+    super::tests::test_upload_state_round_trip(storage).await
+}
+
+#[tokio::test]
+async fn test_list_uploads() -> Result<()> {
+    use std::sync::Arc;
+
+    let storage = Arc::new(super::s3_mock::MockS3::default().storage());
+
+    super::tests::test_list_uploads(storage).await
+}
+
+#[test]
+fn test_parse_timestamp() {
+    assert_eq!(parse_timestamp("1970-01-01T00:00:00.000Z"), Some(0));
+    assert_eq!(
+        parse_timestamp("2009-10-12T17:50:30.000Z"),
+        Some(1255369830)
+    );
+    assert_eq!(parse_timestamp("2024-02-29T12:00:00Z"), Some(1709208000));
+    assert_eq!(parse_timestamp("yesterday"), None);
+}
+
+#[tokio::test]
+async fn test_update_manifest() -> Result<()> {
+    use std::sync::Arc;
+
+    let storage = Arc::new(super::s3_mock::MockS3::default().storage());
+
+    super::tests::test_update_manifest(storage).await
+}
+
+#[tokio::test]
+async fn test_get_manifest_by_tag_and_digest() -> Result<()> {
+    let storage = super::s3_mock::MockS3::default().storage();
+    let name = "test".to_string();
+
+    let details = storage
+        .update_manifest(
+            name.clone(),
+            "latest".to_string(),
+            super::tests::TEST_MANIFEST.as_bytes(),
+        )
+        .await?;
+
+    for reference in ["latest".to_string(), details.digest.clone()] {
+        let manifest = storage.get_manifest(name.clone(), reference).await?;
+        assert_eq!(manifest.digest, details.digest);
+        assert_eq!(manifest.content, super::tests::TEST_MANIFEST.as_bytes());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_manifest_by_digest_without_digest_key() -> Result<()> {
+    let mock = super::s3_mock::MockS3::default();
+    let storage = mock.storage();
+    let name = "test".to_string();
+    let digest = super::tests::sha256_digest(super::tests::TEST_MANIFEST.as_bytes());
+
+    // As stored before manifests were copied to their digest key
+    mock.objects.lock().unwrap().insert(
+        storage.get_manifest_file_path(&name, "latest"),
+        Bytes::from_static(super::tests::TEST_MANIFEST.as_bytes()),
+    );
+
+    // Reads never write, the digest key is only linked explicitly
+    assert!(storage
+        .get_manifest_summary(name.clone(), digest.clone())
+        .await
+        .is_err());
+    assert!(!mock
+        .objects
+        .lock()
+        .unwrap()
+        .contains_key(&storage.get_manifest_file_path(&name, &digest)));
+
+    storage.link_manifest_digests(name.clone()).await?;
+
+    let summary = storage
+        .get_manifest_summary(name.clone(), digest.clone())
+        .await?;
+    assert_eq!(summary.digest, digest);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_tags() -> Result<()> {
+    use std::sync::Arc;
+
+    let storage = Arc::new(super::s3_mock::MockS3::default().storage());
+
+    super::tests::test_list_tags(storage).await
+}
+
+#[tokio::test]
+async fn test_referrers() -> Result<()> {
+    use std::sync::Arc;
+
+    let storage = Arc::new(super::s3_mock::MockS3::default().storage());
+
+    super::tests::test_referrers(storage).await
+}
+
+#[tokio::test]
+async fn test_delete_repository() -> Result<()> {
+    use std::sync::Arc;
+
+    let storage = Arc::new(super::s3_mock::MockS3::default().storage());
+
+    super::tests::test_delete_repository(storage).await
+}
+
+#[tokio::test]
+async fn test_delete_nested_repository() -> Result<()> {
+    use std::sync::Arc;
+
+    let storage = Arc::new(super::s3_mock::MockS3::default().storage());
+
+    super::tests::test_delete_nested_repository(storage).await
+}
+
+#[tokio::test]
+async fn test_repository_size() -> Result<()> {
+    use std::sync::Arc;
+
+    let storage = Arc::new(super::s3_mock::MockS3::default().storage());
+
+    super::tests::test_repository_size(storage).await
+}
+
+#[tokio::test]
+async fn test_verify_blob() -> Result<()> {
+    use std::sync::Arc;
+
+    let mock = super::s3_mock::MockS3::default();
+    let storage = Arc::new(mock.storage());
+
+    let digest = super::tests::test_verify_blob(storage.clone()).await?;
+
+    mock.objects.lock().unwrap().insert(
+        storage.get_layer_file_path("test", &digest),
+        Bytes::from_static(b"corrupted"),
+    );
+
+    let verification = storage.verify_blob("test".to_string(), digest).await?;
+    assert!(!verification.is_valid());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_objects_written_with_encryption() -> Result<()> {
+    use std::sync::Arc;
+
+    let mock = super::s3_mock::MockS3::default();
+    let mut storage = mock.storage();
+    storage.encryption = S3Encryption::Kms {
+        key_id: Some("registry-key".to_string()),
+    };
+    let storage: Arc<dyn Storage> = Arc::new(storage);
+
+    super::tests::upload_layer(&storage, "test", b"secret layer").await?;
+    storage
+        .update_manifest(
+            "test".to_string(),
+            "latest".to_string(),
+            super::tests::TEST_MANIFEST.as_bytes(),
+        )
+        .await?;
+
+    let encryption = mock.encryption.lock().unwrap();
+    let objects = mock.objects.lock().unwrap();
+    assert!(!objects.is_empty());
+    for key in objects.keys() {
+        assert_eq!(
+            encryption.get(key),
+            Some(&("aws:kms".to_string(), Some("registry-key".to_string()))),
+            "{}",
+            key
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_small_chunks_buffered_into_parts() -> Result<()> {
+    let mock = super::s3_mock::MockS3::default();
+    let mut storage = mock.storage();
+    storage.set_multipart_part_size(MIN_MULTIPART_PART_SIZE)?;
+
+    let name = "test".to_string();
+    let upload_container = storage.create_upload_container(name.clone()).await?;
+    let upload_key = storage.get_upload_file_path(&name, &upload_container.uuid);
+
+    // Chunks well under the S3 minimum, each pushed on its own request
+    let chunk = vec![7u8; 1024 * 1024];
+    let mut content = Vec::new();
+    for _ in 0..7 {
+        let stream = futures::stream::iter(vec![Ok(Bytes::from(chunk.clone()))]);
+        let start = content.len() as u64;
+        content.extend_from_slice(&chunk);
+
+        let status = storage
+            .write_upload_container(
+                name.clone(),
+                upload_container.uuid.clone(),
+                Box::pin(stream),
+                (start, content.len() as u64),
+            )
+            .await?;
+        assert_eq!(status.size, content.len() as u64);
+    }
+
+    let parts = storage.list_upload_parts(&upload_key).await?;
+    assert_eq!(
+        parts.iter().map(|(_, size)| *size).collect::<Vec<_>>(),
+        vec![MIN_MULTIPART_PART_SIZE as u64, 2 * 1024 * 1024]
+    );
+
+    let details = storage
+        .close_upload_container(name.clone(), upload_container.uuid, None)
+        .await?;
+    assert_eq!(details.digest, super::tests::sha256_digest(&content));
+
+    let objects = mock.objects.lock().unwrap();
+    assert_eq!(
+        objects.get(&storage.get_layer_file_path(&name, &details.digest)),
+        Some(&Bytes::from(content))
+    );
+    assert!(!objects.keys().any(|key| key.starts_with("uploads/")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_bucket() -> Result<()> {
+    let storage = super::s3_mock::MockS3::default().storage();
+    storage.check_bucket().await?;
+
+    let other = S3Storage::with_client("missing", storage.region.clone(), storage.client.clone());
+    assert!(other.check_bucket().await.is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_multipart_part_size_minimum() {
+    let mut storage = super::s3_mock::MockS3::default().storage();
+    assert_eq!(storage.multipart_part_size(), DEFAULT_MULTIPART_PART_SIZE);
+
+    assert!(storage
+        .set_multipart_part_size(MIN_MULTIPART_PART_SIZE - 1)
+        .is_err());
+    assert_eq!(storage.multipart_part_size(), DEFAULT_MULTIPART_PART_SIZE);
 }
@@ -0,0 +1,355 @@
+//! In-memory stand-in for an S3 endpoint, used to exercise `S3Storage` in tests.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+use hyper::{header::HeaderName, HeaderMap, StatusCode};
+use rusoto_core::{
+    credential::StaticProvider,
+    request::{DispatchSignedRequestFuture, HttpResponse},
+    signature::{SignedRequest, SignedRequestPayload},
+    ByteStream, DispatchSignedRequest, Region,
+};
+use rusoto_s3::S3Client;
+
+use super::S3Storage;
+
+pub const BUCKET: &str = "rustgistry";
+
+/// Server-side encryption algorithm and KMS key an object was written with.
+pub type ObjectEncryption = (String, Option<String>);
+
+/// Key, encryption and parts so far of a multipart upload in progress.
+type MultipartUpload = (String, Option<ObjectEncryption>, BTreeMap<i64, Bytes>);
+
+/// `x-amz-meta-*` headers an object was written with.
+type ObjectMetadata = Vec<(String, String)>;
+
+#[derive(Clone, Default)]
+pub struct MockS3 {
+    pub objects: Arc<Mutex<BTreeMap<String, Bytes>>>,
+    pub encryption: Arc<Mutex<BTreeMap<String, ObjectEncryption>>>,
+    pub multipart_uploads: Arc<Mutex<BTreeMap<String, MultipartUpload>>>,
+    pub metadata: Arc<Mutex<BTreeMap<String, ObjectMetadata>>>,
+    /// Method and key of every request received, in order.
+    pub requests: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl MockS3 {
+    pub fn storage(&self) -> S3Storage {
+        let region = Region::Custom {
+            name: "us-east-1".to_string(),
+            endpoint: "http://localhost".to_string(),
+        };
+
+        let client = S3Client::new_with(
+            self.clone(),
+            StaticProvider::new_minimal("access".to_string(), "secret".to_string()),
+            region.clone(),
+        );
+
+        S3Storage::with_client(BUCKET, region, client)
+    }
+}
+
+fn response(status: StatusCode, body: Bytes) -> HttpResponse {
+    let mut headers = HeaderMap::<String>::default();
+    headers.insert("content-length", body.len().to_string());
+    headers.insert("etag", "\"mock\"".to_string());
+
+    HttpResponse {
+        status,
+        body: ByteStream::from(body.to_vec()),
+        headers,
+    }
+}
+
+fn no_such_key() -> HttpResponse {
+    response(
+        StatusCode::NOT_FOUND,
+        Bytes::from_static(
+            b"<Error><Code>NoSuchKey</Code><Message>The specified key does not exist.</Message></Error>",
+        ),
+    )
+}
+
+fn list_objects(
+    objects: &BTreeMap<String, Bytes>,
+    prefix: &str,
+    delimiter: Option<&str>,
+) -> HttpResponse {
+    let mut contents = String::new();
+    let mut prefixes = BTreeSet::new();
+
+    for (key, bytes) in objects.range(prefix.to_string()..) {
+        let rest = match key.strip_prefix(prefix) {
+            Some(rest) => rest,
+            None => break,
+        };
+
+        match delimiter.and_then(|delimiter| rest.find(delimiter).map(|i| i + delimiter.len())) {
+            Some(end) => {
+                prefixes.insert(format!("{}{}", prefix, &rest[..end]));
+            }
+            None => contents.push_str(&format!(
+                "<Contents><Key>{}</Key><Size>{}</Size></Contents>",
+                key,
+                bytes.len()
+            )),
+        }
+    }
+
+    let prefixes = prefixes
+        .iter()
+        .map(|prefix| {
+            format!(
+                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                prefix
+            )
+        })
+        .collect::<String>();
+
+    response(
+        StatusCode::OK,
+        Bytes::from(format!(
+            "<ListBucketResult><Name>{}</Name><IsTruncated>false</IsTruncated>{}{}</ListBucketResult>",
+            BUCKET, contents, prefixes
+        )),
+    )
+}
+
+impl DispatchSignedRequest for MockS3 {
+    fn dispatch(
+        &self,
+        request: SignedRequest,
+        _timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        let objects = Arc::clone(&self.objects);
+        let encryption = Arc::clone(&self.encryption);
+        let multipart_uploads = Arc::clone(&self.multipart_uploads);
+        let metadata = Arc::clone(&self.metadata);
+        let requests = Arc::clone(&self.requests);
+
+        Box::pin(async move {
+            let key = request
+                .path
+                .trim_start_matches('/')
+                .trim_start_matches(BUCKET)
+                .trim_start_matches('/')
+                .to_string();
+
+            requests
+                .lock()
+                .unwrap()
+                .push((request.method.clone(), key.clone()));
+
+            let header = |name: &str| {
+                request
+                    .headers
+                    .get(name)
+                    .and_then(|values| values.first())
+                    .map(|value| String::from_utf8_lossy(value).to_string())
+            };
+
+            let param = |name: &str| request.params.get(name).cloned().flatten();
+            let upload_id = param("uploadId");
+
+            let object_encryption = header("x-amz-server-side-encryption").map(|algorithm| {
+                (
+                    algorithm,
+                    header("x-amz-server-side-encryption-aws-kms-key-id"),
+                )
+            });
+
+            if request.method == "PUT" && upload_id.is_none() {
+                let mut encryption = encryption.lock().unwrap();
+                match object_encryption.clone() {
+                    Some(object_encryption) => encryption.insert(key.clone(), object_encryption),
+                    None => encryption.remove(&key),
+                };
+
+                let object_metadata = request
+                    .headers
+                    .iter()
+                    .filter(|(name, _)| name.starts_with("x-amz-meta-"))
+                    .filter_map(|(name, values)| {
+                        let value = values.first()?;
+                        Some((name.clone(), String::from_utf8_lossy(value).to_string()))
+                    })
+                    .collect::<Vec<_>>();
+                metadata
+                    .lock()
+                    .unwrap()
+                    .insert(key.clone(), object_metadata);
+            }
+
+            let copy_source = request
+                .headers
+                .get("x-amz-copy-source")
+                .and_then(|values| values.first())
+                .map(|value| String::from_utf8_lossy(value).to_string());
+
+            let response = match (request.method.as_str(), copy_source) {
+                ("POST", _) if request.params.contains_key("uploads") => {
+                    let mut multipart_uploads = multipart_uploads.lock().unwrap();
+                    let upload_id = format!("upload-{}", multipart_uploads.len());
+                    multipart_uploads
+                        .insert(upload_id.clone(), (key, object_encryption, BTreeMap::new()));
+
+                    response(
+                        StatusCode::OK,
+                        Bytes::from(format!(
+                            "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                            upload_id
+                        )),
+                    )
+                }
+                ("PUT", Some(source)) if upload_id.is_some() => {
+                    let source = source.trim_start_matches(&format!("{}/", BUCKET));
+                    let part_number = param("partNumber")
+                        .and_then(|part_number| part_number.parse().ok())
+                        .unwrap_or_default();
+
+                    let bytes = match objects.lock().unwrap().get(source).cloned() {
+                        Some(bytes) => bytes,
+                        None => return Ok(no_such_key()),
+                    };
+                    match multipart_uploads
+                        .lock()
+                        .unwrap()
+                        .get_mut(&upload_id.unwrap_or_default())
+                    {
+                        Some((_, _, parts)) => {
+                            parts.insert(part_number, bytes);
+                            response(
+                                StatusCode::OK,
+                                Bytes::from_static(
+                                    b"<CopyPartResult><ETag>\"mock\"</ETag></CopyPartResult>",
+                                ),
+                            )
+                        }
+                        None => response(StatusCode::NOT_FOUND, Bytes::new()),
+                    }
+                }
+                ("POST", _) if upload_id.is_some() => {
+                    match multipart_uploads
+                        .lock()
+                        .unwrap()
+                        .remove(&upload_id.unwrap_or_default())
+                    {
+                        Some((key, object_encryption, parts)) => {
+                            let bytes = parts.values().flatten().copied().collect::<Vec<u8>>();
+                            objects
+                                .lock()
+                                .unwrap()
+                                .insert(key.clone(), Bytes::from(bytes));
+
+                            let mut encryption = encryption.lock().unwrap();
+                            match object_encryption {
+                                Some(object_encryption) => {
+                                    encryption.insert(key, object_encryption)
+                                }
+                                None => encryption.remove(&key),
+                            };
+
+                            response(
+                                StatusCode::OK,
+                                Bytes::from_static(
+                                    b"<CompleteMultipartUploadResult><ETag>\"mock\"</ETag></CompleteMultipartUploadResult>",
+                                ),
+                            )
+                        }
+                        None => response(StatusCode::NOT_FOUND, Bytes::new()),
+                    }
+                }
+                ("DELETE", _) if upload_id.is_some() => {
+                    multipart_uploads
+                        .lock()
+                        .unwrap()
+                        .remove(&upload_id.unwrap_or_default());
+                    response(StatusCode::NO_CONTENT, Bytes::new())
+                }
+                ("PUT", Some(source)) => {
+                    let source = source.trim_start_matches(&format!("{}/", BUCKET));
+                    let mut objects = objects.lock().unwrap();
+                    match objects.get(source).cloned() {
+                        Some(bytes) => {
+                            // Copies keep the metadata of their source
+                            let mut metadata = metadata.lock().unwrap();
+                            let source_metadata = metadata.get(source).cloned().unwrap_or_default();
+                            metadata.insert(key.clone(), source_metadata);
+
+                            objects.insert(key, bytes);
+                            response(
+                                StatusCode::OK,
+                                Bytes::from_static(
+                                    b"<CopyObjectResult><ETag>\"mock\"</ETag></CopyObjectResult>",
+                                ),
+                            )
+                        }
+                        None => no_such_key(),
+                    }
+                }
+                ("PUT", None) => {
+                    let bytes = match request.payload {
+                        Some(SignedRequestPayload::Buffer(bytes)) => bytes,
+                        Some(SignedRequestPayload::Stream(stream)) => Bytes::from(
+                            stream
+                                .map_ok(|bytes| bytes.to_vec())
+                                .try_concat()
+                                .await
+                                .unwrap_or_default(),
+                        ),
+                        None => Bytes::new(),
+                    };
+
+                    objects.lock().unwrap().insert(key, bytes);
+                    response(StatusCode::OK, Bytes::new())
+                }
+                ("GET", _) if request.params.contains_key("list-type") => list_objects(
+                    &objects.lock().unwrap(),
+                    &param("prefix").unwrap_or_default(),
+                    param("delimiter").as_deref(),
+                ),
+                ("GET", _) => match objects.lock().unwrap().get(&key) {
+                    Some(bytes) => response(StatusCode::OK, bytes.clone()),
+                    None => no_such_key(),
+                },
+                // Only the mock bucket exists
+                ("HEAD", _) if key.is_empty() && request.path.trim_matches('/') == BUCKET => {
+                    response(StatusCode::OK, Bytes::new())
+                }
+                ("HEAD", _) => match objects.lock().unwrap().get(&key) {
+                    Some(bytes) => {
+                        let mut response = response(StatusCode::OK, Bytes::new());
+                        response
+                            .headers
+                            .insert("content-length", bytes.len().to_string());
+                        for (name, value) in
+                            metadata.lock().unwrap().get(&key).into_iter().flatten()
+                        {
+                            if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+                                response.headers.insert(name, value.clone());
+                            }
+                        }
+                        response
+                    }
+                    // S3 doesn't send an error document with HEAD responses
+                    None => response(StatusCode::NOT_FOUND, Bytes::new()),
+                },
+                ("DELETE", _) => {
+                    objects.lock().unwrap().remove(&key);
+                    response(StatusCode::NO_CONTENT, Bytes::new())
+                }
+                _ => response(StatusCode::NOT_IMPLEMENTED, Bytes::new()),
+            };
+
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,423 @@
+use std::{pin::Pin, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+use lru::LruCache;
+use tokio::sync::Mutex;
+
+use super::{
+    base::{ImageLayerInfo, Result, Storage, UploadContainer},
+    is_not_found,
+    types::referrer::Referrer,
+    DigestAlgorithm, ManifestDetails, ManifestSummary, UpdateManifestDetails, UploadDetails,
+    UploadInfo, UploadStatus,
+};
+
+/// Size of the layers held by the cache, in least recently used order.
+struct CacheEntries {
+    layers: LruCache<(String, String), u64>,
+    size: u64,
+}
+
+/// Fronts a slow authoritative storage with a fast cache storage holding
+/// recently pulled layers.
+///
+/// Layers are content addressed and never change once written, so a cached
+/// layer is always up to date. Manifests, uploads and listings are only ever
+/// served by the backend: a tag pushed to the backend is visible to the next
+/// pull, at the cost of paying the backend latency on every manifest request.
+///
+/// Layers already in the cache when the storage is created are only accounted
+/// for, and thus evicted, once they are pulled again.
+pub struct TieredStorage {
+    cache: Arc<dyn Storage>,
+    backend: Arc<dyn Storage>,
+    /// Number of bytes of layers the cache may hold before the least recently
+    /// pulled ones are evicted.
+    pub max_cache_size: u64,
+    /// Also store freshly pushed layers in the cache.
+    pub write_through: bool,
+    entries: Mutex<CacheEntries>,
+}
+
+impl CacheEntries {
+    /// Forgets the layers of repository `name`.
+    fn forget_repository(&mut self, name: &str) {
+        let layers = self
+            .layers
+            .iter()
+            .filter(|((layer_name, _), _)| layer_name == name)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in layers {
+            if let Some(size) = self.layers.pop(&key) {
+                self.size -= size;
+            }
+        }
+    }
+}
+
+impl TieredStorage {
+    pub fn new(
+        cache: Arc<dyn Storage>,
+        backend: Arc<dyn Storage>,
+        max_cache_size: u64,
+    ) -> TieredStorage {
+        TieredStorage {
+            cache,
+            backend,
+            max_cache_size,
+            write_through: false,
+            entries: Mutex::new(CacheEntries {
+                layers: LruCache::unbounded(),
+                size: 0,
+            }),
+        }
+    }
+
+    /// Marks a cached layer as the most recently used one, evicting the least
+    /// recently used layers past `max_cache_size`.
+    async fn touch(&self, name: String, digest: String, size: u64) -> Result<()> {
+        let mut entries = self.entries.lock().await;
+
+        if let Some(previous) = entries.layers.put((name, digest), size) {
+            entries.size -= previous;
+        }
+        entries.size += size;
+
+        while entries.size > self.max_cache_size && entries.layers.len() > 1 {
+            let ((name, digest), size) = match entries.layers.pop_lru() {
+                Some(entry) => entry,
+                None => break,
+            };
+            entries.size -= size;
+
+            self.cache.delete_layer(name, digest).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies a layer from the backend to the cache.
+    async fn populate(&self, name: String, digest: String) -> Result<()> {
+        let stream = self.backend.get_layer(name.clone(), digest.clone()).await?;
+
+        let upload_container = self.cache.create_upload_container(name.clone()).await?;
+        let status = self
+            .cache
+            .write_upload_container(name.clone(), upload_container.uuid.clone(), stream, (0, 0))
+            .await?;
+        self.cache
+            .close_upload_container(name.clone(), upload_container.uuid, Some(digest.clone()))
+            .await?;
+
+        self.touch(name, digest, status.size).await
+    }
+}
+
+#[async_trait]
+impl Storage for TieredStorage {
+    async fn get_image_layer_info(
+        &self,
+        name: String,
+        digest: String,
+    ) -> Result<Option<ImageLayerInfo>> {
+        match self
+            .cache
+            .get_image_layer_info(name.clone(), digest.clone())
+            .await?
+        {
+            Some(info) => Ok(Some(info)),
+            None => self.backend.get_image_layer_info(name, digest).await,
+        }
+    }
+
+    async fn blob_exists(&self, name: String, digest: String) -> Result<bool> {
+        Ok(self.cache.blob_exists(name.clone(), digest.clone()).await?
+            || self.backend.blob_exists(name, digest).await?)
+    }
+
+    async fn get_shared_blob_size(&self, digest: String) -> Result<Option<u64>> {
+        self.backend.get_shared_blob_size(digest).await
+    }
+
+    async fn get_layer(
+        &self,
+        name: String,
+        digest: String,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>> {
+        let cached = self
+            .cache
+            .get_image_layer_info(name.clone(), digest.clone())
+            .await?;
+
+        match cached {
+            Some(info) => self.touch(name.clone(), digest.clone(), info.size).await?,
+            None => {
+                if let Err(e) = self.populate(name.clone(), digest.clone()).await {
+                    eprintln!("{}", e);
+                    return self.backend.get_layer(name, digest).await;
+                }
+            }
+        }
+
+        self.cache.get_layer(name, digest).await
+    }
+
+    async fn create_upload_container(&self, name: String) -> Result<UploadContainer> {
+        self.backend.create_upload_container(name).await
+    }
+
+    async fn check_upload_container_validity(&self, name: String, uuid: String) -> Result<bool> {
+        self.backend
+            .check_upload_container_validity(name, uuid)
+            .await
+    }
+
+    async fn write_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        stream: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
+        range: (u64, u64),
+    ) -> Result<UploadStatus> {
+        self.backend
+            .write_upload_container(name, uuid, stream, range)
+            .await
+    }
+
+    async fn get_upload_status(&self, name: String, uuid: String) -> Result<UploadStatus> {
+        self.backend.get_upload_status(name, uuid).await
+    }
+
+    async fn list_uploads(&self, name: Option<String>) -> Result<Vec<UploadInfo>> {
+        self.backend.list_uploads(name).await
+    }
+
+    async fn delete_upload_container(&self, name: String, uuid: String) -> Result<()> {
+        self.backend.delete_upload_container(name, uuid).await
+    }
+
+    async fn close_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: Option<String>,
+    ) -> Result<UploadDetails> {
+        let details = self
+            .backend
+            .close_upload_container(name.clone(), uuid, digest)
+            .await?;
+
+        if self.write_through {
+            if let Err(e) = self.populate(name, details.digest.clone()).await {
+                eprintln!("{}", e);
+            }
+        }
+
+        Ok(details)
+    }
+
+    async fn store_upload_container(
+        &self,
+        name: String,
+        uuid: String,
+        digest: String,
+    ) -> Result<UploadDetails> {
+        self.backend
+            .store_upload_container(name, uuid, digest)
+            .await
+    }
+
+    async fn get_manifest_summary(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<ManifestSummary> {
+        self.backend.get_manifest_summary(name, reference).await
+    }
+
+    async fn manifest_exists(&self, name: String, reference: String) -> Result<bool> {
+        self.backend.manifest_exists(name, reference).await
+    }
+
+    async fn get_manifest(&self, name: String, reference: String) -> Result<ManifestDetails> {
+        self.backend.get_manifest(name, reference).await
+    }
+
+    async fn get_manifest_raw(
+        &self,
+        name: String,
+        reference: String,
+    ) -> Result<(Bytes, String, String)> {
+        self.backend.get_manifest_raw(name, reference).await
+    }
+
+    async fn update_manifest(
+        &self,
+        name: String,
+        reference: String,
+        content: &[u8],
+    ) -> Result<UpdateManifestDetails> {
+        self.backend.update_manifest(name, reference, content).await
+    }
+
+    async fn delete_manifest(&self, name: String, reference: String) -> Result<()> {
+        self.backend.delete_manifest(name, reference).await
+    }
+
+    async fn delete_layer(&self, name: String, digest: String) -> Result<()> {
+        let cached = {
+            let mut entries = self.entries.lock().await;
+            let size = entries.layers.pop(&(name.clone(), digest.clone()));
+            if let Some(size) = size {
+                entries.size -= size;
+            }
+            size.is_some()
+        };
+
+        if cached || self.cache.blob_exists(name.clone(), digest.clone()).await? {
+            self.cache
+                .delete_layer(name.clone(), digest.clone())
+                .await?;
+        }
+
+        self.backend.delete_layer(name, digest).await
+    }
+
+    async fn set_blob_media_type(
+        &self,
+        name: String,
+        digest: String,
+        media_type: String,
+    ) -> Result<()> {
+        self.backend
+            .set_blob_media_type(name, digest, media_type)
+            .await
+    }
+
+    async fn get_blob_media_type(&self, name: String, digest: String) -> Result<Option<String>> {
+        self.backend.get_blob_media_type(name, digest).await
+    }
+
+    async fn delete_repository(&self, name: String) -> Result<()> {
+        self.entries.lock().await.forget_repository(&name);
+
+        if let Err(e) = self.cache.delete_repository(name.clone()).await {
+            if !is_not_found(&e) {
+                return Err(e);
+            }
+        }
+
+        self.backend.delete_repository(name).await
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        self.backend.list_repositories().await
+    }
+
+    async fn list_layers(&self, name: String) -> Result<Vec<String>> {
+        self.backend.list_layers(name).await
+    }
+
+    async fn list_tags(&self, name: String) -> Result<Vec<String>> {
+        self.backend.list_tags(name).await
+    }
+
+    async fn list_manifests(&self, name: String) -> Result<Vec<String>> {
+        self.backend.list_manifests(name).await
+    }
+
+    async fn add_referrer(&self, name: String, subject: String, referrer: Referrer) -> Result<()> {
+        self.backend.add_referrer(name, subject, referrer).await
+    }
+
+    async fn remove_referrer(&self, name: String, subject: String, digest: String) -> Result<()> {
+        self.backend.remove_referrer(name, subject, digest).await
+    }
+
+    async fn list_referrers(&self, name: String, subject: String) -> Result<Vec<Referrer>> {
+        self.backend.list_referrers(name, subject).await
+    }
+
+    async fn clear_referrers(&self, name: String) -> Result<()> {
+        self.backend.clear_referrers(name).await
+    }
+
+    async fn link_manifest_digests(&self, name: String) -> Result<()> {
+        self.backend.link_manifest_digests(name).await
+    }
+
+    async fn repository_size(&self, name: String) -> Result<u64> {
+        self.backend.repository_size(name).await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.cache.health_check().await?;
+        self.backend.health_check().await
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.backend.digest_algorithm()
+    }
+}
+
+#[tokio::test]
+async fn test_second_pull_served_from_cache() -> Result<()> {
+    use futures::TryStreamExt;
+
+    use super::{s3_mock::MockS3, LocalStorage};
+
+    let temp_dir = tempfile::tempdir()?;
+    let mock = MockS3::default();
+    let backend: Arc<dyn Storage> = Arc::new(mock.storage());
+    let storage: Arc<dyn Storage> = Arc::new(TieredStorage::new(
+        Arc::new(LocalStorage::new(temp_dir.path())),
+        backend.clone(),
+        1024,
+    ));
+
+    let digest = super::tests::upload_layer(&backend, "test", b"hot layer").await?;
+
+    let pull = || async {
+        let layer = storage
+            .get_layer("test".to_string(), digest.clone())
+            .await?;
+        layer.map_ok(|bytes| bytes.to_vec()).try_concat().await
+    };
+
+    assert_eq!(pull().await?, b"hot layer");
+
+    // The backend lost the layer, so it can only come from the cache now
+    mock.objects.lock().unwrap().clear();
+    assert_eq!(pull().await?, b"hot layer");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cache_evicts_least_recently_pulled_layers() -> Result<()> {
+    use super::LocalStorage;
+
+    let cache_dir = tempfile::tempdir()?;
+    let backend_dir = tempfile::tempdir()?;
+    let cache: Arc<dyn Storage> = Arc::new(LocalStorage::new(cache_dir.path()));
+    let backend: Arc<dyn Storage> = Arc::new(LocalStorage::new(backend_dir.path()));
+    let storage = TieredStorage::new(cache.clone(), backend.clone(), 10);
+
+    let first = super::tests::upload_layer(&backend, "test", b"first").await?;
+    let second = super::tests::upload_layer(&backend, "test", b"second").await?;
+
+    drop(storage.get_layer("test".to_string(), first.clone()).await?);
+    drop(
+        storage
+            .get_layer("test".to_string(), second.clone())
+            .await?,
+    );
+
+    assert_eq!(cache.list_layers("test".to_string()).await?, vec![second]);
+
+    Ok(())
+}
@@ -1,20 +1,83 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const OCI_IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const OCI_IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+/// Media type of the `{}` descriptor artifacts without a config point to.
+pub const OCI_EMPTY_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+
+pub type Annotations = BTreeMap<String, String>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Manifest {
     #[serde(rename = "schemaVersion")]
     pub schema_version: u32,
 
-    #[serde(rename = "mediaType")]
-    pub media_type: String,
+    /// Optional for OCI manifests, required for Docker ones.
+    #[serde(default, rename = "mediaType", skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
 
-    pub config: ManifestConfig,
+    /// Image manifests have a config, indexes and manifest lists don't.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<ManifestConfig>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub manifests: Option<Vec<ManifestEntry>>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub layers: Option<Vec<LayerEntry>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Annotations>,
+
+    /// Manifest this one refers to, e.g. the image a signature is about.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<ManifestEntry>,
+
+    #[serde(
+        default,
+        rename = "artifactType",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub artifact_type: Option<String>,
+
+    /// Fields not modeled above, kept so the manifest survives a round trip.
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl Manifest {
+    /// Media type to serve the manifest with, inferred from its shape when the
+    /// document doesn't declare it.
+    pub fn content_type(&self) -> &str {
+        match &self.media_type {
+            Some(media_type) => media_type,
+            None if self.manifests.is_some() => OCI_IMAGE_INDEX_MEDIA_TYPE,
+            None => OCI_IMAGE_MANIFEST_MEDIA_TYPE,
+        }
+    }
+}
+
+/// Media type to serve manifest `content` with, as `Manifest::content_type`
+/// infers it but without modeling the rest of the document.
+pub fn manifest_content_type(content: &[u8]) -> serde_json::Result<String> {
+    #[derive(Deserialize)]
+    struct Shape {
+        #[serde(default, rename = "mediaType")]
+        media_type: Option<String>,
+        #[serde(default)]
+        manifests: Option<serde::de::IgnoredAny>,
+    }
+
+    let shape = serde_json::from_slice::<Shape>(content)?;
+
+    Ok(match shape.media_type {
+        Some(media_type) => media_type,
+        None if shape.manifests.is_some() => OCI_IMAGE_INDEX_MEDIA_TYPE.to_string(),
+        None => OCI_IMAGE_MANIFEST_MEDIA_TYPE.to_string(),
+    })
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +88,20 @@ pub struct ManifestConfig {
     pub size: u64,
 
     pub digest: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Annotations>,
+
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl ManifestConfig {
+    /// Whether this is the empty descriptor of an artifact, whose blob
+    /// clients aren't required to push.
+    pub fn is_empty(&self) -> bool {
+        self.media_type == OCI_EMPTY_MEDIA_TYPE && self.size <= 2
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,12 +109,18 @@ pub struct ManifestEntry {
     #[serde(rename = "mediaType")]
     pub media_type: String,
 
-    pub size: u32,
+    pub size: u64,
 
     pub digest: String,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub platform: Option<Platform>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Annotations>,
+
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,15 +128,102 @@ pub struct LayerEntry {
     #[serde(rename = "mediaType")]
     pub media_type: String,
 
-    pub size: u32,
+    pub size: u64,
 
     pub digest: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Annotations>,
+
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Platform {
     pub architecture: String,
     pub os: String,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub features: Option<Vec<String>>,
+
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+#[test]
+fn test_manifest_annotations_round_trip() -> serde_json::Result<()> {
+    let json = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_IMAGE_MANIFEST_MEDIA_TYPE,
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": 7023,
+            "digest": "sha256:b5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7",
+        },
+        "layers": [{
+            "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+            "size": 32654,
+            "digest": "sha256:9834876dcfb05cb167a5c24953eba58c4ac89b1adf57f28f2f9d09af107ee8f0",
+            "annotations": { "org.opencontainers.image.title": "layer.tar.gz" },
+            "urls": ["https://example.com/layer.tar.gz"],
+        }],
+        "annotations": { "org.opencontainers.image.created": "2023-01-01T00:00:00Z" },
+        "subject": {
+            "mediaType": OCI_IMAGE_MANIFEST_MEDIA_TYPE,
+            "size": 1234,
+            "digest": "sha256:5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270",
+        },
+    });
+
+    let manifest: Manifest = serde_json::from_value(json.clone())?;
+
+    let layers = manifest.layers.as_ref().unwrap();
+    assert_eq!(
+        layers[0].annotations.as_ref().unwrap()["org.opencontainers.image.title"],
+        "layer.tar.gz"
+    );
+    assert_eq!(
+        manifest.annotations.as_ref().unwrap()["org.opencontainers.image.created"],
+        "2023-01-01T00:00:00Z"
+    );
+    assert_eq!(
+        manifest.subject.as_ref().unwrap().digest,
+        "sha256:5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270"
+    );
+
+    assert_eq!(serde_json::to_value(&manifest)?, json);
+
+    Ok(())
+}
+
+#[test]
+fn test_artifact_manifest_round_trip() -> serde_json::Result<()> {
+    let json = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_IMAGE_MANIFEST_MEDIA_TYPE,
+        "artifactType": "application/vnd.example.sbom+json",
+        "config": {
+            "mediaType": OCI_EMPTY_MEDIA_TYPE,
+            "size": 2,
+            "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+            "data": "e30=",
+        },
+        "layers": [{
+            "mediaType": "application/spdx+json",
+            "size": 1024,
+            "digest": "sha256:9834876dcfb05cb167a5c24953eba58c4ac89b1adf57f28f2f9d09af107ee8f0",
+        }],
+    });
+
+    let manifest: Manifest = serde_json::from_value(json.clone())?;
+
+    assert_eq!(
+        manifest.artifact_type.as_deref(),
+        Some("application/vnd.example.sbom+json")
+    );
+    assert!(manifest.config.as_ref().unwrap().is_empty());
+
+    assert_eq!(serde_json::to_value(&manifest)?, json);
+
+    Ok(())
 }
@@ -1 +1,3 @@
 pub mod manifest;
+pub mod referrer;
+pub mod schema1;
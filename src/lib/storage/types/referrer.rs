@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+
+use super::manifest::Manifest;
+
+/// Entry of the referrers index, describing a manifest whose `subject` is
+/// another manifest. Serialized as the descriptor the referrers API lists.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Referrer {
+    #[serde(rename = "mediaType")]
+    pub media_type: String,
+
+    pub size: u64,
+
+    pub digest: String,
+
+    #[serde(
+        default,
+        rename = "artifactType",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub artifact_type: Option<String>,
+}
+
+impl Referrer {
+    /// Describes `manifest`, stored as `size` bytes under `digest`.
+    pub fn new(manifest: &Manifest, digest: String, size: u64) -> Referrer {
+        // Manifests without an explicit artifact type are typed by their config
+        let artifact_type = manifest.artifact_type.clone().or_else(|| {
+            manifest
+                .config
+                .as_ref()
+                .map(|config| config.media_type.clone())
+        });
+
+        Referrer {
+            media_type: manifest.content_type().to_string(),
+            size,
+            digest,
+            artifact_type,
+        }
+    }
+}
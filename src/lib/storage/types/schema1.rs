@@ -0,0 +1,141 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use super::manifest::Manifest;
+
+pub const SCHEMA1_MANIFEST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.v1+json";
+pub const SCHEMA1_SIGNED_MANIFEST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.v1+prettyjws";
+
+/// Deprecated Docker image manifest, only produced for legacy clients.
+#[derive(Debug, Clone, Serialize)]
+pub struct Schema1Manifest {
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: u32,
+
+    pub name: String,
+
+    pub tag: String,
+
+    pub architecture: String,
+
+    /// Layers from the top one down to the base one.
+    #[serde(rename = "fsLayers")]
+    pub fs_layers: Vec<FsLayer>,
+
+    /// One entry per layer, in the order of `fs_layers`.
+    pub history: Vec<History>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FsLayer {
+    #[serde(rename = "blobSum")]
+    pub blob_sum: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct History {
+    /// JSON document describing the layer as a v1 image.
+    #[serde(rename = "v1Compatibility")]
+    pub v1_compatibility: String,
+}
+
+impl Schema1Manifest {
+    /// Best-effort translation of image manifest `manifest`, whose image
+    /// configuration is `config`, to the schema1 format.
+    ///
+    /// The result isn't signed. The v1 layer IDs are derived from the layer
+    /// digests so the same image always translates identically, but they don't
+    /// match the IDs Docker would have computed. Only the top layer carries the
+    /// image configuration; the others only get their creation command, taken
+    /// from the non-empty `history` entries of `config` when they line up.
+    pub fn from_image_manifest(
+        name: String,
+        tag: String,
+        manifest: &Manifest,
+        config: &Value,
+    ) -> Result<Schema1Manifest, String> {
+        let layers = match (&manifest.config, &manifest.layers) {
+            (Some(_), Some(layers)) if !layers.is_empty() => layers,
+            _ => return Err("Only image manifests with layers can be translated".to_string()),
+        };
+
+        let config = config
+            .as_object()
+            .ok_or("The image configuration isn't a JSON object")?;
+
+        let created_by = config
+            .get("history")
+            .and_then(Value::as_array)
+            .map(|history| {
+                history
+                    .iter()
+                    .filter(|entry| entry["empty_layer"] != Value::Bool(true))
+                    .map(|entry| entry["created_by"].clone())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|created_by| created_by.len() == layers.len())
+            .unwrap_or_else(|| vec![Value::Null; layers.len()]);
+
+        let mut fs_layers = Vec::new();
+        let mut history = Vec::new();
+        let mut parent: Option<String> = None;
+
+        for (i, layer) in layers.iter().enumerate() {
+            let id = hex::encode(Sha256::digest(format!(
+                "{} {}",
+                parent.as_deref().unwrap_or_default(),
+                layer.digest
+            )));
+
+            let mut v1_compatibility = if i == layers.len() - 1 {
+                let mut top = config.clone();
+                top.remove("history");
+                top.remove("rootfs");
+                top
+            } else {
+                let mut entry = Map::new();
+                if let Some(created) = config.get("created") {
+                    entry.insert("created".to_string(), created.clone());
+                }
+                entry.insert(
+                    "container_config".to_string(),
+                    serde_json::json!({ "Cmd": [created_by[i]] }),
+                );
+                entry
+            };
+
+            v1_compatibility.insert("id".to_string(), Value::String(id.clone()));
+            if let Some(parent) = &parent {
+                v1_compatibility.insert("parent".to_string(), Value::String(parent.clone()));
+            }
+
+            fs_layers.push(FsLayer {
+                blob_sum: layer.digest.clone(),
+            });
+            history.push(History {
+                v1_compatibility: Value::Object(v1_compatibility).to_string(),
+            });
+
+            parent = Some(id);
+        }
+
+        fs_layers.reverse();
+        history.reverse();
+
+        Ok(Schema1Manifest {
+            schema_version: 1,
+            name,
+            tag,
+            architecture: config
+                .get("architecture")
+                .and_then(Value::as_str)
+                .unwrap_or("amd64")
+                .to_string(),
+            fs_layers,
+            history,
+        })
+    }
+}
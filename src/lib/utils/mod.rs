@@ -15,3 +15,75 @@ where
     let s = String::from_utf8(ser.into_inner())?;
     Ok(s)
 }
+
+/// Expands `$NAME` and `${NAME}` with the environment variables, and a leading
+/// `~` with `$HOME`.
+pub fn expand_env(value: &str) -> Result<String, String> {
+    expand_vars(value, |name| std::env::var(name).ok())
+}
+
+/// Expands `$NAME` and `${NAME}` with `lookup`, and a leading `~` with the
+/// `HOME` variable. Fails on variables `lookup` has no value for rather than
+/// leaving them as is.
+pub fn expand_vars<F>(value: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let var = |name: &str| lookup(name).ok_or_else(|| format!("{} is not set", name));
+
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    if rest == "~" || rest.starts_with("~/") {
+        expanded.push_str(&var("HOME")?);
+        rest = &rest[1..];
+    }
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| format!("Unterminated variable in '{}'", value))?;
+            expanded.push_str(&var(&braced[..end])?);
+            rest = &braced[end + 1..];
+        } else {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            if end == 0 {
+                expanded.push('$');
+            } else {
+                expanded.push_str(&var(&rest[..end])?);
+            }
+            rest = &rest[end..];
+        }
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[test]
+fn test_expand_vars() {
+    let lookup = |name: &str| match name {
+        "FOO" => Some("/tmp".to_string()),
+        "HOME" => Some("/home/registry".to_string()),
+        _ => None,
+    };
+
+    assert_eq!(expand_vars("${FOO}/x", lookup), Ok("/tmp/x".to_string()));
+    assert_eq!(expand_vars("$FOO/x", lookup), Ok("/tmp/x".to_string()));
+    assert_eq!(
+        expand_vars("~/registry", lookup),
+        Ok("/home/registry/registry".to_string())
+    );
+    assert_eq!(
+        expand_vars("/data/a~b$", lookup),
+        Ok("/data/a~b$".to_string())
+    );
+    assert!(expand_vars("${BAR}/x", lookup).is_err());
+    assert!(expand_vars("${FOO/x", lookup).is_err());
+}